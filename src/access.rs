@@ -0,0 +1,207 @@
+//! access
+//!
+//! Find out, before syncing, which parts of the destination rusync would
+//! not be allowed to change. Nothing is written: a dry run tells what would
+//! be changed, and permissions are checked for each of these changes.
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::progress::{PlannedChange, Progress, ProgressInfo};
+use crate::sync::Stats;
+
+/// A path of the destination that can't be changed, and
+/// the entries of the sync that need it to be
+#[derive(Debug, PartialEq)]
+pub struct AccessProblem {
+    /// Relative to the destination
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub entries: Vec<String>,
+}
+
+impl AccessProblem {
+    /// One line, listing at most a few of the entries
+    pub fn describe(&self) -> String {
+        let what = if self.is_dir {
+            "directory is not writable"
+        } else {
+            "file is not writable"
+        };
+        let mut entries = self.entries.iter().take(3).cloned().collect::<Vec<_>>();
+        if self.entries.len() > 3 {
+            entries.push(format!("and {} more", self.entries.len() - 3));
+        }
+        let path = if self.path.as_os_str().is_empty() {
+            ".".to_string()
+        } else {
+            self.path.to_string_lossy().to_string()
+        };
+        format!("{}: {}, needed for {}", path, what, entries.join(", "))
+    }
+}
+
+// Records the changes of the dry run, and forwards warnings
+pub(crate) struct ChangeRecorder {
+    pub(crate) changes: Arc<Mutex<Vec<(PlannedChange, String)>>>,
+    pub(crate) inner: Box<dyn ProgressInfo + Send>,
+}
+
+impl ProgressInfo for ChangeRecorder {
+    fn warning(&self, message: &str) {
+        self.inner.warning(message);
+    }
+
+    fn planned(&self, change: PlannedChange, description: &str) {
+        self.changes
+            .lock()
+            .unwrap()
+            .push((change, description.to_string()));
+    }
+
+    fn progress(&self, progress: &Progress) {
+        self.inner.progress(progress);
+    }
+
+    fn end(&self, _stats: &Stats) {}
+}
+
+/// Check every change against the destination
+pub fn problems(destination: &Path, changes: &[(PlannedChange, String)]) -> Vec<AccessProblem> {
+    find_problems(destination, changes, writable)
+}
+
+fn find_problems<F>(
+    destination: &Path,
+    changes: &[(PlannedChange, String)],
+    is_writable: F,
+) -> Vec<AccessProblem>
+where
+    F: Fn(&Path) -> bool,
+{
+    // Keyed by path relative to the destination
+    let mut problems: BTreeMap<PathBuf, AccessProblem> = BTreeMap::new();
+    for (change, description) in changes {
+        let dest_path = destination.join(description);
+        // What has to be writable for the change to be made
+        let needed = match change {
+            PlannedChange::CopyFile | PlannedChange::UpdateFile if dest_path.is_file() => dest_path,
+            PlannedChange::CopyFile
+            | PlannedChange::UpdateFile
+            | PlannedChange::CreateDir
+            | PlannedChange::CreateLink
+            | PlannedChange::UpdateLink
+            | PlannedChange::RenameDir
+            | PlannedChange::Remove => {
+                // The parent may not exist yet, and would be created
+                // in the closest one that does
+                match dest_path
+                    .parent()
+                    .and_then(|p| p.ancestors().find(|a| a.exists()))
+                {
+                    Some(dir) => dir.to_path_buf(),
+                    None => continue,
+                }
+            }
+            PlannedChange::ReplaceContents
+            | PlannedChange::ReplaceMetadata
+            | PlannedChange::UpdateMetadata => continue,
+        };
+        if is_writable(&needed) {
+            continue;
+        }
+        let rel_path = needed.strip_prefix(destination).unwrap_or(&needed);
+        problems
+            .entry(rel_path.to_path_buf())
+            .or_insert_with(|| AccessProblem {
+                path: rel_path.to_path_buf(),
+                is_dir: needed.is_dir(),
+                entries: vec![],
+            })
+            .entries
+            .push(description.clone());
+    }
+    problems.into_values().collect()
+}
+
+#[cfg(unix)]
+fn writable(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return false,
+    };
+    // Entries of a directory can only be changed if it can also be searched
+    let mode = if path.is_dir() {
+        libc::W_OK | libc::X_OK
+    } else {
+        libc::W_OK
+    };
+    unsafe { libc::access(c_path.as_ptr(), mode) == 0 }
+}
+
+#[cfg(not(unix))]
+fn writable(path: &Path) -> bool {
+    path.metadata()
+        .map(|m| !m.permissions().readonly())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+    use std::fs;
+
+    #[test]
+    fn group_problems_by_path() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-access")?;
+        let dest = tmp_dir.path();
+        fs::create_dir_all(dest.join("locked"))?;
+        fs::create_dir_all(dest.join("open"))?;
+        fs::write(dest.join("open/old.txt"), "old")?;
+        fs::write(dest.join("locked/old.txt"), "old")?;
+        let changes = vec![
+            (PlannedChange::CopyFile, "locked/new.txt".to_string()),
+            (PlannedChange::CreateDir, "locked/sub".to_string()),
+            (PlannedChange::CopyFile, "locked/sub/deep.txt".to_string()),
+            (PlannedChange::CopyFile, "locked/old.txt".to_string()),
+            (PlannedChange::CopyFile, "open/new.txt".to_string()),
+            (PlannedChange::CopyFile, "open/old.txt".to_string()),
+            (PlannedChange::UpdateMetadata, "locked".to_string()),
+        ];
+        let locked = dest.join("locked");
+        let found = find_problems(dest, &changes, |p| !p.starts_with(&locked));
+
+        assert_eq!(
+            found,
+            vec![
+                AccessProblem {
+                    path: PathBuf::from("locked"),
+                    is_dir: true,
+                    entries: vec![
+                        "locked/new.txt".to_string(),
+                        "locked/sub".to_string(),
+                        "locked/sub/deep.txt".to_string(),
+                    ],
+                },
+                AccessProblem {
+                    path: PathBuf::from("locked/old.txt"),
+                    is_dir: false,
+                    entries: vec!["locked/old.txt".to_string()],
+                },
+            ]
+        );
+        assert_eq!(
+            found[0].describe(),
+            "locked: directory is not writable, needed for locked/new.txt, locked/sub, locked/sub/deep.txt"
+        );
+        assert!(problems(dest, &changes).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,564 @@
+//! batch
+//!
+//! Run several independent syncs from one process, as described by a
+//! jobs file like this one (a small subset of TOML):
+//!
+//! ```toml
+//! # How many jobs to run at the same time (1 by default)
+//! parallel = 2
+//! # Start fewer of them if they would need more than this many
+//! # open files, or this many bytes of buffers (both optional)
+//! max_open_files = 64
+//! max_memory = 104857600
+//!
+//! [[job]]
+//! source = "/home/me/photos"
+//! destination = "/mnt/backup/photos"
+//! delete = true
+//!
+//! [[job]]
+//! label = "mail"
+//! source = "/home/me/mail"
+//! destination = "/mnt/backup/mail"
+//! ```
+//!
+//! Every job uses the same options, except for `label` and `delete`
+//! which can be set per job.
+//!
+//! Without `max_open_files`, the limit of the process (`ulimit -n`) is
+//! used, so that running many jobs does not fail with "too many open files".
+//! A job that needs more than the limits on its own still runs, alone.
+//!
+//! `max_memory` only counts the buffers used to read and write files, whose
+//! size is known before a job starts. The rest of what a job keeps in memory
+//! grows with the size of its source, and is not counted: the entries waiting
+//! to be synced, all the paths of the source with `delete`, and the entries
+//! put off by `first`.
+//!
+//! A job can also be run on its own, by its label (see `find()`), for
+//! instance to sync only part of it again.
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::chunk_store::CHUNK_SIZE;
+use crate::error::Error;
+use crate::fsops::BUFFER_SIZE;
+use crate::progress::ProgressInfo;
+use crate::sync::{Stats, SyncOptions, Syncer};
+use crate::tune::MAX_BUFFER_SIZE;
+
+/// Files a job keeps open at most: the directory being walked, the source
+/// and destination files, and a sidecar, checkpoint or temporary file
+pub const FILES_PER_JOB: usize = 4;
+
+// Open files kept for the rest of the process (standard streams,
+// history, jobs file...) when the limit comes from `ulimit -n`
+const RESERVED_FILES: usize = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub label: Option<String>,
+    /// Overrides `SyncOptions::delete` if set
+    pub delete: Option<bool>,
+    /// Overrides `SyncOptions::last_sync` if set. Not read from the jobs
+    /// file: it depends on the history of this source and destination
+    pub last_sync: Option<SystemTime>,
+}
+
+impl Job {
+    /// The label, or the destination if there is none
+    pub fn name(&self) -> String {
+        match self.label {
+            Some(ref label) => label.clone(),
+            None => self.destination.to_string_lossy().to_string(),
+        }
+    }
+
+    /// `options`, with the settings of the job applied
+    pub fn options(&self, options: &SyncOptions) -> SyncOptions {
+        let mut options = options.clone();
+        if self.label.is_some() {
+            options.label = self.label.clone();
+        }
+        if let Some(delete) = self.delete {
+            options.delete = delete;
+        }
+        if self.last_sync.is_some() {
+            options.last_sync = self.last_sync;
+        }
+        options
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    /// Maximum number of jobs running at the same time
+    pub parallel: usize,
+    /// If set, do not start a job if the running ones and this one could
+    /// have more files open than that (see `FILES_PER_JOB`)
+    pub max_open_files: Option<usize>,
+    /// Like `max_open_files`, for the memory used by buffers (see
+    /// `buffer_memory()`). This is not a limit on all the memory used
+    pub max_memory: Option<u64>,
+    pub jobs: Vec<Job>,
+}
+
+/// How a job went
+pub struct Outcome {
+    pub job: Job,
+    pub result: Result<Stats, Error>,
+    pub duration: Duration,
+}
+
+/// `$XDG_CONFIG_HOME/rusync/jobs.toml`, or `~/.config/rusync/jobs.toml`
+/// (`%APPDATA%\rusync\jobs.toml` on Windows)
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(unix)]
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+    #[cfg(windows)]
+    let config_dir = std::env::var_os("APPDATA").map(PathBuf::from);
+    config_dir.map(|d| d.join("rusync").join("jobs.toml"))
+}
+
+/// The job with this label
+pub fn find<'a>(batch: &'a Batch, label: &str) -> Option<&'a Job> {
+    batch
+        .jobs
+        .iter()
+        .find(|job| job.label.as_deref() == Some(label))
+}
+
+pub fn read(path: &Path) -> Result<Batch, Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| Error::from_io(&format!("Could not read {:?}: {}", path, e), &e))?;
+    parse(&contents).map_err(|e| Error::new(&format!("Invalid jobs file {:?}: {}", path, e)))
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    String(String),
+    Bool(bool),
+    Integer(u64),
+}
+
+pub fn parse(contents: &str) -> Result<Batch, String> {
+    let mut parallel = 1;
+    let (mut max_open_files, mut max_memory) = (None, None);
+    // (line number, key-value pairs) of each job
+    let mut tables: Vec<(usize, Vec<(String, Value)>)> = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[job]]" {
+            tables.push((line_number, vec![]));
+            continue;
+        }
+        let (key, value) = parse_key_value(line)
+            .ok_or_else(|| format!("invalid line {}: {:?}", line_number, line))?;
+        match tables.last_mut() {
+            Some((_, pairs)) => pairs.push((key, value)),
+            None if key == "parallel" || key == "max_open_files" || key == "max_memory" => {
+                let n = match value {
+                    Value::Integer(n) if n > 0 => n,
+                    _ => return Err(format!("line {}: expected a positive number", line_number)),
+                };
+                match key.as_str() {
+                    "parallel" => parallel = n as usize,
+                    "max_open_files" => max_open_files = Some(n as usize),
+                    _ => max_memory = Some(n),
+                }
+            }
+            None => return Err(format!("line {}: unknown setting {:?}", line_number, key)),
+        }
+    }
+    let jobs = tables
+        .into_iter()
+        .map(|(line_number, pairs)| {
+            parse_job(pairs).map_err(|e| format!("job at line {}: {}", line_number, e))
+        })
+        .collect::<Result<Vec<Job>, String>>()?;
+    if jobs.is_empty() {
+        return Err("no [[job]] found".to_string());
+    }
+    Ok(Batch {
+        parallel,
+        max_open_files,
+        max_memory,
+        jobs,
+    })
+}
+
+fn parse_job(pairs: Vec<(String, Value)>) -> Result<Job, String> {
+    let (mut source, mut destination, mut label, mut delete) = (None, None, None, None);
+    for (key, value) in pairs {
+        match (key.as_str(), value) {
+            ("source", Value::String(s)) => source = Some(PathBuf::from(s)),
+            ("destination", Value::String(s)) => destination = Some(PathBuf::from(s)),
+            ("label", Value::String(s)) => label = Some(s),
+            ("delete", Value::Bool(b)) => delete = Some(b),
+            ("source", _) | ("destination", _) | ("label", _) | ("delete", _) => {
+                return Err(format!("invalid value for {:?}", key))
+            }
+            _ => return Err(format!("unknown setting {:?}", key)),
+        }
+    }
+    Ok(Job {
+        source: source.ok_or("missing source")?,
+        destination: destination.ok_or("missing destination")?,
+        label,
+        delete,
+        last_sync: None,
+    })
+}
+
+fn parse_key_value(line: &str) -> Option<(String, Value)> {
+    let pos = line.find('=')?;
+    let key = line[..pos].trim();
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return None;
+    }
+    let value = line[pos + 1..].trim();
+    let value = if let Some(quoted) = value.strip_prefix('"') {
+        Value::String(parse_string(quoted)?)
+    } else if value == "true" || value == "false" {
+        Value::Bool(value == "true")
+    } else {
+        Value::Integer(value.parse().ok()?)
+    };
+    Some((key.to_string(), value))
+}
+
+// Parse what follows the opening quote, which must end with the closing
+// quote (and maybe a comment)
+fn parse_string(text: &str) -> Option<String> {
+    let mut res = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let rest = chars.as_str().trim();
+                return if rest.is_empty() || rest.starts_with('#') {
+                    Some(res)
+                } else {
+                    None
+                };
+            }
+            '\\' => match chars.next()? {
+                '"' => res.push('"'),
+                '\\' => res.push('\\'),
+                'n' => res.push('\n'),
+                't' => res.push('\t'),
+                _ => return None,
+            },
+            c => res.push(c),
+        }
+    }
+    None
+}
+
+/// Memory used by the buffers of a job with these options, at most
+pub fn buffer_memory(options: &SyncOptions) -> u64 {
+    let size = if options.snapshot.is_some() {
+        CHUNK_SIZE
+    } else if options.auto_tune {
+        MAX_BUFFER_SIZE
+    } else {
+        // Comparing with the checksum policy reads both sides
+        2 * BUFFER_SIZE
+    };
+    size as u64
+}
+
+#[cfg(unix)]
+fn open_files_limit() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some((limit.rlim_cur as usize).saturating_sub(RESERVED_FILES))
+}
+
+#[cfg(not(unix))]
+fn open_files_limit() -> Option<usize> {
+    None
+}
+
+// What the running jobs use, so that new ones only start when there is room
+struct Budget {
+    max_files: Option<usize>,
+    max_memory: Option<u64>,
+    // Running jobs, open files and memory
+    used: Mutex<(usize, usize, u64)>,
+    released: Condvar,
+}
+
+impl Budget {
+    fn new(max_files: Option<usize>, max_memory: Option<u64>) -> Budget {
+        Budget {
+            max_files,
+            max_memory,
+            used: Mutex::new((0, 0, 0)),
+            released: Condvar::new(),
+        }
+    }
+
+    // Wait until the job fits, or nothing else runs
+    fn acquire(&self, files: usize, memory: u64) {
+        let mut used = self.used.lock().unwrap();
+        loop {
+            let (running, used_files, used_memory) = *used;
+            let fits = self.max_files.map_or(true, |max| used_files + files <= max)
+                && self
+                    .max_memory
+                    .map_or(true, |max| used_memory + memory <= max);
+            if running == 0 || fits {
+                *used = (running + 1, used_files + files, used_memory + memory);
+                return;
+            }
+            used = self.released.wait(used).unwrap();
+        }
+    }
+
+    fn release(&self, files: usize, memory: u64) {
+        let mut used = self.used.lock().unwrap();
+        let (running, used_files, used_memory) = *used;
+        *used = (running - 1, used_files - files, used_memory - memory);
+        self.released.notify_all();
+    }
+}
+
+/// Run every job of the batch, at most `batch.parallel` at the same time
+/// and within `max_open_files` and `max_memory`, and return their outcomes
+/// in the order of the jobs. A failing job does not stop the others
+pub fn run<F>(batch: &Batch, options: &SyncOptions, progress_info: F) -> Vec<Outcome>
+where
+    F: Fn(&Job) -> Box<dyn ProgressInfo + Send> + Sync,
+{
+    let next = Mutex::new(0);
+    let budget = Budget::new(
+        batch.max_open_files.or_else(open_files_limit),
+        batch.max_memory,
+    );
+    let outcomes: Mutex<Vec<Option<Outcome>>> =
+        Mutex::new(batch.jobs.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..batch.parallel.min(batch.jobs.len()) {
+            scope.spawn(|| loop {
+                let index = {
+                    let mut next = next.lock().unwrap();
+                    *next += 1;
+                    *next - 1
+                };
+                let job = match batch.jobs.get(index) {
+                    Some(job) => job,
+                    None => return,
+                };
+                let job_options = job.options(options);
+                let memory = buffer_memory(&job_options);
+                budget.acquire(FILES_PER_JOB, memory);
+                let start = Instant::now();
+                let syncer = Syncer::new(
+                    &job.source,
+                    &job.destination,
+                    job_options,
+                    progress_info(job),
+                );
+                let outcome = Outcome {
+                    job: job.clone(),
+                    result: check_source(job).and_then(|_| syncer.sync()),
+                    duration: start.elapsed(),
+                };
+                budget.release(FILES_PER_JOB, memory);
+                outcomes.lock().unwrap()[index] = Some(outcome);
+            });
+        }
+    });
+    outcomes
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|o| o.expect("every job should have run"))
+        .collect()
+}
+
+fn check_source(job: &Job) -> Result<(), Error> {
+    if job.source.is_dir() {
+        Ok(())
+    } else {
+        Err(Error::new(&format!("{:?} is not a directory", job.source)))
+    }
+}
+
+/// One line per job, and a total
+pub fn report(outcomes: &[Outcome]) -> String {
+    let mut res = format!(
+        "{:<8} {:>8} {:>14} {:>10}  {}\n",
+        "status", "files", "bytes", "duration", "job"
+    );
+    let (mut files, mut bytes, mut failed) = (0, 0, 0);
+    for outcome in outcomes {
+        let (status, copied, copied_bytes) = match outcome.result {
+            Ok(ref stats) if stats.stopped.is_some() => {
+                ("partial", stats.copied, stats.transfers.total_bytes())
+            }
+            Ok(ref stats) => ("ok", stats.copied, stats.transfers.total_bytes()),
+            Err(_) => {
+                failed += 1;
+                ("failed", 0, 0)
+            }
+        };
+        files += copied;
+        bytes += copied_bytes;
+        res.push_str(&format!(
+            "{:<8} {:>8} {:>14} {:>9}s  {}\n",
+            status,
+            copied,
+            copied_bytes,
+            outcome.duration.as_secs(),
+            outcome.job.name()
+        ));
+        if let Err(ref err) = outcome.result {
+            res.push_str(&format!("         {}\n", err));
+        }
+    }
+    res.push_str(&format!(
+        "{} jobs, {} failed: {} files copied, {} bytes\n",
+        outcomes.len(),
+        failed,
+        files,
+        bytes
+    ));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_jobs() {
+        let batch = parse(
+            r#"
+# Nightly backups
+parallel = 2
+
+[[job]]
+source = "/home/me/photos"
+destination = "/mnt/backup/photos"  # the big one
+delete = true
+
+[[job]]
+label = "say \"cheese\""
+source = 'unused'
+"#,
+        );
+        // Single quotes are not supported
+        assert!(batch.is_err());
+
+        let batch = parse(
+            r#"
+parallel = 2
+[[job]]
+source = "/home/me/photos"
+destination = "/mnt/backup/photos"  # the big one
+delete = true
+
+[[job]]
+label = "say \"cheese\""
+source = "mail"
+destination = "backup/mail"
+"#,
+        )
+        .unwrap();
+        assert_eq!(batch.parallel, 2);
+        assert_eq!(batch.max_open_files, None);
+        let limited = parse(
+            "max_open_files = 64\nmax_memory = 1000\n[[job]]\nsource = \"a\"\ndestination = \"b\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            (limited.max_open_files, limited.max_memory),
+            (Some(64), Some(1000))
+        );
+        assert_eq!(
+            find(&batch, "say \"cheese\"").unwrap().source,
+            PathBuf::from("mail")
+        );
+        assert!(find(&batch, "photos").is_none());
+        assert_eq!(
+            batch.jobs,
+            vec![
+                Job {
+                    source: PathBuf::from("/home/me/photos"),
+                    destination: PathBuf::from("/mnt/backup/photos"),
+                    label: None,
+                    delete: Some(true),
+                    last_sync: None,
+                },
+                Job {
+                    source: PathBuf::from("mail"),
+                    destination: PathBuf::from("backup/mail"),
+                    label: Some("say \"cheese\"".to_string()),
+                    delete: None,
+                    last_sync: None,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_jobs() {
+        assert!(parse("").is_err());
+        assert!(parse("[[job]]\nsource = \"a\"\n").is_err());
+        assert!(parse("[[job]]\nsource = \"a\"\ndestination = \"b\"\ndelete = 1\n").is_err());
+        assert!(parse("[[job]]\nsource = \"a\"\ndestination = \"b\"\nsauce = \"c\"\n").is_err());
+        assert!(parse("parallel = 0\n[[job]]\nsource = \"a\"\ndestination = \"b\"\n").is_err());
+        assert!(
+            parse("max_memory = \"1G\"\n[[job]]\nsource = \"a\"\ndestination = \"b\"\n").is_err()
+        );
+    }
+
+    #[test]
+    fn stay_within_budget() {
+        let budget = Budget::new(Some(10), Some(100));
+        budget.acquire(4, 50);
+        budget.acquire(4, 50);
+        // Would be needed by a third job
+        assert_eq!(*budget.used.lock().unwrap(), (2, 8, 100));
+        thread::scope(|scope| {
+            let third = scope.spawn(|| budget.acquire(4, 10));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!third.is_finished());
+            budget.release(4, 50);
+            third.join().unwrap();
+        });
+        assert_eq!(*budget.used.lock().unwrap(), (2, 8, 60));
+
+        // Too big on its own, but nothing else runs
+        let budget = Budget::new(Some(2), None);
+        budget.acquire(4, 0);
+        assert_eq!(*budget.used.lock().unwrap(), (1, 4, 0));
+    }
+}
@@ -0,0 +1,245 @@
+//! blocks
+//!
+//! Remember the hash of every block of the files written to the
+//! destination, so that the next sync using the checksum policy can find
+//! which blocks changed without reading the destination at all, which
+//! matters when it is slow to read (network or cold storage).
+//!
+//! The hashes are stored next to the file:
+//!
+//! ```text
+//! <dest dir>/.<dest name>.rusync-blocks
+//! ```
+//!
+//! It contains the block size, the size and modification time of the
+//! destination file when it was written, then one SHA-256 per block. If
+//! the destination file has changed since, the sidecar is not used.
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+
+use filetime::FileTime;
+
+use crate::error::Error;
+use crate::fsops;
+use crate::fsops::BUFFER_SIZE;
+use crate::hash::Sha256;
+
+const HEADER: &str = "rusync-blocks 1";
+const SUFFIX: &str = ".rusync-blocks";
+
+pub fn sidecar_path(dest: &Path) -> PathBuf {
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    dest.with_file_name(format!(".{}{}", name, SUFFIX))
+}
+
+/// If `rel_path` is a sidecar, the path of the file it belongs to
+pub fn sidecar_owner(rel_path: &Path) -> Option<PathBuf> {
+    let name = rel_path.file_name()?.to_str()?;
+    let owner = name.strip_prefix('.')?.strip_suffix(SUFFIX)?;
+    if owner.is_empty() {
+        return None;
+    }
+    Some(rel_path.with_file_name(owner))
+}
+
+/// True if `rel_path` is the sidecar of a file of the source, so
+/// must not be deleted as extraneous
+pub fn is_needed(rel_path: &Path, source_paths: &HashSet<PathBuf>) -> bool {
+    sidecar_owner(rel_path).map_or(false, |owner| source_paths.contains(&owner))
+}
+
+// Size and modification time, which tell whether the file was changed
+fn stamp(path: &Path) -> Option<(u64, u64, u32)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    Some((metadata.len(), mtime.seconds(), mtime.nanoseconds()))
+}
+
+/// Hash the blocks of a file while it is copied, whatever the size of
+/// the reads
+#[derive(Default)]
+pub struct BlockHasher {
+    current: Sha256,
+    in_block: usize,
+    size: u64,
+    digests: Vec<String>,
+}
+
+impl BlockHasher {
+    pub fn new() -> BlockHasher {
+        BlockHasher::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let len = std::cmp::min(BUFFER_SIZE - self.in_block, data.len());
+            self.current.update(&data[..len]);
+            self.in_block += len;
+            self.size += len as u64;
+            data = &data[len..];
+            if self.in_block == BUFFER_SIZE {
+                self.end_block();
+            }
+        }
+    }
+
+    /// How many bytes were hashed
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn end_block(&mut self) {
+        let current = std::mem::take(&mut self.current);
+        self.digests.push(current.hex_digest());
+        self.in_block = 0;
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if self.in_block > 0 {
+            self.end_block();
+        }
+        self.digests
+    }
+}
+
+/// Write the sidecar of `dest`, from the blocks of what was written to it
+pub fn write(dest: &Path, hasher: BlockHasher) -> Result<(), Error> {
+    let (size, seconds, nanos) = stamp(dest)
+        .ok_or_else(|| Error::new(&format!("Could not read metadata from {:?}", dest)))?;
+    if size != hasher.size() {
+        return Err(Error::new(&format!(
+            "Could not write the block hashes of {:?}: {} bytes were hashed, but it has {}",
+            dest,
+            hasher.size(),
+            size
+        )));
+    }
+    let mut contents = format!(
+        "{}\n{}\t{}\t{}\t{}\n",
+        HEADER, BUFFER_SIZE, size, seconds, nanos
+    );
+    for digest in hasher.finish() {
+        contents.push_str(&digest);
+        contents.push('\n');
+    }
+    let path = sidecar_path(dest);
+    fs::write(&path, contents)
+        .map_err(|e| Error::from_io(&format!("Could not write {:?}: {}", path, e), &e))
+}
+
+/// Same as write(), for when `src` was copied to `dest` without being read
+/// (reflinks, appends, resumed copies): read it now
+pub fn write_from_source(src: &Path, dest: &Path) -> Result<(), Error> {
+    let mut src_file = File::open(src)
+        .map_err(|e| Error::from_io(&format!("Could not open {:?} for reading: {}", src, e), &e))?;
+    let mut hasher = BlockHasher::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let num_read = fsops::read_full(&mut src_file, &mut buffer)
+            .map_err(|e| Error::from_io(&format!("Could not read from {:?}: {}", src, e), &e))?;
+        if num_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..num_read]);
+    }
+    write(dest, hasher)
+}
+
+/// The hashes of the blocks of `dest`, if its sidecar is still valid
+pub fn read(dest: &Path) -> Option<Vec<String>> {
+    let contents = fs::read_to_string(sidecar_path(dest)).ok()?;
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return None;
+    }
+    let fields: Vec<&str> = lines.next()?.split('\t').collect();
+    if fields.len() != 4 || fields[0].parse::<usize>().ok()? != BUFFER_SIZE {
+        return None;
+    }
+    let written = (
+        fields[1].parse().ok()?,
+        fields[2].parse().ok()?,
+        fields[3].parse().ok()?,
+    );
+    if stamp(dest)? != written {
+        return None;
+    }
+    let digests: Vec<String> = lines.map(|l| l.to_string()).collect();
+    let num_blocks = (written.0 + BUFFER_SIZE as u64 - 1) / BUFFER_SIZE as u64;
+    if digests.len() as u64 != num_blocks {
+        return None;
+    }
+    Some(digests)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn test_sidecar_owner() {
+        let owner = |p| sidecar_owner(Path::new(p)).map(|p| p.to_string_lossy().to_string());
+        assert_eq!(
+            sidecar_path(Path::new("a/b.txt")),
+            PathBuf::from("a/.b.txt.rusync-blocks")
+        );
+        assert_eq!(owner("a/.b.txt.rusync-blocks"), Some("a/b.txt".to_string()));
+        assert_eq!(owner("a/b.txt.rusync-blocks"), None);
+        assert_eq!(owner("a/..rusync-blocks"), None);
+        assert_eq!(owner("a/.b.txt"), None);
+    }
+
+    #[test]
+    fn stale_sidecars_are_ignored() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-blocks")?;
+        let src = tmp_dir.path().join("src.bin");
+        let dest = tmp_dir.path().join("dest.bin");
+        let data: Vec<u8> = (0..250_000).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data)?;
+        fs::write(&dest, &data)?;
+
+        assert_eq!(read(&dest), None);
+        write_from_source(&src, &dest).unwrap();
+        let digests = read(&dest).unwrap();
+        assert_eq!(digests.len(), 3);
+        let mut hasher = Sha256::new();
+        hasher.update(&data[..BUFFER_SIZE]);
+        assert_eq!(digests[0], hasher.hex_digest());
+
+        fs::write(&dest, "changed")?;
+        assert_eq!(read(&dest), None);
+        Ok(())
+    }
+
+    #[test]
+    fn hash_blocks_while_copying() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-blocks")?;
+        let dest = tmp_dir.path().join("dest.bin");
+        let data: Vec<u8> = (0..250_000).map(|i| (i % 251) as u8).collect();
+        fs::write(&dest, &data)?;
+        write_from_source(&dest, &dest).unwrap();
+        let expected = read(&dest).unwrap();
+
+        // Reads which do not match the blocks
+        let mut hasher = BlockHasher::new();
+        for chunk in data.chunks(10_007) {
+            hasher.update(chunk);
+        }
+        write(&dest, hasher).unwrap();
+        assert_eq!(read(&dest), Some(expected));
+
+        let mut hasher = BlockHasher::new();
+        hasher.update(&data[..1000]);
+        assert!(write(&dest, hasher).is_err());
+        Ok(())
+    }
+}
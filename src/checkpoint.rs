@@ -0,0 +1,368 @@
+//! checkpoint
+//!
+//! Copy large files so that an interrupted copy can be resumed.
+//!
+//! While copying, a marker is written next to the destination file every
+//! `interval` bytes, once the data up to there has been flushed to disk:
+//!
+//! ```text
+//! <dest dir>/.<dest name>.rusync-checkpoint
+//! ```
+//!
+//! It contains the offset and the SHA-256 of everything before it. The next
+//! copy of the same file checks that both the source and the destination
+//! still start with the same data, and if so, only copies what is after the
+//! offset. The marker is removed once the copy is complete.
+//!
+//! A copy can also be interrupted on purpose (see `SyncOptions::interrupt`):
+//! the data written so far is flushed, and a marker is written for it.
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::Instant;
+
+use crate::entry::Entry;
+use crate::error::Error;
+use crate::fsops::{Hashes, SyncOutcome, BUFFER_SIZE};
+use crate::hash::Sha256;
+use crate::progress::ProgressMessage;
+use crate::tune::BufferTuner;
+
+const HEADER: &str = "rusync-checkpoint 1";
+
+pub fn checkpoint_path(dest: &Path) -> PathBuf {
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    dest.with_file_name(format!(".{}.rusync-checkpoint", name))
+}
+
+/// Copy `src` to `dest`, writing a marker every `interval` bytes so that
+/// an interrupted copy resumes where it stopped. The hash of the whole
+/// file is known even when resuming, but not the hashes of its blocks.
+/// Uses the buffer size of `tuner` if set, and tells it how long it took
+pub fn copy_with_checkpoints(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest: &Entry,
+    interval: u64,
+    interrupt: Option<&AtomicBool>,
+    tuner: Option<&mut BufferTuner>,
+    hashes: &mut Hashes,
+) -> Result<SyncOutcome, Error> {
+    let marker_path = checkpoint_path(dest.path());
+    let mut src_file = File::open(src.path()).map_err(|e| {
+        Error::from_io(
+            &format!("Could not open {} for reading: {}", src.description(), e),
+            &e,
+        )
+    })?;
+    let src_size = src.metadata().map(|m| m.len()).unwrap_or(0);
+
+    let resumed = match read_marker(&marker_path) {
+        Some((offset, digest)) => resume(&mut src_file, dest.path(), offset, &digest),
+        None => None,
+    };
+    let from_scratch = resumed.is_none();
+    let (mut hasher, mut offset, mut dest_file) = match resumed {
+        Some((hasher, offset)) => {
+            let dest_file = fs::OpenOptions::new()
+                .write(true)
+                .open(dest.path())
+                .and_then(|mut f| {
+                    f.set_len(offset)?;
+                    f.seek(SeekFrom::Start(offset))?;
+                    Ok(f)
+                })
+                .map_err(|e| {
+                    Error::from_io(
+                        &format!("Could not open {} for resuming: {}", dest.description(), e),
+                        &e,
+                    )
+                })?;
+            (hasher, offset, dest_file)
+        }
+        None => {
+            src_file.seek(SeekFrom::Start(0)).map_err(|e| {
+                Error::from_io(
+                    &format!("Could not seek in {}: {}", src.description(), e),
+                    &e,
+                )
+            })?;
+            let dest_file = File::create(dest.path()).map_err(|e| {
+                Error::from_io(
+                    &format!("Could not open {} for writing: {}", dest.description(), e),
+                    &e,
+                )
+            })?;
+            (Sha256::new(), 0, dest_file)
+        }
+    };
+    if offset > 0 {
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: offset as usize,
+        };
+        let _ = progress_sender.send(progress);
+    }
+
+    let start = Instant::now();
+    let resumed_at = offset;
+    let mut since_checkpoint = 0;
+    let buffer_size = tuner.as_ref().map_or(BUFFER_SIZE, |t| t.buffer_size());
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let num_read = src_file.read(&mut buffer).map_err(|e| {
+            Error::from_io(
+                &format!("Could not read from {}: {}", src.description(), e),
+                &e,
+            )
+        })?;
+        if num_read == 0 {
+            break;
+        }
+        let data = &buffer[0..num_read];
+        dest_file.write_all(data).map_err(|e| {
+            Error::from_io(
+                &format!("Could not write to {}: {}", dest.description(), e),
+                &e,
+            )
+        })?;
+        hasher.update(data);
+        if let (true, Some(blocks)) = (from_scratch, hashes.blocks.as_mut()) {
+            blocks.update(data);
+        }
+        offset += num_read as u64;
+        since_checkpoint += num_read as u64;
+        let interrupted = interrupt.map_or(false, |i| i.load(Ordering::SeqCst));
+        if since_checkpoint >= interval || interrupted {
+            // The marker must never claim more than what is on disk
+            dest_file.sync_data().map_err(|e| {
+                Error::from_io(
+                    &format!("Could not flush {}: {}", dest.description(), e),
+                    &e,
+                )
+            })?;
+            write_marker(&marker_path, offset, &hasher.clone().hex_digest())?;
+            since_checkpoint = 0;
+        }
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: num_read,
+        };
+        let _ = progress_sender.send(progress);
+        if interrupted {
+            return Ok(SyncOutcome::Interrupted);
+        }
+    }
+    if let Some(tuner) = tuner {
+        tuner.record(offset - resumed_at, start.elapsed());
+    }
+    // Covers what was copied before resuming too
+    if hashes.file.is_some() {
+        hashes.file = Some(hasher);
+    }
+    match fs::remove_file(&marker_path) {
+        Err(ref e) if e.kind() != io::ErrorKind::NotFound => Err(Error::new(&format!(
+            "Could not remove {:?}: {}",
+            marker_path, e
+        ))),
+        _ => Ok(SyncOutcome::FileCopied),
+    }
+}
+
+fn read_marker(path: &Path) -> Option<(u64, String)> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return None;
+    }
+    let mut fields = lines.next()?.split('\t');
+    let offset = fields.next()?.parse().ok()?;
+    let digest = fields.next()?.to_string();
+    Some((offset, digest))
+}
+
+fn write_marker(path: &Path, offset: u64, digest: &str) -> Result<(), Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    fs::write(&tmp_path, format!("{}\n{}\t{}\n", HEADER, offset, digest))
+        .map_err(|e| Error::from_io(&format!("Could not write {:?}: {}", tmp_path, e), &e))?;
+    fs::rename(&tmp_path, path).map_err(|e| {
+        Error::from_io(
+            &format!("Could not rename {:?} to {:?}: {}", tmp_path, path, e),
+            &e,
+        )
+    })
+}
+
+// Check that the source and the destination both start with the data
+// described by the marker. If so, return the state of the hasher after that
+// data, and leave `src_file` positioned right after it.
+fn resume(src_file: &mut File, dest: &Path, offset: u64, digest: &str) -> Option<(Sha256, u64)> {
+    let mut src_hasher = Sha256::new();
+    hash_prefix(src_file, offset, &mut src_hasher).ok()?;
+    if src_hasher.clone().hex_digest() != digest {
+        return None;
+    }
+    let mut dest_file = File::open(dest).ok()?;
+    let mut dest_hasher = Sha256::new();
+    hash_prefix(&mut dest_file, offset, &mut dest_hasher).ok()?;
+    if dest_hasher.hex_digest() != digest {
+        return None;
+    }
+    Some((src_hasher, offset))
+}
+
+fn hash_prefix(file: &mut File, len: u64, hasher: &mut Sha256) -> io::Result<()> {
+    let mut remaining = len;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    while remaining > 0 {
+        let to_read = remaining.min(BUFFER_SIZE as u64) as usize;
+        let num_read = file.read(&mut buffer[..to_read])?;
+        if num_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file is shorter than the checkpoint",
+            ));
+        }
+        hasher.update(&buffer[..num_read]);
+        remaining -= num_read as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+
+    fn test_data() -> Vec<u8> {
+        (0..300_000).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Copy, and return the progress reported for the first read
+    fn copy(src: &Path, dest: &Path) -> usize {
+        let (sender, receiver) = mpsc::channel::<ProgressMessage>();
+        let src_entry = Entry::new("src", src);
+        let dest_entry = Entry::new("dest", dest);
+        copy_with_checkpoints(
+            &sender,
+            &src_entry,
+            &dest_entry,
+            100_000,
+            None,
+            None,
+            &mut Hashes::default(),
+        )
+        .unwrap();
+        match receiver.try_recv() {
+            Ok(ProgressMessage::Syncing { done, .. }) => done,
+            _ => panic!("no progress reported"),
+        }
+    }
+
+    fn interrupted_copy(tmp_path: &Path, dest_prefix: &[u8]) -> (PathBuf, PathBuf) {
+        let data = test_data();
+        let src = tmp_path.join("src.bin");
+        let dest = tmp_path.join("dest.bin");
+        fs::write(&src, &data).unwrap();
+        fs::write(&dest, dest_prefix).unwrap();
+        let mut hasher = Sha256::new();
+        hasher.update(&data[..200_000]);
+        write_marker(&checkpoint_path(&dest), 200_000, &hasher.hex_digest()).unwrap();
+        (src, dest)
+    }
+
+    #[test]
+    fn copy_without_checkpoint() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-checkpoint")?;
+        let src = tmp_dir.path().join("src.bin");
+        let dest = tmp_dir.path().join("dest.bin");
+        fs::write(&src, test_data())?;
+
+        copy(&src, &dest);
+
+        assert_eq!(fs::read(&dest)?, test_data());
+        assert!(!checkpoint_path(&dest).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn resume_from_checkpoint() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-checkpoint")?;
+        // Data after the checkpoint may not have been flushed completely
+        let mut partial = test_data()[..250_000].to_vec();
+        partial[240_000] = 0xff;
+        let (src, dest) = interrupted_copy(tmp_dir.path(), &partial);
+
+        let first_done = copy(&src, &dest);
+
+        assert_eq!(first_done, 200_000);
+        assert_eq!(fs::read(&dest)?, test_data());
+        assert!(!checkpoint_path(&dest).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn interrupt_then_resume() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-checkpoint")?;
+        let src = tmp_dir.path().join("src.bin");
+        let dest = tmp_dir.path().join("dest.bin");
+        fs::write(&src, test_data())?;
+        let (sender, _receiver) = mpsc::channel::<ProgressMessage>();
+        let src_entry = Entry::new("src", &src);
+        let dest_entry = Entry::new("dest", &dest);
+        let interrupt = AtomicBool::new(true);
+
+        let outcome = copy_with_checkpoints(
+            &sender,
+            &src_entry,
+            &dest_entry,
+            100_000,
+            Some(&interrupt),
+            None,
+            &mut Hashes::default(),
+        )
+        .unwrap();
+
+        assert_eq!(outcome, SyncOutcome::Interrupted);
+        let (offset, _) = read_marker(&checkpoint_path(&dest)).unwrap();
+        assert_eq!(offset, fs::metadata(&dest)?.len());
+        assert!(offset < 300_000);
+
+        let first_done = copy(&src, &dest);
+
+        assert_eq!(first_done, offset as usize);
+        assert_eq!(fs::read(&dest)?, test_data());
+        Ok(())
+    }
+
+    #[test]
+    fn restart_when_prefix_differs() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-checkpoint")?;
+        let mut partial = test_data()[..200_000].to_vec();
+        partial[42] = 0xff;
+        let (src, dest) = interrupted_copy(tmp_dir.path(), &partial);
+
+        let first_done = copy(&src, &dest);
+
+        assert_ne!(first_done, 200_000);
+        assert_eq!(fs::read(&dest)?, test_data());
+        Ok(())
+    }
+}
@@ -0,0 +1,579 @@
+//! chunk_store
+//!
+//! Store files in a deduplicated, content-addressed repository.
+//!
+//! Layout of the repository:
+//!
+//! ```text
+//! <root>/chunks/<first two hex digits>/<sha256 of the chunk>
+//! <root>/snapshots/<name>
+//! ```
+//!
+//! Files are cut in fixed-size chunks, and a chunk is only written if
+//! no chunk with the same hash exists yet, so storing a tree that is mostly
+//! identical to a previous snapshot only consumes space for what changed.
+//!
+//! Each snapshot is a text file starting with a `rusync-snapshot 1` header,
+//! followed by one tab-separated line per entry:
+//!
+//! ```text
+//! dir <mode, in octal> <mtime> <path>
+//! file <mode, in octal> <mtime> <size> <comma-separated chunk ids> <path>
+//! link <target> <path>
+//! ```
+//!
+//! Modification times are in nanoseconds since the epoch. Paths and targets
+//! are escaped, see the `escape` module.
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use filetime::FileTime;
+
+use crate::entry::Entry;
+use crate::error::Error;
+use crate::escape;
+use crate::hash::Sha256;
+use crate::progress::ProgressMessage;
+
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024;
+
+const HEADER: &str = "rusync-snapshot 1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotRecord {
+    Dir {
+        path: PathBuf,
+        mode: u32,
+        mtime: u64,
+    },
+    File {
+        path: PathBuf,
+        mode: u32,
+        mtime: u64,
+        size: u64,
+        chunks: Vec<String>,
+    },
+    Link {
+        path: PathBuf,
+        target: PathBuf,
+    },
+}
+
+impl SnapshotRecord {
+    fn to_line(&self) -> String {
+        match self {
+            SnapshotRecord::Dir { path, mode, mtime } => {
+                format!(
+                    "dir\t{:o}\t{}\t{}\n",
+                    mode,
+                    mtime,
+                    escape::escape_path(path)
+                )
+            }
+            SnapshotRecord::File {
+                path,
+                mode,
+                mtime,
+                size,
+                chunks,
+            } => format!(
+                "file\t{:o}\t{}\t{}\t{}\t{}\n",
+                mode,
+                mtime,
+                size,
+                chunks.join(","),
+                escape::escape_path(path)
+            ),
+            SnapshotRecord::Link { path, target } => format!(
+                "link\t{}\t{}\n",
+                escape::escape_path(target),
+                escape::escape_path(path)
+            ),
+        }
+    }
+}
+
+pub fn parse_snapshot(contents: &str) -> Result<Vec<SnapshotRecord>, String> {
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(format!("expected '{}' on the first line", HEADER));
+    }
+    let mut records = vec![];
+    for (i, line) in lines.enumerate() {
+        let invalid = || format!("invalid line {}: {:?}", i + 2, line);
+        let fields: Vec<&str> = line.split('\t').collect();
+        let record = match fields.as_slice() {
+            ["dir", mode, mtime, path] => SnapshotRecord::Dir {
+                path: escape::unescape_path(path)?,
+                mode: u32::from_str_radix(mode, 8).map_err(|_| invalid())?,
+                mtime: mtime.parse().map_err(|_| invalid())?,
+            },
+            ["file", mode, mtime, size, chunks, path] => {
+                let chunks: Vec<String> = match *chunks {
+                    "" => vec![],
+                    chunks => chunks.split(',').map(|c| c.to_string()).collect(),
+                };
+                if !chunks.iter().all(|c| is_chunk_id(c)) {
+                    return Err(invalid());
+                }
+                SnapshotRecord::File {
+                    path: escape::unescape_path(path)?,
+                    mode: u32::from_str_radix(mode, 8).map_err(|_| invalid())?,
+                    mtime: mtime.parse().map_err(|_| invalid())?,
+                    size: size.parse().map_err(|_| invalid())?,
+                    chunks,
+                }
+            }
+            ["link", target, path] => SnapshotRecord::Link {
+                path: escape::unescape_path(path)?,
+                target: escape::unescape_path(target)?,
+            },
+            _ => return Err(invalid()),
+        };
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn is_chunk_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// A snapshot stored in the repository
+#[derive(Debug, PartialEq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub files: u64,
+    /// Total size of the files, before deduplication
+    pub bytes: u64,
+}
+
+pub struct ChunkStore {
+    root: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn open(root: &Path) -> Result<ChunkStore, Error> {
+        let store = ChunkStore {
+            root: root.to_path_buf(),
+        };
+        for dir in &[store.chunks_dir(), store.snapshots_dir()] {
+            fs::create_dir_all(dir)
+                .map_err(|e| Error::from_io(&format!("Could not create {:?}: {}", dir, e), &e))?;
+        }
+        Ok(store)
+    }
+
+    /// Like `open()`, but fail instead of creating a new repository
+    pub fn open_existing(root: &Path) -> Result<ChunkStore, Error> {
+        let store = ChunkStore {
+            root: root.to_path_buf(),
+        };
+        if !store.snapshots_dir().is_dir() {
+            return Err(Error::new(&format!(
+                "{:?} is not a snapshot repository",
+                root
+            )));
+        }
+        Ok(store)
+    }
+
+    pub fn snapshot_path(&self, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.contains('/') || name.contains('\\') || name.starts_with('.') {
+            return Err(Error::new(&format!("Invalid snapshot name: {:?}", name)));
+        }
+        Ok(self.snapshots_dir().join(name))
+    }
+
+    /// Cut the file in chunks and store the ones that are missing
+    /// Returns the list of chunk ids, and whether new data was written.
+    pub fn store_file(
+        &self,
+        progress_sender: &mpsc::Sender<ProgressMessage>,
+        src: &Entry,
+    ) -> Result<(Vec<String>, bool), Error> {
+        let mut src_file = File::open(src.path()).map_err(|e| {
+            Error::from_io(
+                &format!("Could not open {} for reading: {}", src.description(), e),
+                &e,
+            )
+        })?;
+        let src_size = src.metadata().map(|m| m.len()).unwrap_or(0);
+        let mut chunks = vec![];
+        let mut wrote_data = false;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let num_read = read_chunk(&mut src_file, &mut buffer).map_err(|e| {
+                Error::from_io(
+                    &format!("Could not read from {}: {}", src.description(), e),
+                    &e,
+                )
+            })?;
+            if num_read == 0 {
+                break;
+            }
+            let data = &buffer[0..num_read];
+            let mut hasher = Sha256::new();
+            hasher.update(data);
+            let id = hasher.hex_digest();
+            if self.write_chunk(&id, data)? {
+                wrote_data = true;
+            }
+            chunks.push(id);
+            let progress = ProgressMessage::Syncing {
+                description: src.description().clone(),
+                size: src_size as usize,
+                done: num_read,
+            };
+            let _ = progress_sender.send(progress);
+        }
+        Ok((chunks, wrote_data))
+    }
+
+    pub fn write_snapshot(&self, name: &str, records: &[SnapshotRecord]) -> Result<(), Error> {
+        let snapshot_path = self.snapshot_path(name)?;
+        let tmp_path = self.snapshots_dir().join(format!(".{}.tmp", name));
+        let mut contents = format!("{}\n", HEADER);
+        for record in records {
+            contents.push_str(&record.to_line());
+        }
+        fs::write(&tmp_path, contents)
+            .map_err(|e| Error::from_io(&format!("Could not write {:?}: {}", tmp_path, e), &e))?;
+        fs::rename(&tmp_path, &snapshot_path).map_err(|e| {
+            Error::from_io(
+                &format!(
+                    "Could not rename {:?} to {:?}: {}",
+                    tmp_path, snapshot_path, e
+                ),
+                &e,
+            )
+        })
+    }
+
+    pub fn read_snapshot(&self, name: &str) -> Result<Vec<SnapshotRecord>, Error> {
+        let path = self.snapshot_path(name)?;
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| Error::from_io(&format!("Could not read snapshot {}: {}", name, e), &e))?;
+        parse_snapshot(&contents)
+            .map_err(|e| Error::new(&format!("Invalid snapshot {}: {}", name, e)))
+    }
+
+    /// Snapshots of the repository, sorted by name
+    pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>, Error> {
+        let dir = self.snapshots_dir();
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| Error::from_io(&format!("Could not read {:?}: {}", dir, e), &e))?;
+        let mut res = vec![];
+        for entry in entries {
+            let entry = entry.map_err(|e| {
+                Error::from_io(&format!("Could not read entry in {:?}: {}", dir, e), &e)
+            })?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Snapshots being written
+            if name.starts_with('.') {
+                continue;
+            }
+            let mut info = SnapshotInfo {
+                name,
+                files: 0,
+                bytes: 0,
+            };
+            for record in self.read_snapshot(&info.name)? {
+                if let SnapshotRecord::File { size, .. } = record {
+                    info.files += 1;
+                    info.bytes += size;
+                }
+            }
+            res.push(info);
+        }
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(res)
+    }
+
+    /// Write the entries of a snapshot to `dest`, which must not exist or
+    /// be empty. Returns the number of entries restored
+    pub fn restore(&self, name: &str, dest: &Path) -> Result<usize, Error> {
+        let records = self.read_snapshot(name)?;
+        let is_empty = match fs::read_dir(dest) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(_) => !dest.exists(),
+        };
+        if !is_empty {
+            return Err(Error::new(&format!(
+                "Refusing to restore to {:?}: it is not empty",
+                dest
+            )));
+        }
+        fs::create_dir_all(dest)
+            .map_err(|e| Error::from_io(&format!("Could not create {:?}: {}", dest, e), &e))?;
+        let mut dirs = vec![];
+        for record in &records {
+            match record {
+                SnapshotRecord::Dir { path, mode, mtime } => {
+                    let dir = self.restored_path(dest, path)?;
+                    fs::create_dir_all(&dir).map_err(|e| {
+                        Error::from_io(&format!("Could not create {:?}: {}", dir, e), &e)
+                    })?;
+                    dirs.push((dir, *mode, *mtime));
+                }
+                SnapshotRecord::File {
+                    path,
+                    mode,
+                    mtime,
+                    size,
+                    chunks,
+                } => {
+                    let file = self.restored_path(dest, path)?;
+                    self.restore_file(&file, *size, chunks)?;
+                    set_mode(&file, *mode)?;
+                    set_mtime(&file, *mtime)?;
+                }
+                SnapshotRecord::Link { path, target } => {
+                    let link = self.restored_path(dest, path)?;
+                    restore_link(&link, target)?;
+                }
+            }
+        }
+        // Setting the modification time of a directory must be done after
+        // its contents are written, and permissions after that, in case the
+        // directory is read-only
+        dirs.sort_by_key(|(dir, _, _)| std::cmp::Reverse(dir.components().count()));
+        for (dir, mode, mtime) in dirs {
+            set_mtime(&dir, mtime)?;
+            set_mode(&dir, mode)?;
+        }
+        Ok(records.len())
+    }
+
+    // Where `path` of a snapshot goes in `dest`, making sure it stays there
+    fn restored_path(&self, dest: &Path, path: &Path) -> Result<PathBuf, Error> {
+        let is_relative = path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if !is_relative {
+            return Err(Error::new(&format!(
+                "Refusing to restore {:?}: not a path relative to the snapshot",
+                path
+            )));
+        }
+        let res = dest.join(path);
+        if let Some(parent) = res.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                Error::from_io(&format!("Could not create {:?}: {}", parent, e), &e)
+            })?;
+        }
+        Ok(res)
+    }
+
+    fn restore_file(&self, file: &Path, size: u64, chunks: &[String]) -> Result<(), Error> {
+        let mut out = File::create(file).map_err(|e| {
+            Error::from_io(&format!("Could not open {:?} for writing: {}", file, e), &e)
+        })?;
+        let mut written = 0;
+        for id in chunks {
+            let chunk_path = self.chunk_path(id);
+            let data = fs::read(&chunk_path)
+                .map_err(|e| Error::from_io(&format!("Could not read chunk {}: {}", id, e), &e))?;
+            let mut hasher = Sha256::new();
+            hasher.update(&data);
+            if hasher.hex_digest() != *id {
+                return Err(Error::new(&format!("Chunk {} is corrupted", id)));
+            }
+            out.write_all(&data).map_err(|e| {
+                Error::from_io(&format!("Could not write to {:?}: {}", file, e), &e)
+            })?;
+            written += data.len() as u64;
+        }
+        if written != size {
+            return Err(Error::new(&format!(
+                "Restored {} bytes for {:?}, expected {}",
+                written, file, size
+            )));
+        }
+        Ok(())
+    }
+
+    fn chunks_dir(&self) -> PathBuf {
+        self.root.join("chunks")
+    }
+
+    fn snapshots_dir(&self) -> PathBuf {
+        self.root.join("snapshots")
+    }
+
+    fn chunk_path(&self, id: &str) -> PathBuf {
+        self.chunks_dir().join(&id[0..2]).join(id)
+    }
+
+    // Returns false if the chunk was already present
+    fn write_chunk(&self, id: &str, data: &[u8]) -> Result<bool, Error> {
+        let chunk_path = self.chunk_path(id);
+        if chunk_path.exists() {
+            return Ok(false);
+        }
+        // Write to a temporary file first, so that an interrupted
+        // run never leaves a truncated chunk behind
+        let parent = chunk_path
+            .parent()
+            .ok_or_else(|| Error::new(&format!("Could not get parent path of {:?}", chunk_path)))?;
+        fs::create_dir_all(parent)
+            .map_err(|e| Error::from_io(&format!("Could not create {:?}: {}", parent, e), &e))?;
+        let tmp_path = parent.join(format!(".{}.tmp", id));
+        let mut tmp_file = File::create(&tmp_path).map_err(|e| {
+            Error::from_io(
+                &format!("Could not open {:?} for writing: {}", tmp_path, e),
+                &e,
+            )
+        })?;
+        tmp_file.write_all(data).map_err(|e| {
+            Error::from_io(&format!("Could not write to {:?}: {}", tmp_path, e), &e)
+        })?;
+        fs::rename(&tmp_path, &chunk_path).map_err(|e| {
+            Error::from_io(
+                &format!("Could not rename {:?} to {:?}: {}", tmp_path, chunk_path, e),
+                &e,
+            )
+        })?;
+        Ok(true)
+    }
+}
+
+fn set_mtime(path: &Path, mtime: u64) -> Result<(), Error> {
+    let mtime =
+        FileTime::from_seconds_since_1970(mtime / 1_000_000_000, (mtime % 1_000_000_000) as u32);
+    filetime::set_file_times(path, mtime, mtime).map_err(|e| {
+        Error::from_io(
+            &format!("Could not set modification time of {:?}: {}", path, e),
+            &e,
+        )
+    })
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|e| {
+        Error::from_io(
+            &format!("Could not set permissions of {:?}: {}", path, e),
+            &e,
+        )
+    })
+}
+
+#[cfg(windows)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    let metadata = fs::metadata(path).map_err(|e| {
+        Error::from_io(&format!("Could not read metadata of {:?}: {}", path, e), &e)
+    })?;
+    let mut permissions = metadata.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions).map_err(|e| {
+        Error::from_io(
+            &format!("Could not set permissions of {:?}: {}", path, e),
+            &e,
+        )
+    })
+}
+
+#[cfg(unix)]
+fn restore_link(link: &Path, target: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(target, link)
+        .map_err(|e| Error::from_io(&format!("Could not create link {:?}: {}", link, e), &e))
+}
+
+#[cfg(windows)]
+fn restore_link(_link: &Path, _target: &Path) -> Result<(), Error> {
+    // Like when syncing, see fsops::copy_link()
+    Ok(())
+}
+
+// Like read(), but keep reading until the buffer is full or we reach EOF,
+// so that chunks boundaries do not depend on how the OS splits reads
+fn read_chunk(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        let num_read = file.read(&mut buffer[total..])?;
+        if num_read == 0 {
+            break;
+        }
+        total += num_read;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+
+    fn store_test_file(store: &ChunkStore, path: &Path) -> (Vec<String>, bool) {
+        let (sender, _receiver) = mpsc::channel::<ProgressMessage>();
+        let entry = Entry::new("test", path);
+        store.store_file(&sender, &entry).unwrap()
+    }
+
+    #[test]
+    fn identical_files_share_chunks() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-chunk-store")?;
+        let tmp_path = tmp_dir.path();
+        let store = ChunkStore::open(&tmp_path.join("store")).unwrap();
+        let one = tmp_path.join("one.txt");
+        let two = tmp_path.join("two.txt");
+        fs::write(&one, "same contents")?;
+        fs::write(&two, "same contents")?;
+
+        let (first_chunks, wrote) = store_test_file(&store, &one);
+        assert!(wrote);
+        let (second_chunks, wrote) = store_test_file(&store, &two);
+        assert!(!wrote);
+        assert_eq!(first_chunks, second_chunks);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_written_snapshots() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-chunk-store")?;
+        let store = ChunkStore::open(tmp_dir.path()).unwrap();
+        let records = vec![
+            SnapshotRecord::Dir {
+                path: PathBuf::from("empty"),
+                mode: 0o755,
+                mtime: 1_600_000_000_123_456_789,
+            },
+            SnapshotRecord::File {
+                path: PathBuf::from("tab\tand\nnew line"),
+                mode: 0o644,
+                mtime: 1_600_000_000_000_000_000,
+                size: 0,
+                chunks: vec![],
+            },
+            SnapshotRecord::Link {
+                path: PathBuf::from("a\\link"),
+                target: PathBuf::from("../x\ty"),
+            },
+        ];
+        store.write_snapshot("first", &records).unwrap();
+
+        assert_eq!(store.read_snapshot("first").unwrap(), records);
+        assert!(parse_snapshot("file\t644\t0\t0\t\tfoo\n").is_err());
+        assert!(parse_snapshot("rusync-snapshot 1\nfile\t644\t0\t0\tnot-a-chunk\tfoo\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_snapshot_names() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-chunk-store")?;
+        let store = ChunkStore::open(tmp_dir.path()).unwrap();
+        assert!(store.snapshot_path("2018-10-01").is_ok());
+        assert!(store.snapshot_path("../escape").is_err());
+        assert!(store.snapshot_path("").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! conflict
+//!
+//! Decide what to do with files modified in both the source and the
+//! destination since the last sync, according to `SyncOptions::on_conflict`.
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fsops;
+use crate::sync::ConflictPolicy;
+
+const MARKER: &str = ".rusync-conflict-";
+
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    /// Replace the destination with the source
+    CopySource,
+    KeepDestination,
+    /// Move the destination out of the way, then copy the source
+    RenameDestination,
+}
+
+/// What is looked at in each side of a conflict
+#[derive(Clone, Copy, Debug)]
+pub struct Version {
+    /// Modification time, in nanoseconds since the epoch
+    pub mtime: u64,
+    pub size: u64,
+}
+
+impl Version {
+    pub fn of(metadata: &Metadata) -> Version {
+        Version {
+            mtime: fsops::precise_mtime(metadata),
+            size: metadata.len(),
+        }
+    }
+}
+
+/// None if the file did not change on both sides since `last_sync`
+pub fn resolve(
+    policy: ConflictPolicy,
+    last_sync: SystemTime,
+    src: Version,
+    dest: Version,
+) -> Option<Resolution> {
+    let last_sync = last_sync
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    if src.mtime <= last_sync || dest.mtime <= last_sync {
+        return None;
+    }
+    let resolution = match policy {
+        ConflictPolicy::SourceWins => Resolution::CopySource,
+        ConflictPolicy::DestWins => Resolution::KeepDestination,
+        ConflictPolicy::NewerWins => {
+            if dest.mtime > src.mtime {
+                Resolution::KeepDestination
+            } else {
+                Resolution::CopySource
+            }
+        }
+        ConflictPolicy::LargerWins => {
+            if dest.size > src.size {
+                Resolution::KeepDestination
+            } else {
+                Resolution::CopySource
+            }
+        }
+        ConflictPolicy::RenameBoth => Resolution::RenameDestination,
+    };
+    Some(resolution)
+}
+
+/// Where the destination file goes with `ConflictPolicy::RenameBoth`
+pub fn renamed_path(dest: &Path, version: Version) -> PathBuf {
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let secs = version.mtime / 1_000_000_000;
+    dest.with_file_name(format!("{}{}{}", name, MARKER, secs))
+}
+
+/// True if `rel_path` is a renamed destination file of a file of the
+/// source, so must not be deleted as extraneous
+pub fn is_needed(rel_path: &Path, source_paths: &HashSet<PathBuf>) -> bool {
+    let name = match rel_path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    match name.rfind(MARKER) {
+        Some(i) if i > 0 => source_paths.contains(&rel_path.with_file_name(&name[..i])),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+    use filetime::FileTime;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn resolve_conflicts() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-conflict")?;
+        let src = tmp_dir.path().join("src.txt");
+        let dest = tmp_dir.path().join("dest.txt");
+        fs::write(&src, "new source")?;
+        fs::write(&dest, "bigger destination")?;
+        let now = SystemTime::now();
+        let secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let before = FileTime::from_seconds_since_1970(secs - 20, 0);
+        let after = FileTime::from_seconds_since_1970(secs - 10, 0);
+        filetime::set_file_times(&src, after, after)?;
+        filetime::set_file_times(&dest, before, before)?;
+        let last_sync = now - Duration::from_secs(30);
+        let resolution = |policy| {
+            let src_version = Version::of(&fs::metadata(&src).unwrap());
+            let dest_version = Version::of(&fs::metadata(&dest).unwrap());
+            resolve(policy, last_sync, src_version, dest_version)
+        };
+
+        assert_eq!(
+            resolution(ConflictPolicy::SourceWins),
+            Some(Resolution::CopySource)
+        );
+        assert_eq!(
+            resolution(ConflictPolicy::DestWins),
+            Some(Resolution::KeepDestination)
+        );
+        assert_eq!(
+            resolution(ConflictPolicy::NewerWins),
+            Some(Resolution::CopySource)
+        );
+        assert_eq!(
+            resolution(ConflictPolicy::LargerWins),
+            Some(Resolution::KeepDestination)
+        );
+        assert_eq!(
+            resolution(ConflictPolicy::RenameBoth),
+            Some(Resolution::RenameDestination)
+        );
+
+        // Only the source changed since the last sync
+        let last_sync = now - Duration::from_secs(15);
+        let src_version = Version::of(&fs::metadata(&src)?);
+        let dest_version = Version::of(&fs::metadata(&dest)?);
+        assert_eq!(
+            resolve(
+                ConflictPolicy::DestWins,
+                last_sync,
+                src_version,
+                dest_version
+            ),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keep_renamed_files() {
+        let source_paths: HashSet<PathBuf> = vec![PathBuf::from("a/b.txt")].into_iter().collect();
+        let is_kept = |p| is_needed(Path::new(p), &source_paths);
+        assert!(is_kept("a/b.txt.rusync-conflict-1700000000"));
+        assert!(!is_kept("a/c.txt.rusync-conflict-1700000000"));
+        assert!(!is_kept("a/.rusync-conflict-1700000000"));
+        assert!(!is_kept("a/b.txt"));
+    }
+}
@@ -1,35 +1,203 @@
 //! console_info
 //!
 //! Display transfer progress to the command line
+//!
+//! The progress line, and what only makes sense next to it, goes to stderr
+//! by default (see `ConsoleProgressInfo::progress_to`), so that stdout only
+//! has the changes of a dry run and the final stats. With
+//! `ConsoleProgressInfo::print_hashes`, stdout only has the hashes instead
 
-use crate::progress::{Progress, ProgressInfo};
+use crate::progress::{PlannedChange, Progress, ProgressInfo};
 use crate::sync;
-use colored::Colorize;
+use colored::{ColoredString, Colorize};
+use std::cell::{Cell, RefCell};
 use std::io;
 use std::io::Write;
 use term_size;
 
+// println!(), or eprintln!() if stdout is kept for the hashes
+macro_rules! report {
+    ($info:expr, $($arg:tt)*) => {
+        if $info.print_hashes {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
 #[derive(Default)]
-pub struct ConsoleProgressInfo {}
+pub struct ConsoleProgressInfo {
+    /// Also print what the copied files were, by extension and
+    /// by size, and the largest ones
+    pub detailed_stats: bool,
+    /// Before the changes of a dry run, tell what their colors mean
+    pub legend: bool,
+    legend_shown: Cell<bool>,
+    /// Print the hashes of the files read, in the format of sha256sum, and
+    /// write the changes of a dry run and the stats to stderr instead
+    pub print_hashes: bool,
+    // Where the progress goes, stderr if None
+    progress_out: RefCell<Option<Box<dyn Write + Send>>>,
+}
 
 impl ConsoleProgressInfo {
     pub fn new() -> ConsoleProgressInfo {
-        ConsoleProgressInfo {}
+        ConsoleProgressInfo {
+            detailed_stats: false,
+            legend: true,
+            legend_shown: Cell::new(false),
+            print_hashes: false,
+            progress_out: RefCell::new(None),
+        }
+    }
+
+    /// Write the progress line, the legend of dry runs and the name of
+    /// the directories being synced to `out` instead of stderr
+    pub fn progress_to(&mut self, out: Box<dyn Write + Send>) {
+        self.progress_out = RefCell::new(Some(out));
+    }
+
+    fn write_progress(&self, text: &str) {
+        let mut progress_out = self.progress_out.borrow_mut();
+        let _ = match progress_out.as_mut() {
+            Some(out) => out.write_all(text.as_bytes()).and_then(|_| out.flush()),
+            None => {
+                let mut stderr = io::stderr();
+                stderr
+                    .write_all(text.as_bytes())
+                    .and_then(|_| stderr.flush())
+            }
+        };
+    }
+
+    fn erase_line(&self) {
+        let line_width = get_terminal_width();
+        self.write_progress(&format!("{}\r", " ".repeat(line_width)));
+    }
+
+    // For what is reported: escape codes are only for terminals,
+    // not for files and pipes
+    fn color(&self, text: &str, color: &str) -> ColoredString {
+        if is_terminal(if self.print_hashes { 2 } else { 1 }) {
+            text.color(color)
+        } else {
+            text.normal()
+        }
+    }
+
+    fn print_phase(&self, action: &str, index: usize, total: usize, description: &str) {
+        let counter = format!("{} {}/{} ", action, index, total);
+        let file_width = get_terminal_width().saturating_sub(counter.len() + 1);
+        let description = truncate_lossy(description, file_width);
+        self.write_progress(&format!(
+            "{}{filename:<pad$}\r",
+            counter,
+            pad = file_width,
+            filename = description
+        ));
+    }
+
+    fn print_timings(&self, timings: &sync::PhaseTimings) {
+        let seconds = |d: std::time::Duration| format!("{:.1}s", d.as_secs_f64());
+        report!(
+            self,
+            "{} Time spent: scan {}, compare {}, copy {}, delete {}, fixing metadata {}",
+            self.color("::", "blue"),
+            seconds(timings.scan),
+            seconds(timings.compare),
+            seconds(timings.copy),
+            seconds(timings.delete),
+            seconds(timings.fix_metadata)
+        );
+    }
+
+    // Like progress(), for the phases after the copies
+    fn print_transfer_stats(&self, transfers: &sync::TransferStats) {
+        report!(
+            self,
+            "{} Copied files by extension:",
+            self.color("::", "blue")
+        );
+        let mut by_extension: Vec<_> = transfers.by_extension.iter().collect();
+        by_extension.sort_by_key(|(_, tally)| std::cmp::Reverse(tally.bytes));
+        for (extension, tally) in by_extension {
+            let extension = if extension.is_empty() {
+                "(none)".to_string()
+            } else {
+                format!(".{}", extension)
+            };
+            report!(
+                self,
+                "  {:<12} {:>8} files {:>10}",
+                extension,
+                tally.count,
+                human_size(tally.bytes)
+            );
+        }
+        report!(self, "{} Copied files by size:", self.color("::", "blue"));
+        for (bucket, tally) in &transfers.by_size {
+            report!(
+                self,
+                "  >= {:<9} {:>8} files {:>10}",
+                human_size(*bucket),
+                tally.count,
+                human_size(tally.bytes)
+            );
+        }
+        report!(self, "{} Largest files copied:", self.color("::", "blue"));
+        for (description, size) in &transfers.largest {
+            report!(self, "  {:>10} {}", human_size(*size), description);
+        }
     }
 }
 
 impl ProgressInfo for ConsoleProgressInfo {
     fn done_syncing(&self) {
-        erase_line();
+        self.erase_line();
+    }
+
+    fn warning(&self, message: &str) {
+        self.erase_line();
+        eprintln!("{} {}", "Warning:".color("yellow"), message);
+    }
+
+    fn planned(&self, change: PlannedChange, description: &str) {
+        if self.legend && !self.legend_shown.replace(true) {
+            self.write_progress(&format!(
+                "{} {}, {}, {}\n",
+                "::".color("blue"),
+                "new".color("green"),
+                "updated".color("yellow"),
+                "deleted".color("red")
+            ));
+        }
+        // Pad before coloring, since escape codes would count in the width
+        let name = format!("{:<7}", change.name());
+        let color = match change {
+            PlannedChange::CopyFile | PlannedChange::CreateDir | PlannedChange::CreateLink => {
+                "green"
+            }
+            PlannedChange::Remove => "red",
+            _ => "yellow",
+        };
+        let name = self.color(&name, color);
+        report!(self, "{} {}", name, description);
+    }
+
+    fn hashed(&self, digest: &str, description: &str) {
+        if self.print_hashes {
+            println!("{}", checksum_line(digest, description));
+        }
     }
 
     fn start(&self, source: &str, destination: &str) {
-        println!(
-            "{} Syncing from {} to {} …",
+        self.write_progress(&format!(
+            "{} Syncing from {} to {} …\n",
             "::".color("blue"),
             source.bold(),
             destination.bold()
-        )
+        ));
     }
 
     fn new_file(&self, _name: &str) {}
@@ -38,41 +206,137 @@ impl ProgressInfo for ConsoleProgressInfo {
         let eta_str = human_seconds(progress.eta);
         let percent_width = 3;
         let eta_width = eta_str.len();
-        let index = progress.index;
+        // Count directories and symlinks too, so that trees with few
+        // files still show something is happening
+        let index = progress.entries_done;
         let index_width = index.to_string().len();
-        let num_files = progress.num_files;
-        let num_files_width = num_files.to_string().len();
-        let widgets_width = percent_width + index_width + num_files_width + eta_width;
+        let num_entries = progress.num_entries;
+        let num_entries_width = num_entries.to_string().len();
+        let widgets_width = percent_width + index_width + num_entries_width + eta_width;
         let num_separators = 5;
         let line_width = get_terminal_width();
         let file_width = line_width - widgets_width - num_separators - 1;
         let current_file = progress.current_file.clone();
-        let current_file = truncate_lossy(&current_file, file_width as usize);
+        let current_file = truncate_lossy(&current_file, file_width);
         let current_file = format!(
             "{filename:<pad$}",
-            pad = file_width as usize,
+            pad = file_width,
             filename = current_file
         );
-        let file_percent = ((progress.file_done * 100) as usize) / progress.file_size;
-        print!(
+        let file_percent = (progress.file_done * 100)
+            .checked_div(progress.file_size)
+            .unwrap_or(100);
+        self.write_progress(&format!(
             "{:>3}% {}/{} {} {:<}\r",
-            file_percent, index, num_files, current_file, eta_str
-        );
-        let _ = io::stdout().flush();
+            file_percent, index, num_entries, current_file, eta_str
+        ));
+    }
+
+    fn deleting(&self, index: usize, total: usize, description: &str) {
+        self.print_phase("deleting", index, total, description);
+    }
+
+    fn fixing_metadata(&self, index: usize, total: usize, description: &str) {
+        self.print_phase("fixing", index, total, description);
     }
 
     fn end(&self, stats: &sync::Stats) {
-        println!(
+        self.erase_line();
+        if stats.dry_run {
+            report!(
+                self,
+                "{} Dry run: {} files would be copied ({}), {} symlinks created, {} symlinks updated, {} directories created, {} entries removed",
+                self.color("::", "blue"),
+                stats.copied,
+                human_size(stats.transfers.total_bytes()),
+                stats.symlink_created,
+                stats.symlink_updated,
+                stats.dirs_created,
+                stats.files_removed + stats.symlinks_removed + stats.dirs_removed
+            );
+            return;
+        }
+        report!(
+            self,
             "{} Synced {} files ({} up to date)",
-            " ✓".color("green"),
+            self.color(" ✓", "green"),
             stats.num_synced,
             stats.up_to_date
         );
-        println!(
-            "{} files copied, {} symlinks created, {} symlinks updated",
-            stats.copied, stats.symlink_created, stats.symlink_updated
+        report!(
+            self,
+            "{} files copied, {} symlinks created, {} symlinks updated, {} directories created",
+            stats.copied,
+            stats.symlink_created,
+            stats.symlink_updated,
+            stats.dirs_created
         );
+        if stats.symlink_skipped > 0 {
+            report!(self, "{} unsafe symlinks skipped", stats.symlink_skipped);
+        }
+        if stats.dirs_renamed > 0 {
+            report!(self, "{} directories renamed", stats.dirs_renamed);
+        }
+        if stats.verified > 0 {
+            report!(self, "{} copied files verified", stats.verified);
+        }
+        if stats.errors_skipped > 0 {
+            report!(
+                self,
+                "{} entries skipped because of errors",
+                stats.errors_skipped
+            );
+        }
+        if stats.errors_ignored > 0 {
+            report!(self, "{} errors ignored", stats.errors_ignored);
+        }
+        if stats.conflicts > 0 {
+            report!(self, "{} files changed on both sides", stats.conflicts);
+        }
+        if stats.files_removed + stats.symlinks_removed + stats.dirs_removed > 0 {
+            report!(
+                self,
+                "{} files, {} symlinks and {} directories removed",
+                stats.files_removed,
+                stats.symlinks_removed,
+                stats.dirs_removed
+            );
+        }
+        if self.detailed_stats && stats.copied > 0 {
+            self.print_transfer_stats(&stats.transfers);
+        }
+        if self.detailed_stats {
+            self.print_timings(&stats.timings);
+        }
+        if let Some(ref reason) = stats.stopped {
+            report!(
+                self,
+                "{} Stopped early ({}): run again to sync the rest",
+                self.color("::", "yellow"),
+                reason
+            );
+        }
+    }
+}
+
+// Like sha256sum, escape backslashes and newlines, and
+// tell it by starting the line with a backslash
+fn checksum_line(digest: &str, description: &str) -> String {
+    if !description.contains('\\') && !description.contains('\n') {
+        return format!("{}  {}", digest, description);
     }
+    let escaped = description.replace('\\', "\\\\").replace('\n', "\\n");
+    format!("\\{}  {}", digest, escaped)
+}
+
+#[cfg(unix)]
+fn is_terminal(fd: i32) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+#[cfg(not(unix))]
+fn is_terminal(_fd: i32) -> bool {
+    true
 }
 
 fn get_terminal_width() -> usize {
@@ -84,19 +348,26 @@ fn get_terminal_width() -> usize {
     80
 }
 
-fn erase_line() {
-    let line_width = get_terminal_width();
-    let line = vec![32 as u8; line_width as usize];
-    // We're calling from_utf8 on a string containing only spaces,
-    // so calling unwrap() is safe
-    print!("{}\r", String::from_utf8(line).unwrap());
-}
-
 fn human_seconds(s: usize) -> String {
     let hours = s / 3600;
     let minutes = (s / 60) % 60;
     let seconds = s % 60;
-    return format!("{:02}:{:02}:{:02}", hours, minutes, seconds);
+    format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+}
+
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
 }
 
 fn truncate_lossy(text: &str, maxsize: usize) -> String {
@@ -121,6 +392,15 @@ mod test {
         assert_eq!(new_text, "é");
     }
 
+    #[test]
+    fn test_human_size() {
+        assert_eq!("0 B", human_size(0));
+        assert_eq!("1023 B", human_size(1023));
+        assert_eq!("1.0 KiB", human_size(1024));
+        assert_eq!("1.5 MiB", human_size(3 << 19));
+        assert_eq!("100.0 MiB", human_size(100 << 20));
+    }
+
     #[test]
     fn test_human_seconds() {
         assert_eq!("00:00:05", human_seconds(5));
@@ -130,4 +410,37 @@ mod test {
         assert_eq!("200:00:02", human_seconds(720_002));
     }
 
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_progress_to() {
+        let buffer = SharedBuffer::default();
+        let mut console_info = ConsoleProgressInfo::new();
+        console_info.progress_to(Box::new(buffer.clone()));
+        console_info.start("src", "dest");
+        console_info.deleting(1, 2, "old.txt");
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(written.contains("Syncing from"));
+        assert!(written.contains("deleting 1/2 old.txt"));
+    }
+
+    #[test]
+    fn test_checksum_line() {
+        assert_eq!(checksum_line("abc", "a/b.txt"), "abc  a/b.txt");
+        assert_eq!(
+            checksum_line("abc", "back\\slash\nnew line"),
+            "\\abc  back\\\\slash\\nnew line"
+        );
+    }
 }
@@ -0,0 +1,136 @@
+//! dupes
+//!
+//! Find files with the same contents in a tree, to tell how much of it
+//! is redundant. Only files of the same size are hashed.
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::entry::Entry;
+use crate::error::Error;
+use crate::fsops;
+use crate::manifest::{Manifest, ManifestEntry};
+
+/// Files with the same contents
+#[derive(Debug, PartialEq)]
+pub struct Group {
+    /// Size of each file
+    pub size: u64,
+    /// Relative to the directory, sorted
+    pub paths: Vec<PathBuf>,
+}
+
+impl Group {
+    /// What would be saved by keeping only one of the files
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+/// Groups of duplicates in `dir`, most wasteful first. Empty files and
+/// symlinks are ignored
+pub fn find(dir: &Path) -> Result<Vec<Group>, Error> {
+    let manifest = Manifest::from_tree(dir, false)?;
+    let mut by_size: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for (rel_path, entry) in manifest.entries {
+        if let ManifestEntry::File { size, .. } = entry {
+            if size > 0 {
+                by_size.entry(size).or_default().push(rel_path);
+            }
+        }
+    }
+    let mut groups = vec![];
+    for (size, paths) in by_size {
+        if paths.len() < 2 {
+            continue;
+        }
+        let mut by_digest: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for rel_path in paths {
+            let entry = Entry::new(&rel_path.to_string_lossy(), &dir.join(&rel_path));
+            let digest = fsops::file_digest(&entry)?;
+            by_digest.entry(digest).or_default().push(rel_path);
+        }
+        groups.extend(
+            by_digest
+                .into_values()
+                .filter(|paths| paths.len() > 1)
+                .map(|paths| Group { size, paths }),
+        );
+    }
+    groups.sort_by(|a, b| b.wasted().cmp(&a.wasted()).then(a.paths.cmp(&b.paths)));
+    Ok(groups)
+}
+
+/// Each group with its files, and a total
+pub fn report(groups: &[Group]) -> String {
+    let mut res = String::new();
+    for group in groups {
+        res.push_str(&format!(
+            "{} copies of {} bytes, {} bytes wasted:\n",
+            group.paths.len(),
+            group.size,
+            group.wasted()
+        ));
+        for path in &group.paths {
+            res.push_str(&format!("    {}\n", path.to_string_lossy()));
+        }
+    }
+    let files: usize = groups.iter().map(|g| g.paths.len() - 1).sum();
+    let wasted: u64 = groups.iter().map(Group::wasted).sum();
+    res.push_str(&format!(
+        "{} groups of duplicates: {} files, {} bytes could be saved\n",
+        groups.len(),
+        files,
+        wasted
+    ));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+    use std::fs;
+
+    #[test]
+    fn find_duplicates() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-dupes")?;
+        let root = tmp_dir.path();
+        fs::create_dir_all(root.join("a/b"))?;
+        fs::write(root.join("one.txt"), "same")?;
+        fs::write(root.join("a/one.txt"), "same")?;
+        fs::write(root.join("a/b/one.txt"), "same")?;
+        // Same size, different contents
+        fs::write(root.join("a/other.txt"), "diff")?;
+        fs::write(root.join("big.bin"), "bigger file")?;
+        fs::write(root.join("a/big.bin"), "bigger file")?;
+        fs::write(root.join("empty"), "")?;
+        fs::write(root.join("a/empty"), "")?;
+
+        let groups = find(root).unwrap();
+
+        assert_eq!(
+            groups,
+            vec![
+                Group {
+                    size: 11,
+                    paths: vec![PathBuf::from("a/big.bin"), PathBuf::from("big.bin")],
+                },
+                Group {
+                    size: 4,
+                    paths: vec![
+                        PathBuf::from("a/b/one.txt"),
+                        PathBuf::from("a/one.txt"),
+                        PathBuf::from("one.txt"),
+                    ],
+                },
+            ]
+        );
+        assert!(
+            report(&groups).ends_with("2 groups of duplicates: 3 files, 19 bytes could be saved\n")
+        );
+        Ok(())
+    }
+}
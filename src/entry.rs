@@ -14,21 +14,26 @@ pub struct Entry {
 
 impl Entry {
     pub fn new(description: &str, entry_path: &Path) -> Entry {
-        let mut metadata = fs::metadata(&entry_path).ok();
-        let is_link;
-        let symlink_metadata = fs::symlink_metadata(&entry_path);
-        if let Ok(data) = symlink_metadata {
-            is_link = Some(data.file_type().is_symlink());
-            metadata = Some(data);
-        } else {
-            is_link = None;
-        }
+        // A single lstat() is enough, except for symlinks, where
+        // we still have to check whether the target exists
+        let (metadata, is_link) = match fs::symlink_metadata(entry_path) {
+            Ok(data) => {
+                let is_link = data.file_type().is_symlink();
+                (Some(data), Some(is_link))
+            }
+            Err(_) => (None, None),
+        };
+        let exists = match is_link {
+            Some(true) => entry_path.exists(),
+            Some(false) => true,
+            None => false,
+        };
 
         Entry {
             description: String::from(description),
             metadata,
             path: entry_path.to_path_buf(),
-            exists: entry_path.exists(),
+            exists,
             is_link,
         }
     }
@@ -50,6 +55,11 @@ impl Entry {
     pub fn is_link(&self) -> Option<bool> {
         self.is_link
     }
+
+    /// True for actual directories, false for symlinks to directories
+    pub fn is_dir(&self) -> bool {
+        self.metadata.as_ref().map_or(false, |m| m.is_dir())
+    }
 }
 
 #[cfg(test)]
@@ -57,11 +67,13 @@ mod tests {
 
     use super::Entry;
     use super::Path;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
 
     #[test]
     fn new_entry_with_non_existing_path() {
         let path = Path::new("/path/to/nosuch.txt");
-        let entry = Entry::new("nosuch", &path);
+        let entry = Entry::new("nosuch", path);
 
         assert!(!entry.exists());
         assert!(entry.metadata.is_none());
@@ -70,7 +82,7 @@ mod tests {
     #[test]
     fn new_entry_with_existing_path() {
         let path = Path::new(file!());
-        let entry = Entry::new("entry.rs", &path);
+        let entry = Entry::new("entry.rs", path);
 
         assert!(entry.exists());
         assert!(entry.metadata.is_some());
@@ -79,4 +91,17 @@ mod tests {
         assert!(!is_link.unwrap());
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn new_entry_with_broken_link() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-entry")?;
+        let link_path = tmp_dir.path().join("broken");
+        std::os::unix::fs::symlink("nosuch", &link_path)?;
+        let entry = Entry::new("broken", &link_path);
+
+        assert!(!entry.exists());
+        assert!(entry.metadata.is_some());
+        assert_eq!(entry.is_link(), Some(true));
+        Ok(())
+    }
 }
@@ -1,14 +1,31 @@
+use std::io;
+
 #[derive(Debug)]
 pub struct Error {
     message: String,
+    kind: Option<io::ErrorKind>,
 }
 
 impl Error {
     pub fn new(message: &str) -> Self {
         Error {
             message: message.to_string(),
+            kind: None,
+        }
+    }
+
+    /// `message` tells what failed because of `err`
+    pub fn from_io(message: &str, err: &io::Error) -> Self {
+        Error {
+            message: message.to_string(),
+            kind: Some(err.kind()),
         }
     }
+
+    /// What kind of I/O error caused this one, if any
+    pub fn kind(&self) -> Option<io::ErrorKind> {
+        self.kind
+    }
 }
 
 impl std::fmt::Display for Error {
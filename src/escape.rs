@@ -0,0 +1,125 @@
+//! escape
+//!
+//! Write any path as a field of a tab-separated, line-oriented text file.
+//! Backslashes, tabs, carriage returns and newlines are escaped as in C,
+//! and bytes that are not valid UTF-8 as `\xHH`, so that paths can be read
+//! back exactly as they were.
+use std::path::Path;
+use std::path::PathBuf;
+
+pub fn escape(bytes: &[u8]) -> String {
+    let mut res = String::new();
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                push_escaped(&mut res, valid);
+                return res;
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                push_escaped(
+                    &mut res,
+                    std::str::from_utf8(valid).expect("valid up to here"),
+                );
+                let invalid_len = e.error_len().unwrap_or(invalid.len());
+                for byte in &invalid[..invalid_len] {
+                    res.push_str(&format!("\\x{:02x}", byte));
+                }
+                rest = &invalid[invalid_len..];
+            }
+        }
+    }
+}
+
+fn push_escaped(res: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            '\n' => res.push_str("\\n"),
+            c => res.push(c),
+        }
+    }
+}
+
+pub fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("invalid escape sequence in {:?}", text);
+    let mut res = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            res.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => res.push(b'\\'),
+            Some('t') => res.push(b'\t'),
+            Some('r') => res.push(b'\r'),
+            Some('n') => res.push(b'\n'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 {
+                    return Err(invalid());
+                }
+                res.push(u8::from_str_radix(&hex, 16).map_err(|_| invalid())?);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(res)
+}
+
+pub fn escape_path(path: &Path) -> String {
+    escape(&path_bytes(path))
+}
+
+pub fn unescape_path(text: &str) -> Result<PathBuf, String> {
+    unescape(text).map(path_from_bytes)
+}
+
+/// The bytes of `path`, as given by the OS on Unix
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(windows)]
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(windows)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes = b"tab\there\nnew line \\ back\xff\xfeslash \xc3\xa9t\xc3";
+        let escaped = escape(bytes);
+        assert_eq!(
+            escaped,
+            "tab\\there\\nnew line \\\\ back\\xff\\xfeslash ét\\xc3"
+        );
+        assert!(!escaped.contains('\t') && !escaped.contains('\n'));
+        assert_eq!(unescape(&escaped).unwrap(), bytes.to_vec());
+        assert!(unescape("trailing\\").is_err());
+        assert!(unescape("\\x4").is_err());
+        assert!(unescape("\\q").is_err());
+    }
+}
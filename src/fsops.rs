@@ -1,23 +1,38 @@
 extern crate pathdiff;
 
-use std;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 #[cfg(unix)]
 use std::os::unix;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
+use std::time::Instant;
 
 use filetime::FileTime;
 
+use crate::blocks;
+use crate::checkpoint;
 use crate::entry::Entry;
 use crate::error::Error;
+use crate::hash::Sha256;
+use crate::json;
 use crate::progress::ProgressMessage;
+use crate::sync::{LinkRewrite, LongNames, Policy, SyncOptions};
+use crate::tune::BufferTuner;
 
-const BUFFER_SIZE: usize = 100 * 1024;
+pub(crate) const BUFFER_SIZE: usize = 100 * 1024;
+
+// The jobs of a batch run in the same process, at the same time
+static TEMP_NAMES: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(PartialEq, Debug)]
 pub enum SyncOutcome {
@@ -25,14 +40,96 @@ pub enum SyncOutcome {
     FileCopied,
     SymlinkUpdated,
     SymlinkCreated,
+    /// The destination symlink already had the right target
+    SymlinkUpToDate,
+    SymlinkSkipped,
+    /// The copy was stopped by `SyncOptions::interrupt`, and will resume
+    /// from a checkpoint next time
+    Interrupted,
+    /// The file changed in both the source and the destination, and the
+    /// destination was kept (see `SyncOptions::on_conflict`)
+    DestinationKept,
+}
+
+/// What was removed from the destination, with its description
+#[derive(PartialEq, Debug)]
+pub enum Removal {
+    File(String),
+    Symlink(String),
+    Dir(String),
+}
+
+/// Type of an entry, as far as syncing is concerned
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl Kind {
+    /// Symlinks are not followed
+    pub fn of(metadata: &fs::Metadata) -> Kind {
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            Kind::Symlink
+        } else if file_type.is_dir() {
+            Kind::Dir
+        } else {
+            Kind::File
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Dir => "directory",
+            Kind::Symlink => "symlink",
+        }
+    }
+}
+
+/// Error returned when an entry has a different type in the
+/// source and in the destination, and `force` is not set
+pub fn type_conflict(description: &str, src: Kind, dest: Kind) -> Error {
+    Error::new(&format!(
+        "Refusing to replace {} {} by a {} (use --force to replace it)",
+        dest.name(),
+        description,
+        src.name()
+    ))
 }
 
 pub fn get_rel_path(a: &Path, b: &Path) -> Result<PathBuf, Error> {
-    let rel_path = pathdiff::diff_paths(&a, &b).ok_or_else(|| Error::new(""))?;
+    let rel_path = pathdiff::diff_paths(a, b).ok_or_else(|| Error::new(""))?;
     Ok(rel_path)
 }
 
-fn is_more_recent_than(src: &Entry, dest: &Entry) -> bool {
+/// Record `rel_path` as present in the source, along with its parents:
+/// files reached through a symlink to a directory are synced, but the
+/// symlink itself is never sent by the walker
+pub fn add_source_path(source_paths: &mut HashSet<PathBuf>, rel_path: &Path) {
+    for ancestor in rel_path.ancestors() {
+        if ancestor.as_os_str().is_empty() || !source_paths.insert(ancestor.to_path_buf()) {
+            break;
+        }
+    }
+}
+
+/// Modification time, in nanoseconds since the epoch
+pub fn precise_mtime(metadata: &fs::Metadata) -> u64 {
+    let mtime = FileTime::from_last_modification_time(metadata);
+    mtime.seconds() * 1000 * 1000 * 1000 + u64::from(mtime.nanoseconds())
+}
+
+/// True if `src_mtime` is more recent than `dest_mtime` (both in nanoseconds),
+/// by more than `SyncOptions::modify_window`
+pub fn is_newer(src_mtime: u64, dest_mtime: u64, opts: &SyncOptions) -> bool {
+    let window = opts.modify_window.map(|w| w.as_nanos() as u64).unwrap_or(0);
+    src_mtime > dest_mtime.saturating_add(window)
+}
+
+fn is_more_recent_than(src: &Entry, dest: &Entry, opts: &SyncOptions) -> bool {
     if !dest.exists() {
         return true;
     }
@@ -43,18 +140,49 @@ fn is_more_recent_than(src: &Entry, dest: &Entry) -> bool {
     let src_meta = &src_meta.expect("src_meta was None");
     let dest_meta = &dest_meta.expect("dest_meta was None");
 
-    let src_mtime = FileTime::from_last_modification_time(&src_meta);
-    let dest_mtime = FileTime::from_last_modification_time(&dest_meta);
+    is_newer(precise_mtime(src_meta), precise_mtime(dest_meta), opts)
+}
+
+/// An attribute of the source that could not be preserved on the destination
+#[derive(Debug)]
+pub struct MetadataLoss {
+    /// Which attribute was lost ("permissions" or "owner")
+    pub attribute: &'static str,
+    /// Value of the attribute in the source
+    pub expected: String,
+    /// Value of the attribute in the destination, if it could be read
+    pub actual: Option<String>,
+    pub reason: String,
+}
 
-    let src_precise = src_mtime.seconds() * 1000 * 1000 * 1000 + u64::from(src_mtime.nanoseconds());
-    let dest_precise =
-        dest_mtime.seconds() * 1000 * 1000 * 1000 + u64::from(dest_mtime.nanoseconds());
+impl MetadataLoss {
+    pub fn to_error(&self, description: &str) -> Error {
+        Error::new(&format!(
+            "Could not preserve {} of {}: {}",
+            self.attribute, description, self.reason
+        ))
+    }
 
-    src_precise > dest_precise
+    pub fn to_json(&self, description: &str) -> String {
+        let actual = match &self.actual {
+            Some(actual) => json::quote(actual),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"path\": {}, \"attribute\": {}, \"expected\": {}, \"actual\": {}, \"reason\": {}}}",
+            json::quote(description),
+            json::quote(self.attribute),
+            json::quote(&self.expected),
+            actual,
+            json::quote(&self.reason)
+        )
+    }
 }
 
 #[cfg(unix)]
-pub fn copy_permissions(src: &Entry, dest: &Entry) -> Result<(), Error> {
+pub fn copy_permissions(src: &Entry, dest: &Entry) -> Result<Option<MetadataLoss>, Error> {
+    use std::os::unix::fs::PermissionsExt;
+
     let src_meta = &src.metadata();
     // is_link should not be none because we should have been able to
     // read its metadata way back in WalkWorker
@@ -62,54 +190,370 @@ pub fn copy_permissions(src: &Entry, dest: &Entry) -> Result<(), Error> {
         .is_link()
         .unwrap_or_else(|| panic!("is_link was None for {:#?}", src));
     if is_link {
-        return Ok(());
+        return Ok(None);
     }
     // The only way for src_meta to be None is if src is a broken symlink
     // and we checked that right above:
-    let src_meta = &src_meta.expect(&format!("src_meta was None for {:#?}", src));
+    let src_meta = &src_meta.unwrap_or_else(|| panic!("src_meta was None for {:#?}", src));
     let permissions = src_meta.permissions();
+    let expected = permissions.mode() & 0o7777;
+    // Rewriting a file in place does not change its permissions, so there's
+    // nothing to do if the destination already had the correct ones
+    if dest.metadata().map(|m| m.permissions().mode() & 0o7777) == Some(expected) {
+        return Ok(None);
+    }
     let dest_file = File::open(dest.path()).map_err(|e| {
-        Error::new(&format!(
-            "could not open {} while copying permissions: {}",
-            dest.description(),
-            e
-        ))
+        Error::from_io(
+            &format!(
+                "could not open {} while copying permissions: {}",
+                dest.description(),
+                e
+            ),
+            &e,
+        )
+    })?;
+    if let Err(e) = dest_file.set_permissions(permissions) {
+        return Ok(Some(MetadataLoss {
+            attribute: "permissions",
+            expected: format!("{:o}", expected),
+            actual: None,
+            reason: e.to_string(),
+        }));
+    }
+    // Some file systems (like FAT) accept the call but silently
+    // ignore it, so check what we actually got
+    let dest_meta = dest_file.metadata().map_err(|e| {
+        Error::from_io(
+            &format!("Could not read metadata of {}: {}", dest.description(), e),
+            &e,
+        )
     })?;
-    dest_file.set_permissions(permissions).map_err(|e| {
+    let actual = dest_meta.permissions().mode() & 0o7777;
+    if actual != expected {
+        return Ok(Some(MetadataLoss {
+            attribute: "permissions",
+            expected: format!("{:o}", expected),
+            actual: Some(format!("{:o}", actual)),
+            reason: "not supported by the destination".to_string(),
+        }));
+    }
+    Ok(None)
+}
+
+pub fn copy_mtime(src: &Entry, dest: &Entry) -> Result<(), Error> {
+    let src_meta = src.metadata().expect("src_meta should not be None");
+    let dest_meta = dest.metadata().expect("dest_meta should not be None");
+    let atime = FileTime::from_last_access_time(dest_meta);
+    let mtime = FileTime::from_last_modification_time(src_meta);
+    filetime::set_file_times(dest.path(), atime, mtime).map_err(|e| {
+        Error::from_io(
+            &format!(
+                "Could not set modification time of {}: {}",
+                dest.description(),
+                e
+            ),
+            &e,
+        )
+    })
+}
+
+/// Give `dest` the owner and group of `src`, shifted by `offset`
+/// (see `SyncOptions::chown_offset`)
+#[cfg(unix)]
+pub fn copy_ownership(
+    src: &Entry,
+    dest: &Entry,
+    offset: u32,
+) -> Result<Option<MetadataLoss>, Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let src_meta = src
+        .metadata()
+        .unwrap_or_else(|| panic!("src_meta was None for {:#?}", src));
+    // (uid_t)-1 tells lchown() not to change the owner, so it can't be set
+    let shift = |id: u32| id.checked_add(offset).filter(|id| *id != u32::MAX);
+    let (uid, gid) = match (shift(src_meta.uid()), shift(src_meta.gid())) {
+        (Some(uid), Some(gid)) => (uid, gid),
+        _ => {
+            return Ok(Some(MetadataLoss {
+                attribute: "owner",
+                expected: format!(
+                    "{}:{}",
+                    u64::from(src_meta.uid()) + u64::from(offset),
+                    u64::from(src_meta.gid()) + u64::from(offset)
+                ),
+                actual: None,
+                reason: format!("out of range once shifted by {}", offset),
+            }))
+        }
+    };
+    let expected = format!("{}:{}", uid, gid);
+    // Like in copy_permissions(), skip the syscalls when possible. Symlinks
+    // are re-created when updated though, so always fix them
+    if let Some(dest_meta) = dest.metadata() {
+        if !dest_meta.file_type().is_symlink() && (dest_meta.uid(), dest_meta.gid()) == (uid, gid) {
+            return Ok(None);
+        }
+    }
+    let dest_path = CString::new(dest.path().as_os_str().as_bytes()).map_err(|e| {
         Error::new(&format!(
-            "Could not set permissions for {}: {}",
+            "Invalid path {} while copying ownership: {}",
             dest.description(),
             e
         ))
     })?;
-    Ok(())
+    // lchown() so that we change the owner of symlinks instead of their targets
+    let res = unsafe { libc::lchown(dest_path.as_ptr(), uid, gid) };
+    if res != 0 {
+        return Ok(Some(MetadataLoss {
+            attribute: "owner",
+            expected,
+            actual: None,
+            reason: std::io::Error::last_os_error().to_string(),
+        }));
+    }
+    let dest_meta = fs::symlink_metadata(dest.path()).map_err(|e| {
+        Error::from_io(
+            &format!("Could not read metadata of {}: {}", dest.description(), e),
+            &e,
+        )
+    })?;
+    if (dest_meta.uid(), dest_meta.gid()) != (uid, gid) {
+        return Ok(Some(MetadataLoss {
+            attribute: "owner",
+            expected,
+            actual: Some(format!("{}:{}", dest_meta.uid(), dest_meta.gid())),
+            reason: "not supported by the destination".to_string(),
+        }));
+    }
+    Ok(None)
+}
+
+pub fn read_link(src: &Entry) -> Result<PathBuf, Error> {
+    fs::read_link(src.path()).map_err(|e| {
+        Error::from_io(
+            &format!("Could not read link {}: {}", src.description(), e),
+            &e,
+        )
+    })
+}
+
+// Remove `.` and `..` components without touching the file system
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !res.pop() {
+                    res.push(component)
+                }
+            }
+            _ => res.push(component),
+        }
+    }
+    res
+}
+
+/// Length of a file name as the file system counts it: bytes on Unix,
+/// UTF-16 code units on Windows
+#[cfg(unix)]
+pub fn name_len(name: &OsStr) -> usize {
+    use std::os::unix::ffi::OsStrExt;
+    name.as_bytes().len()
+}
+
+#[cfg(windows)]
+pub fn name_len(name: &OsStr) -> usize {
+    name.to_string_lossy().chars().map(char::len_utf16).sum()
+}
+
+// Added to truncated names, so that names sharing a long prefix stay different
+fn name_hash(name: &[u8]) -> String {
+    let mut digest = Sha256::new();
+    digest.update(name);
+    format!("~{}", &digest.hex_digest()[..8])
+}
+
+/// Shorten `name` to at most `max_len`, keeping its extension. A hash of the
+/// whole name is added so that names sharing a long prefix stay different
+#[cfg(unix)]
+pub fn truncate_name(name: &OsStr, max_len: usize) -> OsString {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let name = name.as_bytes();
+    let suffix = name_hash(name);
+    let extension = match name.iter().rposition(|b| *b == b'.') {
+        Some(pos) if pos > 0 && name.len() - pos < max_len / 4 => &name[pos..],
+        _ => &[],
+    };
+    let stem = &name[..name.len() - extension.len()];
+    let budget = max_len.saturating_sub(suffix.len() + extension.len());
+    let mut end = budget.min(stem.len());
+    // Don't cut a UTF-8 character in two. Invalid bytes are kept as they are
+    if let Some(start) = (end.saturating_sub(3)..end)
+        .rev()
+        .find(|i| stem[*i] & 0xc0 != 0x80)
+    {
+        if let Err(e) = std::str::from_utf8(&stem[start..end]) {
+            if e.error_len().is_none() {
+                end = start;
+            }
+        }
+    }
+    let mut res = stem[..end].to_vec();
+    res.extend_from_slice(suffix.as_bytes());
+    res.extend_from_slice(extension);
+    OsString::from_vec(res)
+}
+
+#[cfg(windows)]
+pub fn truncate_name(name: &OsStr, max_len: usize) -> OsString {
+    let name = name.to_string_lossy();
+    let suffix = name_hash(name.as_bytes());
+    let extension = match name.rfind('.') {
+        Some(pos) if pos > 0 && name_len(OsStr::new(&name[pos..])) < max_len / 4 => &name[pos..],
+        _ => "",
+    };
+    let budget = max_len.saturating_sub(suffix.len() + name_len(OsStr::new(extension)));
+    let mut stem = String::new();
+    let mut len = 0;
+    for c in name[..name.len() - extension.len()].chars() {
+        len += c.len_utf16();
+        if len > budget {
+            break;
+        }
+        stem.push(c);
+    }
+    OsString::from(format!("{}{}{}", stem, suffix, extension))
+}
+
+/// Where the entry at `rel_path` in the source goes in a destination allowing
+/// names of at most `max_len`, according to `long_names`. None if it must be
+/// skipped. Comes with the warning to report, if any
+pub fn dest_rel_path(
+    rel_path: &Path,
+    max_len: Option<usize>,
+    long_names: LongNames,
+) -> (Option<PathBuf>, Option<String>) {
+    let max_len = match max_len {
+        None => return (Some(rel_path.to_path_buf()), None),
+        Some(max_len) => max_len,
+    };
+    let mut res = PathBuf::new();
+    let mut warning = None;
+    let num_components = rel_path.iter().count();
+    for (i, name) in rel_path.iter().enumerate() {
+        if name_len(name) <= max_len {
+            res.push(name);
+            continue;
+        }
+        // Entries below a long directory name were already reported with it
+        let is_last = i + 1 == num_components;
+        match long_names {
+            LongNames::Skip => {
+                if is_last {
+                    warning = Some(format!(
+                        "Skipping {}: name longer than the {} allowed by the destination",
+                        rel_path.to_string_lossy(),
+                        max_len
+                    ));
+                }
+                return (None, warning);
+            }
+            LongNames::Truncate => {
+                let truncated = truncate_name(name, max_len);
+                if is_last {
+                    warning = Some(format!(
+                        "Name of {} is longer than the {} allowed by the destination, using {}",
+                        rel_path.to_string_lossy(),
+                        max_len,
+                        truncated.to_string_lossy()
+                    ));
+                }
+                res.push(truncated);
+            }
+        }
+    }
+    (Some(res), warning)
 }
 
-fn copy_link(src: &Entry, dest: &Entry) -> Result<SyncOutcome, Error> {
-    let src_target = std::fs::read_link(src.path())
-        .map_err(|e| Error::new(&format!("Could not read link {}: {}", src.description(), e)))?;
+pub fn absolute_path(path: &Path) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(cwd) => normalize_path(&cwd.join(path)),
+        Err(_) => normalize_path(path),
+    }
+}
+
+/// Compute the target of the destination symlink, given the target of the
+/// source symlink located at `link_rel_path`.
+/// `source` and `destination` must be absolute and normalized.
+pub fn rewrite_link_target(
+    target: &Path,
+    link_rel_path: &Path,
+    source: &Path,
+    destination: &Path,
+    rewrite: Option<LinkRewrite>,
+) -> PathBuf {
+    let rewrite = match rewrite {
+        None => return target.to_path_buf(),
+        Some(rewrite) => rewrite,
+    };
+    let normalized = normalize_path(target);
+    if !target.is_absolute() || !normalized.starts_with(source) {
+        return target.to_path_buf();
+    }
+    match rewrite {
+        LinkRewrite::Relative => {
+            let link_dir = source.join(link_rel_path);
+            let link_dir = link_dir.parent().unwrap_or(source);
+            pathdiff::diff_paths(&normalized, link_dir).unwrap_or_else(|| target.to_path_buf())
+        }
+        LinkRewrite::Reroot => {
+            // We just checked that normalized starts with source
+            let rel_target = normalized.strip_prefix(source).unwrap();
+            destination.join(rel_target)
+        }
+    }
+}
+
+/// Returns true if a symlink located at `link_rel_path` inside `root`
+/// and pointing to `target` would point outside `root`.
+/// `root` must be absolute and normalized.
+pub fn link_escapes(target: &Path, link_rel_path: &Path, root: &Path) -> bool {
+    let link_path = root.join(link_rel_path);
+    let link_dir = link_path.parent().unwrap_or(root);
+    let resolved = normalize_path(&link_dir.join(target));
+    !resolved.starts_with(root)
+}
+
+pub fn copy_link(src: &Entry, dest: &Entry, src_target: &Path) -> Result<SyncOutcome, Error> {
     let is_link = dest.is_link();
     let outcome;
     match is_link {
         Some(true) => {
             let dest_target = std::fs::read_link(dest.path()).map_err(|e| {
-                Error::new(&format!(
-                    "Could not read link {}: {}",
-                    dest.description(),
-                    e
-                ))
+                Error::from_io(
+                    &format!("Could not read link {}: {}", dest.description(), e),
+                    &e,
+                )
             })?;
-            if dest_target != src_target {
+            if dest_target != *src_target {
                 fs::remove_file(dest.path()).map_err(|e| {
-                    Error::new(&format!(
-                        "Could not remove {} while updating link: {}",
-                        dest.description(),
-                        e
-                    ))
+                    Error::from_io(
+                        &format!(
+                            "Could not remove {} while updating link: {}",
+                            dest.description(),
+                            e
+                        ),
+                        &e,
+                    )
                 })?;
                 outcome = SyncOutcome::SymlinkUpdated;
             } else {
-                return Ok(SyncOutcome::UpToDate);
+                return Ok(SyncOutcome::SymlinkUpToDate);
             }
         }
         Some(false) => {
@@ -126,14 +570,17 @@ fn copy_link(src: &Entry, dest: &Entry) -> Result<SyncOutcome, Error> {
     }
     #[cfg(unix)]
     {
-        let symlink_result = unix::fs::symlink(&src_target, &dest.path());
+        let symlink_result = unix::fs::symlink(src_target, dest.path());
         match symlink_result {
-            Err(e) => Err(Error::new(&format!(
-                "Could not create link from {} to {}: {}",
-                dest.description(),
-                src.description(),
-                e
-            ))),
+            Err(e) => Err(Error::from_io(
+                &format!(
+                    "Could not create link from {} to {}: {}",
+                    dest.description(),
+                    src.description(),
+                    e
+                ),
+                &e,
+            )),
             Ok(_) => Ok(outcome),
         }
     }
@@ -148,36 +595,43 @@ pub fn copy_entry(
     progress_sender: &mpsc::Sender<ProgressMessage>,
     src: &Entry,
     dest: &Entry,
+    buffer_size: usize,
+    hashes: &mut Hashes,
 ) -> Result<SyncOutcome, Error> {
     let src_path = src.path();
     let mut src_file = File::open(src_path).map_err(|e| {
-        Error::new(&format!(
-            "Could not open {} for reading: {}",
-            src.description(),
-            e
-        ))
+        Error::from_io(
+            &format!("Could not open {} for reading: {}", src.description(), e),
+            &e,
+        )
     })?;
     let src_meta = src.metadata().expect("src_meta should not be None");
     let src_size = src_meta.len();
     let dest_path = dest.path();
     let mut dest_file = File::create(dest_path).map_err(|e| {
-        Error::new(&format!(
-            "Could not open {} for writing: {}",
-            dest.description(),
-            e
-        ))
+        Error::from_io(
+            &format!("Could not open {} for writing: {}", dest.description(), e),
+            &e,
+        )
     })?;
-    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut buffer = vec![0; buffer_size];
     loop {
         let num_read = src_file.read(&mut buffer).map_err(|e| {
-            Error::new(&format!("Could not read from {}: {}", src.description(), e))
+            Error::from_io(
+                &format!("Could not read from {}: {}", src.description(), e),
+                &e,
+            )
         })?;
         if num_read == 0 {
             break;
         }
         dest_file.write_all(&buffer[0..num_read]).map_err(|e| {
-            Error::new(&format!("Could not write to {}: {}", dest.description(), e))
+            Error::from_io(
+                &format!("Could not write to {}: {}", dest.description(), e),
+                &e,
+            )
         })?;
+        hashes.update(&buffer[0..num_read]);
         let progress = ProgressMessage::Syncing {
             description: src.description().clone(),
             size: src_size as usize,
@@ -188,34 +642,518 @@ pub fn copy_entry(
     Ok(SyncOutcome::FileCopied)
 }
 
+/// Copy `src` to a file in `temp_dir`, then move it over `dest`, so
+/// that `dest` is never seen half-written.
+pub fn copy_through_temp_dir(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest: &Entry,
+    temp_dir: &Path,
+    buffer_size: usize,
+    hashes: &mut Hashes,
+) -> Result<SyncOutcome, Error> {
+    let name = dest
+        .path()
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temp_path = temp_dir.join(temp_name(&name));
+    let temp_entry = Entry::new(dest.description(), &temp_path);
+    let res = copy_entry(progress_sender, src, &temp_entry, buffer_size, hashes)
+        .and_then(|outcome| move_file(&temp_path, dest).map(|_| outcome));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    res
+}
+
+/// A name based on `name` that no other sync uses at the same time,
+/// whether it runs in another process or in this one
+pub(crate) fn temp_name(name: &str) -> String {
+    let count = TEMP_NAMES.fetch_add(1, Ordering::SeqCst);
+    format!(".rusync-{}-{}-{}", std::process::id(), count, name)
+}
+
+// Rename if possible, otherwise (different file systems) copy and remove
+fn move_file(from: &Path, dest: &Entry) -> Result<(), Error> {
+    let err = match fs::rename(from, dest.path()) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    #[cfg(unix)]
+    let crosses_devices = err.raw_os_error() == Some(libc::EXDEV);
+    #[cfg(windows)]
+    let crosses_devices = true;
+    if !crosses_devices {
+        return Err(Error::new(&format!(
+            "Could not move {:?} to {}: {}",
+            from,
+            dest.description(),
+            err
+        )));
+    }
+    fs::copy(from, dest.path())
+        .and_then(|_| fs::remove_file(from))
+        .map_err(|e| {
+            Error::from_io(
+                &format!("Could not copy {:?} to {}: {}", from, dest.description(), e),
+                &e,
+            )
+        })
+}
+
+/// True if both paths are on the same file system,
+/// so that files can be renamed from one to the other
+#[cfg(unix)]
+pub fn same_file_system(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
+pub fn same_file_system(_a: &Path, _b: &Path) -> bool {
+    true
+}
+
+/// Try to make `dest` a copy-on-write clone of `src`.
+/// Returns false if the file system does not support it, in which case
+/// a regular copy should be done instead.
+#[cfg(target_os = "linux")]
+pub fn clone_entry(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest: &Entry,
+) -> Result<bool, Error> {
+    use std::os::unix::io::AsRawFd;
+
+    // from linux/fs.h
+    const FICLONE: u32 = 0x4004_9409;
+
+    let src_file = File::open(src.path()).map_err(|e| {
+        Error::from_io(
+            &format!("Could not open {} for reading: {}", src.description(), e),
+            &e,
+        )
+    })?;
+    let dest_file = File::create(dest.path()).map_err(|e| {
+        Error::from_io(
+            &format!("Could not open {} for writing: {}", dest.description(), e),
+            &e,
+        )
+    })?;
+    let res = unsafe { libc::ioctl(dest_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) };
+    if res != 0 {
+        // Not on the same file system, or reflinks are not supported:
+        // either way, copy_entry() will overwrite the (empty) destination
+        return Ok(false);
+    }
+    let src_size = src.metadata().map(|m| m.len()).unwrap_or(0) as usize;
+    let progress = ProgressMessage::Syncing {
+        description: src.description().clone(),
+        size: src_size,
+        done: src_size,
+    };
+    let _ = progress_sender.send(progress);
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn clone_entry(
+    _progress_sender: &mpsc::Sender<ProgressMessage>,
+    _src: &Entry,
+    _dest: &Entry,
+) -> Result<bool, Error> {
+    Ok(false)
+}
+
 fn has_different_size(src: &Entry, dest: &Entry) -> bool {
     let src_meta = src.metadata().expect("src_meta should not be None");
     let dest_meta = dest.metadata();
     match dest_meta {
         None => true,
-        Some(dest_meta) => (dest_meta.len() != src_meta.len()),
+        Some(dest_meta) => dest_meta.len() != src_meta.len(),
     }
 }
 
-pub fn sync_entries(
+/// Copy what was appended to `src` since `dest` was written, assuming
+/// `dest` is a prefix of `src`
+pub fn append_entry(
     progress_sender: &mpsc::Sender<ProgressMessage>,
     src: &Entry,
     dest: &Entry,
 ) -> Result<SyncOutcome, Error> {
-    let _ = progress_sender.send(ProgressMessage::StartSync(src.description().to_string()));
-    let is_link = src.is_link().expect("src.is_link should not be None");
-    if is_link {
-        return copy_link(&src, &dest);
+    let mut src_file = File::open(src.path()).map_err(|e| {
+        Error::from_io(
+            &format!("Could not open {} for reading: {}", src.description(), e),
+            &e,
+        )
+    })?;
+    let src_size = src.metadata().map(|m| m.len()).unwrap_or(0);
+    let dest_size = dest.metadata().map(|m| m.len()).unwrap_or(0);
+    src_file.seek(SeekFrom::Start(dest_size)).map_err(|e| {
+        Error::from_io(
+            &format!("Could not seek in {}: {}", src.description(), e),
+            &e,
+        )
+    })?;
+    let mut dest_file = fs::OpenOptions::new()
+        .append(true)
+        .open(dest.path())
+        .map_err(|e| {
+            Error::from_io(
+                &format!("Could not open {} for appending: {}", dest.description(), e),
+                &e,
+            )
+        })?;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let num_read = src_file.read(&mut buffer).map_err(|e| {
+            Error::from_io(
+                &format!("Could not read from {}: {}", src.description(), e),
+                &e,
+            )
+        })?;
+        if num_read == 0 {
+            break;
+        }
+        dest_file.write_all(&buffer[0..num_read]).map_err(|e| {
+            Error::from_io(
+                &format!("Could not write to {}: {}", dest.description(), e),
+                &e,
+            )
+        })?;
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: num_read,
+        };
+        let _ = progress_sender.send(progress);
+    }
+    Ok(SyncOutcome::FileCopied)
+}
+
+/// Compare `src` and `dest`, which have the same size, and rewrite `dest`
+/// from the first difference on. This way `src` is only read once, whether
+/// the file changed or not. If the hashes of the blocks of `dest` are known
+/// (see the blocks module), `dest` is not read at all
+pub fn rewrite_differences(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest: &Entry,
+    basis: Option<&[String]>,
+    hashes: &mut Hashes,
+) -> Result<SyncOutcome, Error> {
+    let mut src_file = File::open(src.path()).map_err(|e| {
+        Error::from_io(
+            &format!("Could not open {} for reading: {}", src.description(), e),
+            &e,
+        )
+    })?;
+    // Only opened for writing at the first difference, so that
+    // read-only files which did not change can still be compared
+    let mut dest_file = match basis {
+        Some(_) => None,
+        None => Some(File::open(dest.path()).map_err(|e| {
+            Error::from_io(
+                &format!("Could not open {} for reading: {}", dest.description(), e),
+                &e,
+            )
+        })?),
+    };
+    let open_for_writing = || {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(dest.path())
+            .map_err(|e| {
+                Error::from_io(
+                    &format!("Could not open {} for writing: {}", dest.description(), e),
+                    &e,
+                )
+            })
+    };
+    let src_size = src.metadata().map(|m| m.len()).unwrap_or(0);
+    let read_error = |entry: &Entry, e: std::io::Error| {
+        Error::from_io(
+            &format!("Could not read from {}: {}", entry.description(), e),
+            &e,
+        )
+    };
+    let write_error = |e| {
+        Error::from_io(
+            &format!("Could not write to {}: {}", dest.description(), e),
+            &e,
+        )
+    };
+    let mut src_buffer = vec![0; BUFFER_SIZE];
+    let mut dest_buffer = vec![0; BUFFER_SIZE];
+    let mut offset = 0;
+    let mut block = 0;
+    let mut writer: Option<File> = None;
+    loop {
+        let num_read = read_full(&mut src_file, &mut src_buffer).map_err(|e| read_error(src, e))?;
+        if num_read == 0 {
+            break;
+        }
+        let data = &src_buffer[..num_read];
+        hashes.update(data);
+        if writer.is_none() {
+            let same = match (basis, dest_file.as_mut()) {
+                (Some(basis), _) => {
+                    let mut hasher = Sha256::new();
+                    hasher.update(data);
+                    basis.get(block) == Some(&hasher.hex_digest())
+                }
+                (None, Some(dest_file)) => {
+                    let dest_read =
+                        read_full(dest_file, &mut dest_buffer).map_err(|e| read_error(dest, e))?;
+                    dest_buffer[..dest_read] == *data
+                }
+                (None, None) => false,
+            };
+            block += 1;
+            if same {
+                offset += num_read as u64;
+                continue;
+            }
+            let mut file = open_for_writing()?;
+            file.seek(SeekFrom::Start(offset)).map_err(write_error)?;
+            writer = Some(file);
+            // What is before was read, but did not need to be written
+            if offset > 0 {
+                let progress = ProgressMessage::Syncing {
+                    description: src.description().clone(),
+                    size: src_size as usize,
+                    done: offset as usize,
+                };
+                let _ = progress_sender.send(progress);
+            }
+        }
+        if let Some(file) = writer.as_mut() {
+            file.write_all(data).map_err(write_error)?;
+        }
+        offset += num_read as u64;
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done: num_read,
+        };
+        let _ = progress_sender.send(progress);
     }
-    let different_size = has_different_size(&src, &dest);
-    let more_recent = is_more_recent_than(&src, &dest);
-    // TODO: check if files really are different ?
-    if more_recent || different_size {
-        return copy_entry(&progress_sender, &src, &dest);
+    // The source may have shrunk since its size was compared
+    let dest_size = fs::metadata(dest.path()).map(|m| m.len()).unwrap_or(0);
+    if writer.is_none() && dest_size != offset {
+        writer = Some(open_for_writing()?);
+    }
+    match writer {
+        Some(file) => {
+            file.set_len(offset).map_err(write_error)?;
+            Ok(SyncOutcome::FileCopied)
+        }
+        None => Ok(SyncOutcome::UpToDate),
+    }
+}
+
+// Like Read::read(), but only return less than `buffer.len()` at the end of the file
+pub(crate) fn read_full(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let num_read = file.read(&mut buffer[filled..])?;
+        if num_read == 0 {
+            break;
+        }
+        filled += num_read;
+    }
+    Ok(filled)
+}
+
+/// SHA-256 of the contents of the file, as a hex string
+pub fn file_digest(entry: &Entry) -> Result<String, Error> {
+    let mut file = File::open(entry.path()).map_err(|e| {
+        Error::from_io(
+            &format!("Could not open {} for reading: {}", entry.description(), e),
+            &e,
+        )
+    })?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let num_read = file.read(&mut buffer).map_err(|e| {
+            Error::from_io(
+                &format!("Could not read from {}: {}", entry.description(), e),
+                &e,
+            )
+        })?;
+        if num_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[0..num_read]);
+    }
+    Ok(hasher.hex_digest())
+}
+
+fn has_different_contents(src: &Entry, dest: &Entry) -> Result<bool, Error> {
+    if has_different_size(src, dest) {
+        return Ok(true);
+    }
+    Ok(file_digest(src)? != file_digest(dest)?)
+}
+
+/// Copy a regular file, if needed, according to the policy matching
+/// its path. Symlinks are handled by copy_link(). Plain copies use the
+/// buffer size of `tuner` if set, and tell it how long they took.
+/// Whatever is read from the source is given to `hashes`
+pub fn sync_file(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest: &Entry,
+    opts: &SyncOptions,
+    tuner: Option<&mut BufferTuner>,
+    hashes: &mut Hashes,
+) -> Result<SyncOutcome, Error> {
+    let policy = opts.policy_for(Path::new(src.description()));
+    let needs_copy = match policy {
+        Policy::Default => is_more_recent_than(src, dest, opts) || has_different_size(src, dest),
+        Policy::SizeOnly => has_different_size(src, dest),
+        // Reflinks and staging in a temporary directory replace the whole
+        // file, otherwise compare and copy in the same pass
+        Policy::Checksum if opts.reflink || opts.temp_dir.is_some() => {
+            has_different_contents(src, dest)?
+        }
+        Policy::Checksum => {
+            if !has_different_size(src, dest) {
+                let basis = if opts.block_sidecars {
+                    blocks::read(dest.path())
+                } else {
+                    None
+                };
+                let outcome =
+                    rewrite_differences(progress_sender, src, dest, basis.as_deref(), hashes)?;
+                send_digest(progress_sender, src, hashes);
+                return Ok(outcome);
+            }
+            true
+        }
+        Policy::Append => {
+            let src_size = src.metadata().map(|m| m.len()).unwrap_or(0);
+            match dest.metadata().map(|m| m.len()) {
+                Some(dest_size) if dest_size == src_size => false,
+                Some(dest_size) if dest_size < src_size => {
+                    return append_entry(progress_sender, src, dest);
+                }
+                _ => true,
+            }
+        }
+    };
+    if needs_copy {
+        return copy_file(progress_sender, src, dest, opts, tuner, hashes);
     }
     Ok(SyncOutcome::UpToDate)
 }
 
+/// What is computed from the contents of the source while it is copied
+#[derive(Default)]
+pub struct Hashes {
+    /// Of the whole file, with `SyncOptions::hash_files`
+    pub file: Option<Sha256>,
+    /// Of each block, with `SyncOptions::block_sidecars`
+    pub blocks: Option<blocks::BlockHasher>,
+}
+
+impl Hashes {
+    pub fn new(opts: &SyncOptions) -> Hashes {
+        Hashes {
+            file: if opts.hash_files {
+                Some(Sha256::new())
+            } else {
+                None
+            },
+            blocks: if opts.block_sidecars {
+                Some(blocks::BlockHasher::new())
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(file) = self.file.as_mut() {
+            file.update(data);
+        }
+        if let Some(blocks) = self.blocks.as_mut() {
+            blocks.update(data);
+        }
+    }
+}
+
+// Once the whole source was read
+fn send_digest(progress_sender: &mpsc::Sender<ProgressMessage>, src: &Entry, hashes: &mut Hashes) {
+    if let Some(hasher) = hashes.file.take() {
+        let _ = progress_sender.send(ProgressMessage::Hashed {
+            description: src.description().clone(),
+            digest: hasher.hex_digest(),
+        });
+    }
+}
+
+/// Replace `dest` with `src`, without comparing them first
+pub(crate) fn copy_file(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    src: &Entry,
+    dest: &Entry,
+    opts: &SyncOptions,
+    tuner: Option<&mut BufferTuner>,
+    hashes: &mut Hashes,
+) -> Result<SyncOutcome, Error> {
+    if opts.reflink && clone_entry(progress_sender, src, dest)? {
+        // Nothing was read
+        if hashes.file.take().is_some() {
+            let _ = progress_sender.send(ProgressMessage::Hashed {
+                description: src.description().clone(),
+                digest: file_digest(src)?,
+            });
+        }
+        return Ok(SyncOutcome::FileCopied);
+    }
+    if let Some(interval) = opts.checkpoint_interval {
+        if src.metadata().map(|m| m.len()).unwrap_or(0) > interval {
+            let interrupt = opts.interrupt.as_deref();
+            let outcome = checkpoint::copy_with_checkpoints(
+                progress_sender,
+                src,
+                dest,
+                interval,
+                interrupt,
+                tuner,
+                hashes,
+            )?;
+            if outcome == SyncOutcome::FileCopied {
+                send_digest(progress_sender, src, hashes);
+            }
+            return Ok(outcome);
+        }
+    }
+    let buffer_size = tuner.as_ref().map_or(BUFFER_SIZE, |t| t.buffer_size());
+    let start = Instant::now();
+    let outcome = match opts.temp_dir {
+        Some(ref temp_dir) => {
+            copy_through_temp_dir(progress_sender, src, dest, temp_dir, buffer_size, hashes)?
+        }
+        None => copy_entry(progress_sender, src, dest, buffer_size, hashes)?,
+    };
+    send_digest(progress_sender, src, hashes);
+    if let Some(tuner) = tuner {
+        tuner.record(
+            src.metadata().map(|m| m.len()).unwrap_or(0),
+            start.elapsed(),
+        );
+    }
+    Ok(outcome)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -223,7 +1161,6 @@ mod tests {
     extern crate tempdir;
     use self::tempdir::TempDir;
 
-    use std;
     #[cfg(unix)]
     use std::os::unix;
     use std::path::Path;
@@ -235,7 +1172,7 @@ mod tests {
 
     #[cfg(unix)]
     fn create_link(src: &str, dest: &Path) -> Result<(), std::io::Error> {
-        unix::fs::symlink(&src, &dest)
+        unix::fs::symlink(src, dest)
     }
 
     #[cfg(unix)]
@@ -249,18 +1186,92 @@ mod tests {
     #[cfg(unix)]
     fn setup_copy_test(tmp_path: &Path) -> Result<PathBuf, std::io::Error> {
         let src = &tmp_path.join("src");
-        create_file(&src)?;
+        create_file(src)?;
         let src_link = &tmp_path.join("src_link");
-        create_link("src", &src_link)?;
+        create_link("src", src_link)?;
         Ok(src_link.to_path_buf())
     }
 
     #[cfg(unix)]
     fn sync_src_link(tmp_path: &Path, src_link: &Path, dest: &str) -> Result<SyncOutcome, Error> {
-        let src_entry = Entry::new("src", &src_link);
-        let dest_path = &tmp_path.join(&dest);
-        let dest_entry = Entry::new(&dest, dest_path);
-        copy_link(&src_entry, &dest_entry)
+        let src_entry = Entry::new("src", src_link);
+        let dest_path = &tmp_path.join(dest);
+        let dest_entry = Entry::new(dest, dest_path);
+        let src_target = read_link(&src_entry)?;
+        copy_link(&src_entry, &dest_entry, &src_target)
+    }
+
+    #[test]
+    fn rewrite_only_differences() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-fsops")?;
+        let src_path = tmp_dir.path().join("src");
+        let dest_path = tmp_dir.path().join("dest");
+        let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src_path, &data)?;
+        let mut changed = data.clone();
+        changed[250_000] = 0xff;
+        std::fs::write(&dest_path, &changed)?;
+
+        let (sender, receiver) = mpsc::channel::<ProgressMessage>();
+        let src_entry = Entry::new("src", &src_path);
+        let dest_entry = Entry::new("dest", &dest_path);
+        let outcome = rewrite_differences(
+            &sender,
+            &src_entry,
+            &dest_entry,
+            None,
+            &mut Hashes::default(),
+        )
+        .unwrap();
+        assert_eq!(outcome, SyncOutcome::FileCopied);
+        assert_eq!(std::fs::read(&dest_path)?, data);
+        // The first two chunks were equal, so were not written
+        match receiver.try_recv() {
+            Ok(ProgressMessage::Syncing { done, .. }) => assert_eq!(done, 2 * BUFFER_SIZE),
+            _ => panic!("no progress reported"),
+        }
+
+        let outcome = rewrite_differences(
+            &sender,
+            &src_entry,
+            &dest_entry,
+            None,
+            &mut Hashes::default(),
+        )
+        .unwrap();
+        assert_eq!(outcome, SyncOutcome::UpToDate);
+
+        // Nothing is written, so a read-only destination is fine
+        let mut permissions = std::fs::metadata(&dest_path)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&dest_path, permissions)?;
+        let outcome = rewrite_differences(
+            &sender,
+            &src_entry,
+            &dest_entry,
+            None,
+            &mut Hashes::default(),
+        )
+        .unwrap();
+        assert_eq!(outcome, SyncOutcome::UpToDate);
+        let mut permissions = std::fs::metadata(&dest_path)?.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(&dest_path, permissions)?;
+
+        // The source shrank after the sizes were compared
+        std::fs::write(&src_path, &data[..100])?;
+        let outcome = rewrite_differences(
+            &sender,
+            &src_entry,
+            &dest_entry,
+            None,
+            &mut Hashes::default(),
+        )
+        .unwrap();
+        assert_eq!(outcome, SyncOutcome::FileCopied);
+        assert_eq!(std::fs::read(&dest_path)?, &data[..100]);
+        Ok(())
     }
 
     #[cfg(unix)]
@@ -270,9 +1281,9 @@ mod tests {
         let tmp_path = tmp_dir.path();
         let src_link = setup_copy_test(tmp_path)?;
 
-        let outcome = sync_src_link(&tmp_path, &src_link, "new");
+        let outcome = sync_src_link(tmp_path, &src_link, "new");
         assert_eq!(outcome.unwrap(), SyncOutcome::SymlinkCreated);
-        assert_links_to(&tmp_path, "new", "src");
+        assert_links_to(tmp_path, "new", "src");
         Ok(())
     }
 
@@ -284,10 +1295,10 @@ mod tests {
         let src_link = setup_copy_test(tmp_path)?;
 
         let broken_link = &tmp_path.join("broken");
-        create_link("no-such-file", &broken_link)?;
-        let outcome = sync_src_link(&tmp_path, &src_link, "broken");
+        create_link("no-such-file", broken_link)?;
+        let outcome = sync_src_link(tmp_path, &src_link, "broken");
         assert_eq!(outcome.unwrap(), SyncOutcome::SymlinkUpdated);
-        assert_links_to(&tmp_path, "broken", "src");
+        assert_links_to(tmp_path, "broken", "src");
         Ok(())
     }
 
@@ -299,12 +1310,12 @@ mod tests {
         let src_link = setup_copy_test(tmp_path)?;
 
         let old_dest = &tmp_path.join("old");
-        create_file(&old_dest)?;
+        create_file(old_dest)?;
         let existing_link = tmp_path.join("existing_link");
         create_link("old", &existing_link)?;
-        let outcome = sync_src_link(&tmp_path, &src_link, "existing_link");
+        let outcome = sync_src_link(tmp_path, &src_link, "existing_link");
         assert_eq!(outcome.unwrap(), SyncOutcome::SymlinkUpdated);
-        assert_links_to(&tmp_path, "existing_link", "src");
+        assert_links_to(tmp_path, "existing_link", "src");
         Ok(())
     }
 
@@ -317,11 +1328,191 @@ mod tests {
 
         let existing_file = tmp_path.join("existing");
         create_file(&existing_file)?;
-        let outcome = sync_src_link(&tmp_path, &src_link, "existing");
+        let outcome = sync_src_link(tmp_path, &src_link, "existing");
         assert!(outcome.is_err());
         let err = outcome.err().unwrap();
         let desc = err.to_string();
         assert!(desc.contains("existing"));
         Ok(())
     }
+
+    #[test]
+    fn metadata_loss_as_json() {
+        let loss = MetadataLoss {
+            attribute: "permissions",
+            expected: "755".to_string(),
+            actual: Some("644".to_string()),
+            reason: "not supported by the destination".to_string(),
+        };
+        assert_eq!(
+            loss.to_json("a_dir/foo.exe"),
+            "{\"path\": \"a_dir/foo.exe\", \"attribute\": \"permissions\", \
+             \"expected\": \"755\", \"actual\": \"644\", \
+             \"reason\": \"not supported by the destination\"}"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn owner_out_of_range() -> Result<(), std::io::Error> {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = TempDir::new("test-rusync-fsops")?;
+        let src_path = tmp_dir.path().join("src.txt");
+        let dest_path = tmp_dir.path().join("dest.txt");
+        fs::write(&src_path, "src")?;
+        fs::write(&dest_path, "src")?;
+        let src = Entry::new("src.txt", &src_path);
+        let dest = Entry::new("dest.txt", &dest_path);
+        let src_meta = fs::metadata(&src_path)?;
+        let offset = u32::MAX - src_meta.uid();
+        let loss = copy_ownership(&src, &dest, offset)
+            .unwrap()
+            .expect("owner can't be shifted to (uid_t)-1");
+        assert_eq!(
+            loss.to_json("dest.txt"),
+            format!(
+                "{{\"path\": \"dest.txt\", \"attribute\": \"owner\", \
+                 \"expected\": \"{}:{}\", \"actual\": null, \
+                 \"reason\": \"out of range once shifted by {}\"}}",
+                u32::MAX,
+                u64::from(src_meta.gid()) + u64::from(offset),
+                offset
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(Path::new("/a/./b/../c")), Path::new("/a/c"));
+        assert_eq!(normalize_path(Path::new("../a")), Path::new("../a"));
+    }
+
+    #[test]
+    fn test_truncate_name() {
+        let long = format!("{}.txt", "é".repeat(200));
+        let truncated = truncate_name(OsStr::new(&long), 255);
+        assert!(name_len(&truncated) <= 255);
+        let truncated = truncated.to_string_lossy();
+        assert!(truncated.starts_with("éé"));
+        assert!(truncated.ends_with(".txt"));
+        // Names with the same prefix stay different
+        let other = format!("{}.txt", "é".repeat(201));
+        assert_ne!(
+            truncate_name(OsStr::new(&other), 255).to_string_lossy(),
+            truncated
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn truncate_raw_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        // Not valid UTF-8, but short enough
+        let name = OsStr::from_bytes(&[0xe9; 200]);
+        assert_eq!(name_len(name), 200);
+        assert_eq!(
+            dest_rel_path(Path::new(name), Some(255), LongNames::Truncate).0,
+            Some(PathBuf::from(name))
+        );
+
+        let long = [&[0xe9; 300][..], b".txt"].concat();
+        let truncated = truncate_name(OsStr::from_bytes(&long), 255);
+        assert!(name_len(&truncated) <= 255);
+        assert!(truncated.as_bytes().starts_with(&[0xe9; 200]));
+        assert!(truncated.as_bytes().ends_with(b".txt"));
+
+        // "é" is 2 bytes: the stem is cut after a whole one
+        let long = "é".repeat(200);
+        let truncated = truncate_name(OsStr::new(&long), 20);
+        assert_eq!(truncated.to_str().map(|t| t.len()), Some(19));
+    }
+
+    #[test]
+    fn io_errors_have_a_kind() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-fsops")?;
+        let missing = Entry::new("missing", &tmp_dir.path().join("missing"));
+        let err = file_digest(&missing).unwrap_err();
+        assert_eq!(err.kind(), Some(std::io::ErrorKind::NotFound));
+        assert_eq!(Error::new("not from I/O").kind(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_temp_name() {
+        let first = temp_name("a.txt");
+        assert!(first.starts_with(".rusync-") && first.ends_with("-a.txt"));
+        assert_ne!(temp_name("a.txt"), first);
+    }
+
+    #[test]
+    fn test_dest_rel_path() {
+        let rel_path = Path::new("a_very_long_dir/sub/file.txt");
+        let (res, warning) = dest_rel_path(rel_path, None, LongNames::Skip);
+        assert_eq!(res, Some(rel_path.to_path_buf()));
+        assert_eq!(warning, None);
+
+        let (res, warning) = dest_rel_path(rel_path, Some(10), LongNames::Skip);
+        assert_eq!(res, None);
+        // Only the directory itself is reported
+        assert_eq!(warning, None);
+        let (res, warning) = dest_rel_path(Path::new("a_very_long_dir"), Some(10), LongNames::Skip);
+        assert_eq!(res, None);
+        assert!(warning.unwrap().starts_with("Skipping a_very_long_dir"));
+
+        let (res, _) = dest_rel_path(rel_path, Some(10), LongNames::Truncate);
+        let truncated = truncate_name(OsStr::new("a_very_long_dir"), 10);
+        assert_eq!(res, Some(Path::new(&truncated).join("sub/file.txt")));
+    }
+
+    #[test]
+    fn test_rewrite_link_target() {
+        let source = Path::new("/src");
+        let dest = Path::new("/backup/dest");
+        let rewrite = |target: &str, mode| {
+            rewrite_link_target(
+                Path::new(target),
+                Path::new("a_dir/link"),
+                source,
+                dest,
+                mode,
+            )
+        };
+        let relative = Some(LinkRewrite::Relative);
+        let reroot = Some(LinkRewrite::Reroot);
+
+        assert_eq!(
+            rewrite("/src/b_dir/three.txt", None),
+            Path::new("/src/b_dir/three.txt")
+        );
+        assert_eq!(
+            rewrite("/src/b_dir/three.txt", relative),
+            Path::new("../b_dir/three.txt")
+        );
+        assert_eq!(
+            rewrite("/src/a_dir/one.txt", relative),
+            Path::new("one.txt")
+        );
+        assert_eq!(
+            rewrite("/src/b_dir/three.txt", reroot),
+            Path::new("/backup/dest/b_dir/three.txt")
+        );
+        // Targets outside the source, or already relative, are left alone
+        assert_eq!(rewrite("/etc/passwd", relative), Path::new("/etc/passwd"));
+        assert_eq!(rewrite("one.txt", reroot), Path::new("one.txt"));
+    }
+
+    #[test]
+    fn test_link_escapes() {
+        let root = Path::new("/var/www");
+        let escapes = |target: &str| link_escapes(Path::new(target), Path::new("a/link"), root);
+        assert!(!escapes("one.txt"));
+        assert!(!escapes("../b/two.txt"));
+        assert!(!escapes("/var/www/b/two.txt"));
+        assert!(escapes("../../etc/passwd"));
+        assert!(escapes("/etc/passwd"));
+        assert!(escapes("b/../../../outside"));
+    }
 }
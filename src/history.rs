@@ -0,0 +1,413 @@
+//! history
+//!
+//! Keep a summary of every run, so that changes in how much is
+//! transferred from one run to the next can be spotted.
+//!
+//! The history is a text file starting with a `rusync-history 2` header,
+//! followed by one tab-separated line per run:
+//!
+//! ```text
+//! <time> <status> <files copied> <bytes copied> <duration, ms> <errors> <label, or -> <source> <destination>
+//! ```
+//!
+//! `<time>` is when the run ended, in seconds since the epoch, with
+//! nanoseconds: it tells which files were changed after a sync. The label
+//! and the paths are escaped as described in the escape module. Histories
+//! with a `rusync-history 1` header, where they are not, are still read,
+//! and rewritten the first time a run is added.
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::escape;
+use crate::sync::Stats;
+use crate::template;
+
+const HEADER: &str = "rusync-history 2";
+// Same fields, but not escaped
+const OLD_HEADER: &str = "rusync-history 1";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Complete,
+    /// Stopped early, see `Stats::stopped`
+    Partial,
+    Failed,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Complete => "complete",
+            Status::Partial => "partial",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+impl std::str::FromStr for Status {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "complete" => Ok(Status::Complete),
+            "partial" => Ok(Status::Partial),
+            "failed" => Ok(Status::Failed),
+            _ => Err(format!("unknown status: {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Run {
+    /// When the run ended, since the epoch
+    pub time: Duration,
+    pub status: Status,
+    pub copied: u64,
+    pub bytes: u64,
+    pub duration: Duration,
+    /// Entries skipped because of errors, or 1 for failed runs
+    pub errors: u64,
+    pub label: Option<String>,
+    pub source: String,
+    pub destination: String,
+}
+
+impl Run {
+    pub fn new(source: &Path, destination: &Path, stats: &Stats, duration: Duration) -> Run {
+        let status = if stats.stopped.is_some() {
+            Status::Partial
+        } else {
+            Status::Complete
+        };
+        Run {
+            time: now(),
+            status,
+            copied: stats.copied,
+            bytes: stats.transfers.total_bytes(),
+            duration,
+            errors: stats.errors_skipped,
+            label: stats.label.clone(),
+            source: display_path(source),
+            destination: display_path(destination),
+        }
+    }
+
+    pub fn failed(source: &Path, destination: &Path, duration: Duration) -> Run {
+        Run {
+            time: now(),
+            status: Status::Failed,
+            copied: 0,
+            bytes: 0,
+            duration,
+            errors: 1,
+            label: None,
+            source: display_path(source),
+            destination: display_path(destination),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{}.{:09}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.time.as_secs(),
+            self.time.subsec_nanos(),
+            self.status.name(),
+            self.copied,
+            self.bytes,
+            self.duration.as_millis(),
+            self.errors,
+            self.label.as_deref().map_or("-".to_string(), escape_field),
+            escape_field(&self.source),
+            escape_field(&self.destination)
+        )
+    }
+}
+
+fn escape_field(field: &str) -> String {
+    escape::escape(field.as_bytes())
+}
+
+fn unescape_field(field: &str) -> Option<String> {
+    let bytes = escape::unescape(field).ok()?;
+    Some(String::from_utf8_lossy(&bytes).to_string())
+}
+
+// Absolute, so that runs started from different directories can be compared
+fn display_path(path: &Path) -> String {
+    fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// Seconds since the epoch, with nanoseconds since the fraction was added
+fn parse_time(s: &str) -> Option<Duration> {
+    let (secs, nanos) = match s.split_once('.') {
+        Some((secs, nanos)) if nanos.len() == 9 => (secs, nanos.parse().ok()?),
+        Some(_) => return None,
+        None => (s, 0),
+    };
+    Some(Duration::new(secs.parse().ok()?, nanos))
+}
+
+/// `$XDG_DATA_HOME/rusync/history`, or `~/.local/share/rusync/history`
+/// (`%APPDATA%\rusync\history` on Windows)
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(unix)]
+    let data_dir = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share")));
+    #[cfg(windows)]
+    let data_dir = std::env::var_os("APPDATA").map(PathBuf::from);
+    data_dir.map(|d| d.join("rusync").join("history"))
+}
+
+pub fn append(path: &Path, run: &Run) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| Error::from_io(&format!("Could not create {:?}: {}", parent, e), &e))?;
+    }
+    if is_old(path) {
+        let mut runs = read(path)?;
+        runs.push(run.clone());
+        return write(path, &runs);
+    }
+    let is_new = !path.exists();
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| Error::from_io(&format!("Could not open {:?}: {}", path, e), &e))?;
+    let mut text = String::new();
+    if is_new {
+        text.push_str(HEADER);
+        text.push('\n');
+    }
+    text.push_str(&run.to_line());
+    file.write_all(text.as_bytes())
+        .map_err(|e| Error::from_io(&format!("Could not write to {:?}: {}", path, e), &e))
+}
+
+// True if `path` has the header of histories which were not escaped
+fn is_old(path: &Path) -> bool {
+    fs::read_to_string(path)
+        .map(|contents| contents.lines().next() == Some(OLD_HEADER))
+        .unwrap_or(false)
+}
+
+fn write(path: &Path, runs: &[Run]) -> Result<(), Error> {
+    let mut text = format!("{}\n", HEADER);
+    for run in runs {
+        text.push_str(&run.to_line());
+    }
+    fs::write(path, text)
+        .map_err(|e| Error::from_io(&format!("Could not write to {:?}: {}", path, e), &e))
+}
+
+pub fn read(path: &Path) -> Result<Vec<Run>, Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| Error::from_io(&format!("Could not read {:?}: {}", path, e), &e))?;
+    parse(&contents).map_err(|e| Error::new(&format!("Invalid history {:?}: {}", path, e)))
+}
+
+pub fn parse(contents: &str) -> Result<Vec<Run>, String> {
+    let mut lines = contents.lines();
+    let unescape: fn(&str) -> Option<String> = match lines.next() {
+        Some(HEADER) => unescape_field,
+        Some(OLD_HEADER) => |field: &str| Some(field.to_string()),
+        _ => return Err(format!("expected '{}' on the first line", HEADER)),
+    };
+    let mut runs = vec![];
+    for (i, line) in lines.enumerate() {
+        let invalid = || format!("invalid line {}: {:?}", i + 2, line);
+        let fields: Vec<&str> = line.split('\t').collect();
+        let run = match fields.as_slice() {
+            [time, status, copied, bytes, duration, errors, label, source, destination] => Run {
+                time: parse_time(time).ok_or_else(invalid)?,
+                status: status.parse().map_err(|_| invalid())?,
+                copied: copied.parse().map_err(|_| invalid())?,
+                bytes: bytes.parse().map_err(|_| invalid())?,
+                duration: Duration::from_millis(duration.parse().map_err(|_| invalid())?),
+                errors: errors.parse().map_err(|_| invalid())?,
+                label: match *label {
+                    "-" => None,
+                    label => Some(unescape(label).ok_or_else(invalid)?),
+                },
+                source: unescape(source).ok_or_else(invalid)?,
+                destination: unescape(destination).ok_or_else(invalid)?,
+            },
+            _ => return Err(invalid()),
+        };
+        runs.push(run);
+    }
+    Ok(runs)
+}
+
+/// When the last complete run between `source` and `destination` ended
+pub fn last_sync(runs: &[Run], source: &Path, destination: &Path) -> Option<SystemTime> {
+    let (source, destination) = (display_path(source), display_path(destination));
+    runs.iter()
+        .rev()
+        .find(|r| {
+            r.status == Status::Complete && r.source == source && r.destination == destination
+        })
+        .map(|r| UNIX_EPOCH + r.time)
+}
+
+/// True if `run` copied more than three times the median of the runs
+/// before it (with at least 3 of them), which is worth a closer look
+pub fn is_unusual(run: &Run, previous: &[Run]) -> bool {
+    if previous.len() < 3 {
+        return false;
+    }
+    let mut bytes: Vec<u64> = previous.iter().map(|r| r.bytes).collect();
+    bytes.sort_unstable();
+    let median = bytes[bytes.len() / 2];
+    run.bytes > 3 * median.max(1)
+}
+
+/// One line per run, oldest first. Runs are compared with
+/// previous runs between the same source and destination
+pub fn report(runs: &[Run]) -> String {
+    let mut res = format!(
+        "{:<19}  {:<8} {:>8} {:>14} {:>10} {:>6}  {}\n",
+        "date", "status", "files", "bytes", "duration", "errors", "destination"
+    );
+    for (i, run) in runs.iter().enumerate() {
+        let (year, month, day, hours, minutes, seconds) =
+            template::local_time(run.time.as_secs() as i64);
+        let previous: Vec<Run> = runs[..i]
+            .iter()
+            .filter(|r| r.source == run.source && r.destination == run.destination)
+            .cloned()
+            .collect();
+        let marker = if is_unusual(run, &previous) { " !" } else { "" };
+        res.push_str(&format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}  {:<8} {:>8} {:>14} {:>9}s {:>6}  {}{}\n",
+            year,
+            month,
+            day,
+            hours,
+            minutes,
+            seconds,
+            run.status.name(),
+            run.copied,
+            run.bytes,
+            run.duration.as_secs(),
+            run.errors,
+            run.destination,
+            marker
+        ));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+
+    fn run(bytes: u64) -> Run {
+        Run {
+            time: Duration::new(1_600_000_000, 250_000_000),
+            status: Status::Complete,
+            copied: 3,
+            bytes,
+            duration: Duration::from_millis(1500),
+            errors: 0,
+            label: None,
+            source: "src".to_string(),
+            destination: "dest".to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trip() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-history")?;
+        let path = tmp_dir.path().join("sub/history");
+        let mut partial = run(42);
+        partial.status = Status::Partial;
+        partial.label = Some("nightly".to_string());
+
+        append(&path, &run(10)).unwrap();
+        append(&path, &partial).unwrap();
+
+        assert_eq!(read(&path).unwrap(), vec![run(10), partial]);
+        Ok(())
+    }
+
+    #[test]
+    fn escape_fields() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-history")?;
+        let path = tmp_dir.path().join("history");
+        let mut odd = run(10);
+        odd.label = Some("tab\there".to_string());
+        odd.source = "new\nline".to_string();
+        odd.destination = "C:\\backup".to_string();
+
+        append(&path, &odd).unwrap();
+
+        assert_eq!(fs::read_to_string(&path)?.lines().count(), 2);
+        assert_eq!(read(&path).unwrap(), vec![odd]);
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade_old_histories() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-history")?;
+        let path = tmp_dir.path().join("history");
+        fs::write(
+            &path,
+            "rusync-history 1\n1600000000.250000000\tcomplete\t3\t10\t1500\t0\t-\tsrc\tC:\\dest\n",
+        )?;
+        let mut old = run(10);
+        old.destination = "C:\\dest".to_string();
+        assert_eq!(read(&path).unwrap(), vec![old.clone()]);
+
+        append(&path, &run(20)).unwrap();
+
+        assert!(fs::read_to_string(&path)?.starts_with("rusync-history 2\n"));
+        assert_eq!(read(&path).unwrap(), vec![old, run(20)]);
+        Ok(())
+    }
+
+    #[test]
+    fn read_whole_second_times() {
+        let runs = parse("rusync-history 1\n1600000000\tcomplete\t3\t10\t1500\t0\t-\tsrc\tdest\n");
+        let mut expected = run(10);
+        expected.time = Duration::from_secs(1_600_000_000);
+        assert_eq!(runs, Ok(vec![expected]));
+        assert!(
+            parse("rusync-history 1\n1600000000.25\tcomplete\t3\t10\t1500\t0\t-\tsrc\tdest\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_history() {
+        assert!(parse("not a history\n").is_err());
+        assert!(parse("rusync-history 1\n1\tcomplete\t1\n").is_err());
+        assert!(parse("rusync-history 1\n1\tdone\t1\t1\t1\t0\t-\ta\tb\n").is_err());
+    }
+
+    #[test]
+    fn spot_unusual_runs() {
+        let previous = vec![run(100), run(120), run(90)];
+        assert!(!is_unusual(&run(300), &previous));
+        assert!(is_unusual(&run(1000), &previous));
+        // Not enough runs to tell
+        assert!(!is_unusual(&run(1000), &previous[..2]));
+    }
+}
@@ -0,0 +1,35 @@
+//! Helpers to write machine-readable output without pulling a
+//! serialization framework
+
+/// Return `text` as a quoted JSON string
+pub fn quote(text: &str) -> String {
+    let mut res = String::with_capacity(text.len() + 2);
+    res.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("foo.txt"), "\"foo.txt\"");
+        assert_eq!(quote("a \"b\"\\c"), "\"a \\\"b\\\"\\\\c\"");
+        assert_eq!(quote("tab\there\n"), "\"tab\\there\\n\"");
+        assert_eq!(quote("\u{1}"), "\"\\u0001\"");
+    }
+}
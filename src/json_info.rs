@@ -0,0 +1,167 @@
+//! json_info
+//!
+//! Report progress as JSON, one object per line, for other programs.
+//!
+//! Every object has a `schema` field (see `SCHEMA`) and an `event` field:
+//!
+//! ```text
+//! {"schema": 1, "event": "file", "path": "a_dir/one.txt"}
+//! {"schema": 1, "event": "warning", "message": "..."}
+//! {"schema": 1, "event": "planned", "change": "copy", "path": "a_dir/one.txt"}
+//! {"schema": 1, "event": "hash", "sha256": "...", "path": "a_dir/one.txt"}
+//! {"schema": 1, "event": "deleting", "index": 1, "total": 3, "path": "old.txt"}
+//! {"schema": 1, "event": "fixing", "index": 1, "total": 2, "path": "a_dir"}
+//! {"schema": 1, "event": "stats", "dry_run": false, "copied": 3, ...}
+//! {"schema": 1, "event": "error", "message": "..."}
+//! ```
+//!
+//! Within a schema, changes are additive only: new events and new fields
+//! may appear, and should be ignored by readers that don't know them, but
+//! existing ones keep their names and meaning. Anything else bumps `SCHEMA`.
+use std::time::Duration;
+
+use crate::json;
+use crate::progress::{PlannedChange, ProgressInfo};
+use crate::sync::Stats;
+
+/// Version of the format of the objects written
+pub const SCHEMA: u32 = 1;
+
+#[derive(Default)]
+pub struct JsonProgressInfo {}
+
+impl JsonProgressInfo {
+    pub fn new() -> JsonProgressInfo {
+        JsonProgressInfo {}
+    }
+}
+
+impl ProgressInfo for JsonProgressInfo {
+    fn new_file(&self, name: &str) {
+        println!("{}", event("file", &[("path", json::quote(name))]));
+    }
+
+    fn warning(&self, message: &str) {
+        println!("{}", event("warning", &[("message", json::quote(message))]));
+    }
+
+    fn planned(&self, change: PlannedChange, description: &str) {
+        let fields = [
+            ("change", json::quote(change.name())),
+            ("path", json::quote(description)),
+        ];
+        println!("{}", event("planned", &fields));
+    }
+
+    fn hashed(&self, digest: &str, description: &str) {
+        let fields = [
+            ("sha256", json::quote(digest)),
+            ("path", json::quote(description)),
+        ];
+        println!("{}", event("hash", &fields));
+    }
+
+    fn deleting(&self, index: usize, total: usize, description: &str) {
+        let fields = [
+            ("index", index.to_string()),
+            ("total", total.to_string()),
+            ("path", json::quote(description)),
+        ];
+        println!("{}", event("deleting", &fields));
+    }
+
+    fn fixing_metadata(&self, index: usize, total: usize, description: &str) {
+        let fields = [
+            ("index", index.to_string()),
+            ("total", total.to_string()),
+            ("path", json::quote(description)),
+        ];
+        println!("{}", event("fixing", &fields));
+    }
+
+    fn end(&self, stats: &Stats) {
+        println!("{}", stats_event(stats));
+    }
+}
+
+/// The `error` event, for errors that stopped the sync
+pub fn error_event(message: &str) -> String {
+    event("error", &[("message", json::quote(message))])
+}
+
+fn event(name: &str, fields: &[(&str, String)]) -> String {
+    let mut res = format!("{{\"schema\": {}, \"event\": {}", SCHEMA, json::quote(name));
+    for (key, value) in fields {
+        res.push_str(&format!(", {}: {}", json::quote(key), value));
+    }
+    res.push('}');
+    res
+}
+
+fn optional(text: &Option<String>) -> String {
+    match text {
+        Some(text) => json::quote(text),
+        None => "null".to_string(),
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    duration.as_millis().to_string()
+}
+
+fn stats_event(stats: &Stats) -> String {
+    let fields = [
+        ("dry_run", stats.dry_run.to_string()),
+        ("label", optional(&stats.label)),
+        ("num_files", stats.num_files.to_string()),
+        ("num_dirs", stats.num_dirs.to_string()),
+        ("total_size", stats.total_size.to_string()),
+        ("num_synced", stats.num_synced.to_string()),
+        ("up_to_date", stats.up_to_date.to_string()),
+        ("copied", stats.copied.to_string()),
+        ("bytes_copied", stats.transfers.total_bytes().to_string()),
+        ("symlink_created", stats.symlink_created.to_string()),
+        ("symlink_updated", stats.symlink_updated.to_string()),
+        ("symlink_skipped", stats.symlink_skipped.to_string()),
+        ("symlink_up_to_date", stats.symlink_up_to_date.to_string()),
+        ("dirs_created", stats.dirs_created.to_string()),
+        ("dirs_renamed", stats.dirs_renamed.to_string()),
+        ("files_removed", stats.files_removed.to_string()),
+        ("symlinks_removed", stats.symlinks_removed.to_string()),
+        ("dirs_removed", stats.dirs_removed.to_string()),
+        ("verified", stats.verified.to_string()),
+        ("errors_skipped", stats.errors_skipped.to_string()),
+        ("errors_ignored", stats.errors_ignored.to_string()),
+        ("conflicts", stats.conflicts.to_string()),
+        ("stopped", optional(&stats.stopped)),
+        ("scan_ms", millis(stats.timings.scan)),
+        ("compare_ms", millis(stats.timings.compare)),
+        ("copy_ms", millis(stats.timings.copy)),
+        ("delete_ms", millis(stats.timings.delete)),
+        ("fix_metadata_ms", millis(stats.timings.fix_metadata)),
+    ];
+    event("stats", &fields)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        assert_eq!(
+            error_event("no \"dest\""),
+            "{\"schema\": 1, \"event\": \"error\", \"message\": \"no \\\"dest\\\"\"}"
+        );
+        let mut stats = Stats::new();
+        stats.copied = 3;
+        stats.stopped = Some("--max-files".to_string());
+        let stats = stats_event(&stats);
+        assert!(stats.starts_with("{\"schema\": 1, \"event\": \"stats\", \"dry_run\": false"));
+        assert!(stats.contains("\"label\": null, "));
+        assert!(stats.contains("\"copied\": 3, "));
+        assert!(stats.contains("\"stopped\": \"--max-files\", "));
+        assert!(stats.ends_with("\"fix_metadata_ms\": 0}"));
+    }
+}
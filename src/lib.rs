@@ -31,16 +31,40 @@
 //!
 extern crate colored;
 extern crate filetime;
+#[cfg(unix)]
+extern crate libc;
 extern crate term_size;
 
+pub mod access;
+pub mod batch;
+mod blocks;
+mod checkpoint;
+pub mod chunk_store;
+mod conflict;
 pub mod console_info;
+pub mod dupes;
 mod entry;
 mod error;
+mod escape;
 mod fsops;
+mod hash;
+pub mod history;
+mod json;
+pub mod json_info;
+pub mod manifest;
+pub mod pattern;
+pub mod probe;
 pub mod progress;
+mod rename;
+mod sample;
+mod space;
 pub mod sync;
+pub mod template;
+mod throttle;
+mod tune;
 mod workers;
 pub use crate::console_info::ConsoleProgressInfo;
+pub use crate::error::Error;
 pub use crate::sync::Stats;
 pub use crate::sync::SyncOptions;
 pub use crate::sync::Syncer;
@@ -2,13 +2,174 @@ extern crate colored;
 extern crate rusync;
 extern crate structopt;
 
+use colored::Colorize;
+use rusync::batch;
+use rusync::chunk_store::ChunkStore;
 use rusync::console_info::ConsoleProgressInfo;
-use rusync::sync::SyncOptions;
+use rusync::history;
+use rusync::json_info;
+use rusync::json_info::JsonProgressInfo;
+use rusync::pattern::Pattern;
+use rusync::progress::ProgressInfo;
+use rusync::sync::{ConflictPolicy, LinkRewrite, LongNames, PolicyRule, SyncOptions};
 use rusync::Syncer;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use structopt::clap::{Error, ErrorKind, Shell};
 use structopt::StructOpt;
 
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(name = "completions", about = "Print shell completions to stdout")]
+    Completions {
+        #[structopt(raw(possible_values = "&Shell::variants()"))]
+        shell: Shell,
+    },
+
+    #[structopt(
+        name = "manpage",
+        about = "Print a man page (in roff format) to stdout"
+    )]
+    Manpage,
+
+    #[structopt(
+        name = "manifest",
+        about = "Print a manifest of a directory to stdout, for use with --dry-run --manifest"
+    )]
+    Manifest {
+        #[structopt(
+            long = "checksums",
+            help = "Include the hash of every file, so that 'checksum' policies can be used"
+        )]
+        checksums: bool,
+
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+
+    #[structopt(
+        name = "index",
+        about = "Write a compact binary index of a directory, usable wherever a manifest is"
+    )]
+    Index {
+        #[structopt(
+            long = "checksums",
+            help = "Include the hash of every file, so that 'checksum' policies can be used"
+        )]
+        checksums: bool,
+
+        #[structopt(long = "out", parse(from_os_str), help = "Where to write the index")]
+        out: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+
+    #[structopt(
+        name = "diff",
+        about = "Print what syncing LEFT to RIGHT would change. Each side is a directory, a manifest or an index"
+    )]
+    Diff {
+        #[structopt(
+            long = "checksums",
+            help = "Compare files by contents when both sides have hashes (directories are hashed)"
+        )]
+        checksums: bool,
+
+        #[structopt(parse(from_os_str))]
+        left: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        right: PathBuf,
+    },
+
+    #[structopt(
+        name = "dupes",
+        about = "Report files with the same contents in a directory, and how much space they waste"
+    )]
+    Dupes {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+
+    #[structopt(
+        name = "snapshots",
+        about = "List the snapshots of a repository written with --snapshot"
+    )]
+    Snapshots {
+        #[structopt(parse(from_os_str))]
+        repository: PathBuf,
+    },
+
+    #[structopt(
+        name = "restore",
+        about = "Write the contents of a snapshot to a new or empty directory"
+    )]
+    Restore {
+        #[structopt(parse(from_os_str))]
+        repository: PathBuf,
+
+        snapshot: String,
+
+        #[structopt(parse(from_os_str))]
+        destination: PathBuf,
+    },
+
+    #[structopt(
+        name = "history",
+        about = "Show a summary of previous runs. Runs that copied much more than usual are marked with '!'"
+    )]
+    History {
+        #[structopt(
+            long = "file",
+            parse(from_os_str),
+            help = "Read this history file instead of the default one"
+        )]
+        file: Option<PathBuf>,
+
+        #[structopt(long = "last", help = "Only show the last N runs")]
+        last: Option<usize>,
+    },
+
+    #[structopt(
+        name = "batch",
+        about = "Run the syncs described in a jobs file, with the options given before 'batch', and print a combined report"
+    )]
+    Batch {
+        #[structopt(
+            long = "parallel",
+            help = "Run up to N jobs at the same time, overriding 'parallel' in the jobs file"
+        )]
+        parallel: Option<usize>,
+
+        #[structopt(
+            long = "max-open-files",
+            help = "Start fewer jobs at the same time if they could have more than N files open, overriding 'max_open_files' in the jobs file (the default is the limit of the process)"
+        )]
+        max_open_files: Option<usize>,
+
+        #[structopt(
+            long = "max-memory",
+            parse(try_from_str = "parse_size"),
+            help = "Start fewer jobs at the same time if their copy buffers could use more than SIZE (e.g. 100M), overriding 'max_memory' in the jobs file. What jobs keep about the entries they walk is not counted"
+        )]
+        max_memory: Option<u64>,
+
+        #[structopt(parse(from_os_str))]
+        jobs_file: PathBuf,
+    },
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "rusync")]
 struct Opt {
@@ -18,40 +179,1214 @@ struct Opt {
     )]
     no_preserve_permissions: bool,
 
-    #[structopt(parse(from_os_str))]
-    source: PathBuf,
+    #[structopt(long = "owner", help = "Preserve owner and group. no-op on Windows")]
+    preserve_ownership: bool,
+
+    #[structopt(
+        long = "chown-offset",
+        help = "Preserve owner and group, adding N to their ids, for instance to map them into the user namespace of a rootless container"
+    )]
+    chown_offset: Option<u32>,
+
+    #[structopt(
+        long = "delete",
+        help = "Delete files and directories from the destination that do not exist in the source"
+    )]
+    delete: bool,
+
+    #[structopt(
+        long = "rewrite-links",
+        help = "Rewrite absolute symlink targets pointing inside the source: 'relative' makes them relative to the link, 'reroot' makes them point inside the destination"
+    )]
+    rewrite_links: Option<LinkRewrite>,
+
+    #[structopt(
+        long = "long-names",
+        help = "What to do with entries whose names are too long for the destination: 'skip' (the default) or 'truncate'"
+    )]
+    long_names: Option<LongNames>,
+
+    #[structopt(
+        long = "checksum-symlinks",
+        help = "Warn when a copied symlink pointing outside the source points to different contents than the original"
+    )]
+    checksum_symlinks: bool,
+
+    #[structopt(
+        long = "safe-links",
+        help = "Skip symlinks whose targets would point outside the destination"
+    )]
+    safe_links: bool,
+
+    #[structopt(
+        long = "policy",
+        raw(number_of_values = "1"),
+        help = "Per-file policy, as GLOB:POLICY, for instance '*.iso:size-only'. POLICY is one of 'default', 'size-only', 'checksum' or 'append'. May be repeated, the first matching rule wins"
+    )]
+    policies: Vec<PolicyRule>,
+
+    #[structopt(
+        long = "first",
+        raw(number_of_values = "1"),
+        parse(from_str = "Pattern::new"),
+        help = "Sync entries matching this glob (for instance '*.sqlite' or 'latest/') before the others. May be repeated"
+    )]
+    first: Vec<Pattern>,
+
+    #[structopt(
+        long = "ignore-errors-from",
+        raw(number_of_values = "1"),
+        parse(from_str = "Pattern::new"),
+        help = "Only warn about entries matching this glob (for instance 'cache/' or '*.sock') that can't be synced, without failing. May be repeated"
+    )]
+    ignore_errors_from: Vec<Pattern>,
+
+    #[structopt(
+        long = "policy-file",
+        parse(from_os_str),
+        help = "Read per-file policies from this file, one GLOB: POLICY rule per line. Rules given with --policy come first"
+    )]
+    policy_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "dry-run",
+        help = "Do not change anything, only print what would be done"
+    )]
+    dry_run: bool,
+
+    #[structopt(
+        long = "audit",
+        help = "Like --dry-run, but also tell whether files that would be replaced differ by contents or only by metadata"
+    )]
+    audit: bool,
+
+    #[structopt(
+        long = "manifest",
+        parse(from_os_str),
+        help = "With --dry-run, compare against this manifest (generated by 'rusync manifest') instead of the destination"
+    )]
+    manifest: Option<PathBuf>,
+
+    #[structopt(
+        long = "max-files-per-sec",
+        help = "Do not process more than this many files, symlinks and directories per second, to spare slow (network) file systems"
+    )]
+    max_files_per_sec: Option<u32>,
+
+    #[structopt(
+        long = "estimate-first",
+        help = "Walk the whole source before copying anything, so that progress and ETA are accurate from the start"
+    )]
+    estimate_first: bool,
+
+    #[structopt(
+        long = "checkpoint",
+        parse(try_from_str = "parse_size"),
+        conflicts_with = "temp_dir",
+        help = "Copy files bigger than SIZE (e.g. 512M, 10G) so that an interrupted copy can resume, writing a checkpoint every SIZE bytes"
+    )]
+    checkpoint: Option<u64>,
+
+    #[structopt(
+        long = "max-bytes",
+        parse(try_from_str = "parse_size"),
+        help = "Do not start copying new files once SIZE bytes (e.g. 500M, 2G) have been copied"
+    )]
+    max_bytes: Option<u64>,
+
+    #[structopt(
+        long = "max-files",
+        help = "Do not start copying new files once this many files have been copied"
+    )]
+    max_files: Option<u64>,
+
+    #[structopt(
+        long = "max-duration",
+        parse(try_from_str = "parse_duration"),
+        help = "Do not start copying new files after this long (e.g. 45m, 2h, 1h30m). Exits with status 2 if the sync was not complete"
+    )]
+    max_duration: Option<Duration>,
+
+    #[structopt(
+        long = "stop-timeout",
+        parse(try_from_str = "parse_duration"),
+        help = "On SIGTERM, stop cleanly so that running again resumes the sync, and exit with status 3. If that takes longer than this (30s by default), exit with status 143 right away"
+    )]
+    stop_timeout: Option<Duration>,
+
+    #[structopt(
+        long = "deleted-list",
+        parse(from_os_str),
+        help = "Write the paths removed from the destination (or that would be, with --dry-run) to this file, one per line"
+    )]
+    deleted_list: Option<PathBuf>,
+
+    #[structopt(
+        long = "verify-sample",
+        parse(try_from_str = "parse_percent"),
+        help = "Read again a random sample of this percentage of the copied files, and check they match the source"
+    )]
+    verify_sample: Option<f64>,
+
+    #[structopt(
+        long = "temp-dir",
+        parse(from_os_str),
+        help = "Write files in this directory first, then move them to the destination"
+    )]
+    temp_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "json",
+        help = "Print progress and stats as JSON objects, one per line, instead of text"
+    )]
+    json: bool,
+
+    #[structopt(
+        long = "stats",
+        help = "At the end, also show copied files by extension and by size, the largest ones, and the time spent in each phase"
+    )]
+    stats: bool,
+
+    #[structopt(
+        long = "history-file",
+        parse(from_os_str),
+        help = "Append a summary of the run to this file instead of the default one (see 'rusync history')"
+    )]
+    history_file: Option<PathBuf>,
+
+    #[structopt(long = "no-history", help = "Do not record this run in the history")]
+    no_history: bool,
+
+    #[structopt(
+        long = "check-space",
+        help = "Before syncing, check that the destination has enough free space and inodes"
+    )]
+    check_space: bool,
+
+    #[structopt(
+        long = "check-access",
+        help = "Do not sync, but list the destination directories and files that the sync would need to change and can't. Exits with status 1 if there are any"
+    )]
+    check_access: bool,
+
+    #[structopt(
+        long = "auto-tune",
+        help = "Measure copy speed during the first seconds, and pick the size of the copy buffer accordingly"
+    )]
+    auto_tune: bool,
+
+    #[structopt(
+        long = "no-legend",
+        help = "With --dry-run or --audit, don't print what the colors of changes mean before listing them"
+    )]
+    no_legend: bool,
+
+    #[structopt(
+        long = "progress-fd",
+        help = "Write the progress line to this file descriptor instead of stderr (2), for instance 1 for stdout. Changes listed by --dry-run and the final stats always go to stdout"
+    )]
+    progress_fd: Option<i32>,
+
+    #[structopt(
+        long = "block-sidecars",
+        help = "Write the hashes of the blocks of each copied file next to it, so that later syncs with the 'checksum' policy don't need to read the destination"
+    )]
+    block_sidecars: bool,
+
+    #[structopt(
+        long = "on-conflict",
+        help = "What to do with files changed in both the source and the destination since the last complete sync recorded in the history: 'source-wins' (the default), 'dest-wins', 'newer-wins', 'larger-wins' or 'rename-both'"
+    )]
+    on_conflict: Option<ConflictPolicy>,
 
-    #[structopt(parse(from_os_str))]
-    destination: PathBuf,
+    #[structopt(
+        long = "print-hashes",
+        conflicts_with = "json",
+        help = "Print the SHA-256 of every file read during the sync to stdout, in the format of sha256sum. The changes of a dry run and the stats go to stderr instead"
+    )]
+    print_hashes: bool,
+
+    #[structopt(
+        long = "force",
+        help = "Replace destination entries that have a different type (file, directory or symlink) than in the source, instead of aborting"
+    )]
+    force: bool,
+
+    #[structopt(
+        long = "dir-times",
+        help = "Preserve modification times of directories"
+    )]
+    dir_times: bool,
+
+    #[structopt(long = "times", help = "Preserve modification times of files")]
+    file_times: bool,
+
+    #[structopt(
+        long = "archive",
+        help = "Preserve as much as possible, like rsync -a: same as --times --dir-times --owner"
+    )]
+    archive: bool,
+
+    #[structopt(
+        long = "mirror",
+        help = "Make the destination an exact copy of the source: same as --delete --times --dir-times"
+    )]
+    mirror: bool,
+
+    #[structopt(
+        long = "metadata-report",
+        parse(from_os_str),
+        help = "Instead of aborting when permissions or ownership cannot be preserved, write the lost attributes to this file, as JSON lines"
+    )]
+    metadata_report: Option<PathBuf>,
+
+    #[structopt(
+        long = "rsync-trailing-slash",
+        help = "Follow rsync's trailing slash convention: 'src/' syncs the contents of src, 'src' creates <destination>/src"
+    )]
+    rsync_trailing_slash: bool,
+
+    #[structopt(
+        long = "cow-dest",
+        help = "Create destination files as copy-on-write clones when both trees are on a file system supporting reflinks"
+    )]
+    reflink: bool,
+
+    #[structopt(
+        long = "snapshot",
+        help = "Store the source as a new snapshot with the given name, in a deduplicated chunk repository located in the destination"
+    )]
+    snapshot: Option<String>,
+
+    #[structopt(
+        long = "pick",
+        help = "Interactively pick which top-level entries of the source to sync"
+    )]
+    pick: bool,
+
+    #[structopt(
+        long = "only",
+        raw(number_of_values = "1"),
+        parse(from_os_str),
+        help = "Only sync this path, relative to the source (for instance '2024/05/'). Only what leads to it is walked. May be repeated"
+    )]
+    only: Vec<PathBuf>,
+
+    #[structopt(
+        long = "profile",
+        help = "Take the source, destination and settings from the job with this label in the jobs file"
+    )]
+    profile: Option<String>,
+
+    #[structopt(
+        long = "jobs-file",
+        parse(from_os_str),
+        help = "Jobs file used by --profile, instead of ~/.config/rusync/jobs.toml"
+    )]
+    jobs_file: Option<PathBuf>,
+
+    #[structopt(
+        parse(from_os_str),
+        help = "To sync a directory named like a subcommand, put <source> and <destination> after '--'"
+    )]
+    source: Option<PathBuf>,
+
+    #[structopt(
+        parse(from_os_str),
+        help = "May contain {date}, {time} and {hostname} placeholders. Use {{ for a literal {"
+    )]
+    destination: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
 impl Opt {
     fn preserve_permissions(&self) -> bool {
         !self.no_preserve_permissions
     }
+
+    fn preserve_ownership(&self) -> bool {
+        self.preserve_ownership || self.archive || self.chown_offset.is_some()
+    }
+
+    fn delete(&self) -> bool {
+        self.delete || self.mirror
+    }
+
+    fn dir_times(&self) -> bool {
+        self.dir_times || self.archive || self.mirror
+    }
+
+    fn file_times(&self) -> bool {
+        self.file_times || self.archive || self.mirror
+    }
 }
 
 fn main() {
-    let opt = Opt::from_args();
-    let source = &opt.source;
+    let opt = parse_args();
+    if let Some(ref command) = opt.command {
+        run_command(&opt, command);
+        return;
+    }
+    let profile = opt.profile.as_ref().map(|label| find_profile(&opt, label));
+    let (source, destination) = match (&profile, &opt.source, &opt.destination) {
+        (Some(job), None, None) => (&job.source, &job.destination),
+        (Some(_), _, _) => Error::with_description(
+            "<source> and <destination> come from the jobs file with --profile",
+            ErrorKind::ArgumentConflict,
+        )
+        .exit(),
+        (None, Some(source), Some(destination)) => (source, destination),
+        _ => Error::with_description(
+            "<source> and <destination> are required",
+            ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
     if !source.is_dir() {
         eprintln!("{} is not a directory", source.to_string_lossy());
         process::exit(1);
     }
-    let destination = &opt.destination;
+    let destination = match rusync::template::expand_path(destination) {
+        Ok(destination) => destination,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
 
-    let console_info = ConsoleProgressInfo::new();
-    let mut options = SyncOptions::new();
-    options.preserve_permissions = opt.preserve_permissions();
-    let syncer = Syncer::new(&source, &destination, options, Box::new(console_info));
+    let mut options = sync_options(&opt);
+    if let Some(ref job) = profile {
+        options = job.options(&options);
+    }
+    options.interrupt = stop_on_sigterm(&opt);
+    if opt.on_conflict.is_some() {
+        options.last_sync = last_sync(opt.history_file.clone(), source, &destination);
+    }
+    if opt.pick {
+        match pick_entries(source) {
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+            Ok(ref picked) if picked.is_empty() => {
+                eprintln!("Nothing picked, exiting");
+                process::exit(0);
+            }
+            Ok(picked) => options.only = picked,
+        }
+    }
+    if opt.check_access {
+        let syncer = Syncer::new(source, &destination, options, progress_info(&opt));
+        check_access(syncer);
+    }
+    let dry_run = options.dry_run || options.audit;
+    let syncer = Syncer::new(source, &destination, options, progress_info(&opt));
+    let start = Instant::now();
     let stats = syncer.sync();
+    if !dry_run && !opt.no_history {
+        let run = match stats {
+            Ok(ref stats) => history::Run::new(source, &destination, stats, start.elapsed()),
+            Err(_) => history::Run::failed(source, &destination, start.elapsed()),
+        };
+        record_run(opt.history_file.clone(), &run);
+    }
+    if let (Some(path), Ok(stats)) = (&opt.deleted_list, &stats) {
+        if let Err(err) = write_deleted_list(path, &stats.removed) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
     match stats {
         Err(err) => {
+            if opt.json {
+                println!("{}", json_info::error_event(&err.to_string()));
+            }
             eprintln!("{}", err);
             process::exit(1);
         }
+        Ok(_) if interrupted() => process::exit(3),
+        Ok(ref stats) if stats.stopped.is_some() => {
+            // Partial, but running again resumes the sync
+            process::exit(2);
+        }
         Ok(_) => {
             process::exit(0);
         }
     }
 }
+
+fn progress_info(opt: &Opt) -> Box<dyn ProgressInfo + Send> {
+    if opt.json {
+        return Box::new(JsonProgressInfo::new());
+    }
+    let mut console_info = ConsoleProgressInfo::new();
+    console_info.print_hashes = opt.print_hashes;
+    console_info.detailed_stats = opt.stats;
+    console_info.legend = !opt.no_legend;
+    if let Some(fd) = opt.progress_fd {
+        console_info.progress_to(progress_output(fd));
+    }
+    Box::new(console_info)
+}
+
+#[cfg(unix)]
+fn progress_output(fd: i32) -> Box<dyn Write + Send> {
+    use std::os::unix::io::FromRawFd;
+
+    match fd {
+        1 => return Box::new(io::stdout()),
+        2 => return Box::new(io::stderr()),
+        _ => (),
+    }
+    // A copy, since every job of a batch closes its own
+    let copy = unsafe { libc::dup(fd) };
+    if copy == -1 {
+        eprintln!("--progress-fd: {} is not an open file descriptor", fd);
+        process::exit(1);
+    }
+    Box::new(unsafe { fs::File::from_raw_fd(copy) })
+}
+
+#[cfg(not(unix))]
+fn progress_output(fd: i32) -> Box<dyn Write + Send> {
+    match fd {
+        1 => Box::new(io::stdout()),
+        2 => Box::new(io::stderr()),
+        _ => {
+            eprintln!("--progress-fd: only 1 and 2 are supported on this platform");
+            process::exit(1);
+        }
+    }
+}
+
+// Options given on the command line, shared by all the jobs of a batch
+fn sync_options(opt: &Opt) -> SyncOptions {
+    let mut options = SyncOptions::new();
+    options.preserve_permissions = opt.preserve_permissions();
+    options.preserve_ownership = opt.preserve_ownership();
+    options.chown_offset = opt.chown_offset.unwrap_or(0);
+    options.dir_times = opt.dir_times();
+    options.file_times = opt.file_times();
+    options.delete = opt.delete();
+    options.rewrite_links = opt.rewrite_links;
+    options.checksum_symlinks = opt.checksum_symlinks;
+    if let Some(long_names) = opt.long_names {
+        options.long_names = long_names;
+    }
+    options.safe_links = opt.safe_links;
+    options.force = opt.force;
+    options.hash_files = opt.print_hashes;
+    if let Some(on_conflict) = opt.on_conflict {
+        options.on_conflict = on_conflict;
+    }
+    options.metadata_report = opt.metadata_report.clone();
+    options.snapshot = opt.snapshot.clone();
+    options.rsync_trailing_slash = opt.rsync_trailing_slash;
+    options.reflink = opt.reflink;
+    options.estimate_first = opt.estimate_first;
+    options.dry_run = opt.dry_run;
+    options.max_files_per_sec = opt.max_files_per_sec;
+    options.checkpoint_interval = opt.checkpoint;
+    options.max_bytes = opt.max_bytes;
+    options.max_files = opt.max_files;
+    options.max_duration = opt.max_duration;
+    options.verify_sample = opt.verify_sample;
+    options.temp_dir = opt.temp_dir.clone();
+    options.manifest = opt.manifest.clone();
+    options.audit = opt.audit;
+    options.policies = opt.policies.clone();
+    options.first = opt.first.clone();
+    options.ignore_errors_from = opt.ignore_errors_from.clone();
+    for path in &opt.only {
+        let is_relative = path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if !is_relative {
+            eprintln!("--only {:?}: expected a path inside the source", path);
+            process::exit(1);
+        }
+    }
+    options.only = opt.only.clone();
+    options.check_space = opt.check_space;
+    options.auto_tune = opt.auto_tune;
+    options.block_sidecars = opt.block_sidecars;
+    if let Some(ref policy_file) = opt.policy_file {
+        match read_policy_file(policy_file) {
+            Ok(rules) => options.policies.extend(rules),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+    options
+}
+
+// clap refuses positional arguments named like a subcommand (or close to
+// one), even after '--': take <source> and <destination> from what follows
+// '--' ourselves, and point to it when a path was taken for a subcommand
+fn parse_args() -> Opt {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let err = match Opt::from_iter_safe(&args) {
+        Ok(opt) => return opt,
+        Err(err) => err,
+    };
+    if err.kind == ErrorKind::HelpDisplayed || err.kind == ErrorKind::VersionDisplayed {
+        err.exit();
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--") {
+        if let Ok(mut opt) = Opt::from_iter_safe(&args[..i]) {
+            let mut paths: Vec<PathBuf> =
+                opt.source.iter().chain(&opt.destination).cloned().collect();
+            paths.extend(args[i + 1..].iter().map(PathBuf::from));
+            if opt.command.is_none() && paths.len() <= 2 {
+                let mut paths = paths.into_iter();
+                opt.source = paths.next();
+                opt.destination = paths.next();
+                return opt;
+            }
+        }
+    }
+    // What clap took for a subcommand
+    let clash = match err.kind {
+        ErrorKind::InvalidSubcommand => err
+            .info
+            .as_ref()
+            .and_then(|info| info.first())
+            .map(OsString::from),
+        _ => args[1..]
+            .iter()
+            .find(|arg| is_subcommand(arg) && Path::new(arg).exists())
+            .cloned(),
+    };
+    match clash {
+        Some(path) => {
+            eprintln!("{}", err.message);
+            eprintln!(
+                "If {} is a source or destination, put them after '--': rusync [OPTIONS] -- <source> <destination>",
+                path.to_string_lossy()
+            );
+            process::exit(1);
+        }
+        None => err.exit(),
+    }
+}
+
+fn is_subcommand(arg: &OsStr) -> bool {
+    match Command::clap().get_matches_from_safe([OsStr::new("rusync"), arg]) {
+        Ok(_) => true,
+        Err(err) => !matches!(
+            err.kind,
+            ErrorKind::UnknownArgument
+                | ErrorKind::UnrecognizedSubcommand
+                | ErrorKind::InvalidSubcommand
+        ),
+    }
+}
+
+fn run_command(opt: &Opt, command: &Command) {
+    let mut app = Opt::clap();
+    match command {
+        Command::Batch {
+            parallel,
+            max_open_files,
+            max_memory,
+            jobs_file,
+        } => {
+            let limits = (*max_open_files, *max_memory);
+            run_batch(opt, jobs_file, *parallel, limits)
+        }
+        Command::Completions { shell } => {
+            app.gen_completions_to("rusync", *shell, &mut io::stdout());
+        }
+        Command::Manpage => {
+            // Unwrapped, so that lines are only split by the man page reader
+            let mut app = app.set_term_width(0);
+            let mut help = vec![];
+            if let Err(e) = app.write_long_help(&mut help) {
+                eprintln!("Could not generate help: {}", e);
+                process::exit(1);
+            }
+            print!("{}", to_manpage(&String::from_utf8_lossy(&help)));
+        }
+        Command::History { file, last } => {
+            let path = match file.clone().or_else(history::default_path) {
+                Some(path) => path,
+                None => {
+                    eprintln!("Could not find the history file, use --file");
+                    process::exit(1);
+                }
+            };
+            if !path.exists() {
+                println!("No runs recorded yet");
+                return;
+            }
+            match history::read(&path) {
+                Ok(runs) => {
+                    let report = history::report(&runs);
+                    let mut lines: Vec<&str> = report.lines().collect();
+                    if let Some(last) = last {
+                        // Keep the header
+                        let skip = lines.len().saturating_sub(last + 1);
+                        lines.drain(1..1 + skip);
+                    }
+                    for line in lines {
+                        println!("{}", line);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        Command::Manifest { checksums, dir } => {
+            match rusync::manifest::Manifest::from_tree(dir, *checksums) {
+                Ok(manifest) => print!("{}", manifest.to_text()),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        Command::Dupes { dir } => match rusync::dupes::find(dir) {
+            Ok(groups) => print!("{}", rusync::dupes::report(&groups)),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        },
+        Command::Snapshots { repository } => {
+            let res = ChunkStore::open_existing(repository).and_then(|store| store.snapshots());
+            match res {
+                Ok(snapshots) => {
+                    for info in snapshots {
+                        println!("{}\t{} files\t{} bytes", info.name, info.files, info.bytes);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        Command::Restore {
+            repository,
+            snapshot,
+            destination,
+        } => {
+            let res = ChunkStore::open_existing(repository)
+                .and_then(|store| store.restore(snapshot, destination));
+            match res {
+                Ok(count) => println!(
+                    "Restored {} entries of {} to {}",
+                    count,
+                    snapshot,
+                    destination.to_string_lossy()
+                ),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        Command::Index {
+            checksums,
+            out,
+            dir,
+        } => {
+            let res = rusync::manifest::Manifest::from_tree(dir, *checksums).and_then(|m| {
+                fs::write(out, m.to_index())
+                    .map_err(|e| rusync::Error::new(&format!("Could not write {:?}: {}", out, e)))
+            });
+            if let Err(err) = res {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        Command::Diff {
+            checksums,
+            left,
+            right,
+        } => {
+            let load = |path: &Path| match rusync::manifest::Manifest::load(path, *checksums) {
+                Ok(manifest) => manifest,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            };
+            for (change, path) in rusync::manifest::diff(&load(left), &load(right)) {
+                println!("{:<7} {}", change.name(), path.to_string_lossy());
+            }
+        }
+    }
+}
+
+fn find_profile(opt: &Opt, label: &str) -> batch::Job {
+    let path = match opt.jobs_file.clone().or_else(batch::default_path) {
+        Some(path) => path,
+        None => {
+            eprintln!("Could not find the jobs file, use --jobs-file");
+            process::exit(1);
+        }
+    };
+    let batch = match batch::read(&path) {
+        Ok(batch) => batch,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    match batch::find(&batch, label) {
+        Some(job) => job.clone(),
+        None => {
+            eprintln!("No job labelled {:?} in {:?}", label, path);
+            process::exit(1);
+        }
+    }
+}
+
+// Only warnings are shown when jobs run in parallel,
+// since their progress would be mixed up
+struct JobProgressInfo {
+    name: String,
+}
+
+impl ProgressInfo for JobProgressInfo {
+    fn warning(&self, message: &str) {
+        eprintln!("{} {}: {}", "Warning:".color("yellow"), self.name, message);
+    }
+}
+
+// `limits` are the open files and memory given on the command line
+fn run_batch(
+    opt: &Opt,
+    jobs_file: &Path,
+    parallel: Option<usize>,
+    limits: (Option<usize>, Option<u64>),
+) {
+    let mut batch = match batch::read(jobs_file) {
+        Ok(batch) => batch,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    if let Some(parallel) = parallel {
+        batch.parallel = parallel.max(1);
+    }
+    let (max_open_files, max_memory) = limits;
+    if max_open_files.is_some() {
+        batch.max_open_files = max_open_files;
+    }
+    if max_memory.is_some() {
+        batch.max_memory = max_memory;
+    }
+    for job in &mut batch.jobs {
+        match rusync::template::expand_path(&job.destination) {
+            Ok(destination) => job.destination = destination,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        if opt.on_conflict.is_some() {
+            job.last_sync = last_sync(opt.history_file.clone(), &job.source, &job.destination);
+        }
+    }
+    let mut options = sync_options(opt);
+    options.interrupt = stop_on_sigterm(opt);
+    let dry_run = options.dry_run || options.audit;
+    let outcomes = batch::run(&batch, &options, |job| -> Box<dyn ProgressInfo + Send> {
+        if batch.parallel > 1 && !opt.json {
+            return Box::new(JobProgressInfo { name: job.name() });
+        }
+        progress_info(opt)
+    });
+    if !dry_run && !opt.no_history {
+        for outcome in &outcomes {
+            let job = &outcome.job;
+            let run = match outcome.result {
+                Ok(ref stats) => {
+                    history::Run::new(&job.source, &job.destination, stats, outcome.duration)
+                }
+                Err(_) => history::Run::failed(&job.source, &job.destination, outcome.duration),
+            };
+            record_run(opt.history_file.clone(), &run);
+        }
+    }
+    // With --json, jobs that did not fail already printed their stats
+    if opt.json {
+        for outcome in &outcomes {
+            if let Err(ref err) = outcome.result {
+                let message = format!("{}: {}", outcome.job.name(), err);
+                println!("{}", json_info::error_event(&message));
+            }
+        }
+    } else {
+        println!("{} Batch report:", "::".color("blue"));
+        print!("{}", batch::report(&outcomes));
+    }
+    if outcomes.iter().any(|o| o.result.is_err()) {
+        process::exit(1);
+    }
+    if interrupted() {
+        process::exit(3);
+    }
+    if outcomes
+        .iter()
+        .any(|o| o.result.as_ref().map_or(false, |s| s.stopped.is_some()))
+    {
+        process::exit(2);
+    }
+}
+
+// Turn the output of --help into a man page: each heading (USAGE:,
+// FLAGS:, ...) starts a section, and each argument a tagged paragraph
+// with its help below it
+fn to_manpage(help: &str) -> String {
+    let mut res = format!(
+        ".TH RUSYNC 1 \"\" \"rusync {version}\"\n\
+         .SH NAME\n\
+         rusync \\- {description}\n",
+        version = env!("CARGO_PKG_VERSION"),
+        description = env!("CARGO_PKG_DESCRIPTION"),
+    );
+    let mut section = "";
+    // The name, authors and description come first
+    for line in help.lines().skip_while(|line| *line != "USAGE:") {
+        let text = line.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        if indent == 0 {
+            section = text.trim_end_matches(':');
+            let heading = match section {
+                "USAGE" => "SYNOPSIS",
+                heading => heading,
+            };
+            res.push_str(&format!(".SH {}\n", heading));
+        } else if section != "USAGE" && indent <= 8 {
+            res.push_str(&format!(".TP\n\\fB{}\\fR\n", roff_escape(text)));
+        } else {
+            res.push_str(&format!("{}\n", roff_escape(text)));
+        }
+    }
+    res
+}
+
+fn roff_escape(text: &str) -> String {
+    let text = text.replace('\\', "\\\\").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
+
+/// Parse a number of bytes, with an optional K, M, G or T suffix
+/// (powers of 1024)
+fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, multiplier) = match text.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&text[..text.len() - 1], 1 << 10),
+        Some('M') => (&text[..text.len() - 1], 1 << 20),
+        Some('G') => (&text[..text.len() - 1], 1 << 30),
+        Some('T') => (&text[..text.len() - 1], 1 << 40),
+        _ => (text, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("Invalid size: {:?}", text))
+}
+
+/// Parse a duration such as 90s, 45m, 2h or 1h30m. Numbers
+/// without a unit are seconds
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration: {:?}", text);
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(invalid()),
+        };
+        let n: u64 = number.parse().map_err(|_| invalid())?;
+        seconds = n
+            .checked_mul(unit)
+            .and_then(|n| seconds.checked_add(n))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        let n: u64 = number.parse().map_err(|_| invalid())?;
+        seconds = seconds.checked_add(n).ok_or_else(invalid)?;
+    }
+    if seconds == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+fn parse_percent(text: &str) -> Result<f64, String> {
+    match text.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("Invalid percentage: {:?}", text)),
+    }
+}
+
+// Set once by stop_on_sigterm(), and never freed, so that
+// the signal handler can read it without taking a lock
+static STOP_REQUESTED: AtomicPtr<AtomicBool> = AtomicPtr::new(ptr::null_mut());
+
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn stop_requested() -> Option<&'static AtomicBool> {
+    unsafe { STOP_REQUESTED.load(Ordering::SeqCst).as_ref() }
+}
+
+fn interrupted() -> bool {
+    stop_requested().map_or(false, |stop| stop.load(Ordering::SeqCst))
+}
+
+// Only sets the flag: the sync notices it between files (or between
+// chunks of files copied with --checkpoint), and stops like on --max-files
+#[cfg(unix)]
+extern "C" fn on_sigterm(_: libc::c_int) {
+    if let Some(stop) = stop_requested() {
+        stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// Returns the flag to stop the sync with, set on SIGTERM. If the sync does
+// not stop in time, exit anyway: what was copied so far is still there, only
+// the last file (or what is after its last checkpoint) will be copied again
+#[cfg(unix)]
+fn stop_on_sigterm(opt: &Opt) -> Option<Arc<AtomicBool>> {
+    if stop_requested().is_none() {
+        let stop = Arc::new(AtomicBool::new(false));
+        STOP_REQUESTED.store(Arc::into_raw(stop) as *mut AtomicBool, Ordering::SeqCst);
+    }
+    let stop_ptr = STOP_REQUESTED.load(Ordering::SeqCst) as *const AtomicBool;
+    // Another reference to it, STOP_REQUESTED keeps its own
+    let stop = unsafe {
+        Arc::increment_strong_count(stop_ptr);
+        Arc::from_raw(stop_ptr)
+    };
+    let handler = on_sigterm as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+    let timeout = opt.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT);
+    thread::spawn(move || {
+        while !interrupted() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        eprintln!(
+            "Received SIGTERM, stopping (waiting at most {}s)",
+            timeout.as_secs()
+        );
+        thread::sleep(timeout);
+        eprintln!("Could not stop in time, exiting");
+        process::exit(143);
+    });
+    Some(stop)
+}
+
+#[cfg(not(unix))]
+fn stop_on_sigterm(_opt: &Opt) -> Option<Arc<AtomicBool>> {
+    None
+}
+
+fn check_access(syncer: Syncer) -> ! {
+    match syncer.check_access() {
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        Ok(ref problems) if problems.is_empty() => {
+            println!("{} Nothing to sync is out of reach", "::".color("blue"));
+            process::exit(0);
+        }
+        Ok(problems) => {
+            let total: usize = problems.iter().map(|p| p.entries.len()).sum();
+            println!(
+                "{} {} entries can't be synced, because of {} paths:",
+                "::".color("blue"),
+                total,
+                problems.len()
+            );
+            for problem in problems {
+                println!("{}", problem.describe());
+            }
+            process::exit(1);
+        }
+    }
+}
+
+// Without a history, nothing is known to have been synced before,
+// so there are no conflicts
+fn last_sync(path: Option<PathBuf>, source: &Path, destination: &Path) -> Option<SystemTime> {
+    let path = path.or_else(history::default_path)?;
+    if !path.exists() {
+        return None;
+    }
+    match history::read(&path) {
+        Ok(runs) => history::last_sync(&runs, source, destination),
+        Err(err) => {
+            eprintln!(
+                "{} {}: conflicts can't be detected",
+                "Warning:".color("yellow"),
+                err
+            );
+            None
+        }
+    }
+}
+
+// Failing to record the run is not worth failing the whole command. Only
+// warn about the file given with --history-file: the default one is
+// an extra, which may not be writable
+fn record_run(path: Option<PathBuf>, run: &history::Run) {
+    let explicit = path.is_some();
+    let path = match path.or_else(history::default_path) {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(err) = history::append(&path, run) {
+        if explicit {
+            eprintln!("{} {}", "Warning:".color("yellow"), err);
+        }
+    }
+}
+
+fn write_deleted_list(path: &Path, removed: &[String]) -> Result<(), String> {
+    let mut contents = String::new();
+    for description in removed {
+        contents.push_str(description);
+        contents.push('\n');
+    }
+    fs::write(path, contents).map_err(|e| format!("Could not write {:?}: {}", path, e))
+}
+
+fn read_policy_file(path: &Path) -> Result<Vec<PolicyRule>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.to_string_lossy(), e))?;
+    rusync::sync::parse_policy_rules(&contents)
+        .map_err(|e| format!("In {}: {}", path.to_string_lossy(), e))
+}
+
+fn pick_entries(source: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(source)
+        .map_err(|e| format!("Could not read {}: {}", source.to_string_lossy(), e))?;
+    let mut names: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| PathBuf::from(e.file_name()))
+        .collect();
+    names.sort();
+
+    // stdout is for data, so ask on stderr
+    eprintln!(
+        "{} Entries in {}:",
+        "::".color("blue"),
+        source.to_string_lossy().bold()
+    );
+    for (i, name) in names.iter().enumerate() {
+        let suffix = if source.join(name).is_dir() { "/" } else { "" };
+        eprintln!("{:>4}) {}{}", i + 1, name.to_string_lossy(), suffix);
+    }
+    eprint!("Entries to sync (for instance: 1 3-5, or 'all'): ");
+    let _ = io::stderr().flush();
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| format!("Could not read selection: {}", e))?;
+    let indexes = parse_selection(&answer, names.len())?;
+    Ok(indexes.into_iter().map(|i| names[i].clone()).collect())
+}
+
+// Parse something like "1, 3-5" into a list of 0-based indexes
+fn parse_selection(answer: &str, count: usize) -> Result<Vec<usize>, String> {
+    let answer = answer.trim();
+    if answer == "all" {
+        return Ok((0..count).collect());
+    }
+    let mut res = vec![];
+    for word in answer.split(|c: char| c == ',' || c.is_whitespace()) {
+        if word.is_empty() {
+            continue;
+        }
+        let (start, end) = match word.find('-') {
+            Some(pos) => (&word[..pos], &word[pos + 1..]),
+            None => (word, word),
+        };
+        let invalid = || format!("Invalid selection: {}", word);
+        let parse = |x: &str| {
+            x.parse::<usize>()
+                .ok()
+                .filter(|n| *n >= 1 && *n <= count)
+                .ok_or_else(invalid)
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            return Err(invalid());
+        }
+        for i in start..=end {
+            if !res.contains(&(i - 1)) {
+                res.push(i - 1);
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(172_800)));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("3w").is_err());
+        // Too long, instead of overflowing
+        assert!(parse_duration("99999999999999999d").is_err());
+        assert!(parse_duration("18446744073709551615s1").is_err());
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(parse_selection("all", 3), Ok(vec![0, 1, 2]));
+        assert_eq!(parse_selection("2", 3), Ok(vec![1]));
+        assert_eq!(parse_selection("1, 3-5\n", 5), Ok(vec![0, 2, 3, 4]));
+        // Duplicates are only picked once, in the order given
+        assert_eq!(parse_selection("3 1-3", 3), Ok(vec![2, 0, 1]));
+        assert_eq!(parse_selection("", 3), Ok(vec![]));
+        assert_eq!(parse_selection(" \n", 3), Ok(vec![]));
+
+        assert!(parse_selection("0", 3).is_err());
+        assert!(parse_selection("4", 3).is_err());
+        assert!(parse_selection("2-4", 3).is_err());
+        assert!(parse_selection("3-2", 3).is_err());
+        assert!(parse_selection("one", 3).is_err());
+        assert!(parse_selection("1-", 3).is_err());
+    }
+}
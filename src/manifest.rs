@@ -0,0 +1,563 @@
+//! manifest
+//!
+//! Describe a tree (paths, sizes, modification times and optionally hashes),
+//! so that a dry run can compare the source against a destination that is
+//! not currently available.
+//!
+//! A manifest is a text file starting with a `rusync-manifest 2` header,
+//! followed by one tab-separated line per entry:
+//!
+//! ```text
+//! file <size> <mtime, in nanoseconds> <sha256, or -> <path>
+//! link <target> <path>
+//! dir <path>
+//! ```
+//!
+//! Paths and link targets are escaped as described in the escape module.
+//! Manifests with a `rusync-manifest 1` header, where they are not, can
+//! still be read.
+//!
+//! Manifests can also be written as a more compact binary index (see
+//! `Manifest::to_index()`), which can be read wherever a manifest can.
+//! Two manifests (or trees) can be compared with `diff()`.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::entry::Entry;
+use crate::error::Error;
+use crate::escape;
+use crate::fsops;
+use crate::hash;
+use crate::progress::PlannedChange;
+
+const HEADER: &str = "rusync-manifest 2";
+// Same lines, but not escaped
+const OLD_HEADER: &str = "rusync-manifest 1";
+const INDEX_MAGIC: &[u8] = b"rusync-index\0";
+const INDEX_VERSION: u64 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManifestEntry {
+    File {
+        size: u64,
+        /// Modification time, in nanoseconds since the epoch
+        mtime: u64,
+        digest: Option<String>,
+    },
+    Link {
+        target: PathBuf,
+    },
+    Dir,
+}
+
+impl ManifestEntry {
+    pub(crate) fn kind(&self) -> fsops::Kind {
+        match self {
+            ManifestEntry::File { .. } => fsops::Kind::File,
+            ManifestEntry::Link { .. } => fsops::Kind::Symlink,
+            ManifestEntry::Dir => fsops::Kind::Dir,
+        }
+    }
+
+    /// Describe what is at `path`, without following symlinks.
+    /// Returns None if there is nothing there.
+    pub fn from_path(path: &Path, with_digest: bool) -> Result<Option<ManifestEntry>, Error> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(None),
+        };
+        let file_type = metadata.file_type();
+        let entry = if file_type.is_symlink() {
+            let target = fs::read_link(path).map_err(|e| {
+                Error::from_io(&format!("Could not read link {:?}: {}", path, e), &e)
+            })?;
+            ManifestEntry::Link { target }
+        } else if file_type.is_dir() {
+            ManifestEntry::Dir
+        } else {
+            let digest = if with_digest {
+                Some(fsops::file_digest(&Entry::new(
+                    &path.to_string_lossy(),
+                    path,
+                ))?)
+            } else {
+                None
+            };
+            ManifestEntry::File {
+                size: metadata.len(),
+                mtime: fsops::precise_mtime(&metadata),
+                digest,
+            }
+        };
+        Ok(Some(entry))
+    }
+}
+
+/// Entries of a tree, indexed by their path relative to its root
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Walk the tree at `root`. If `with_digests` is true, the contents
+    /// of every file is read to compute its hash.
+    pub fn from_tree(root: &Path, with_digests: bool) -> Result<Manifest, Error> {
+        let mut manifest = Manifest::default();
+        let mut subdirs = vec![PathBuf::new()];
+        while let Some(rel_dir) = subdirs.pop() {
+            let dir = root.join(&rel_dir);
+            let entries = fs::read_dir(&dir).map_err(|e| {
+                Error::from_io(&format!("Could not read directory {:?}: {}", dir, e), &e)
+            })?;
+            for entry in entries {
+                let entry = entry.map_err(|e| {
+                    Error::from_io(&format!("Could not read entry in {:?}: {}", dir, e), &e)
+                })?;
+                let rel_path = rel_dir.join(entry.file_name());
+                let manifest_entry = ManifestEntry::from_path(&entry.path(), with_digests)?;
+                if let Some(manifest_entry) = manifest_entry {
+                    if manifest_entry == ManifestEntry::Dir {
+                        subdirs.push(rel_path.clone());
+                    }
+                    manifest.entries.insert(rel_path, manifest_entry);
+                }
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Read a manifest, in text or in binary form
+    pub fn read(path: &Path) -> Result<Manifest, Error> {
+        let contents = fs::read(path)
+            .map_err(|e| Error::from_io(&format!("Could not read {:?}: {}", path, e), &e))?;
+        let res = if contents.starts_with(INDEX_MAGIC) {
+            Manifest::from_index(&contents)
+        } else {
+            String::from_utf8(contents)
+                .map_err(|_| "not valid UTF-8".to_string())
+                .and_then(|text| Manifest::parse(&text))
+        };
+        res.map_err(|e| Error::new(&format!("Invalid manifest {:?}: {}", path, e)))
+    }
+
+    /// Describe `path`, which is either a directory or a manifest
+    pub fn load(path: &Path, with_digests: bool) -> Result<Manifest, Error> {
+        if path.is_dir() {
+            Manifest::from_tree(path, with_digests)
+        } else {
+            Manifest::read(path)
+        }
+    }
+
+    /// Binary form of the manifest: a magic string, a version, the number
+    /// of entries, then the entries. Numbers are LEB128 varints. Entries are
+    /// sorted, and each path is stored as the length of the prefix it shares
+    /// with the previous one, followed by the rest. Paths and link targets
+    /// are stored as the OS gives them, even if they are not valid UTF-8
+    pub fn to_index(&self) -> Vec<u8> {
+        let mut res = INDEX_MAGIC.to_vec();
+        write_varint(&mut res, INDEX_VERSION);
+        write_varint(&mut res, self.entries.len() as u64);
+        let mut previous: &[u8] = &[];
+        let paths: Vec<Vec<u8>> = self.entries.keys().map(|p| escape::path_bytes(p)).collect();
+        for (path, entry) in paths.iter().zip(self.entries.values()) {
+            let shared = previous
+                .iter()
+                .zip(path)
+                .take_while(|(a, b)| a == b)
+                .count();
+            match entry {
+                ManifestEntry::File { .. } => res.push(0),
+                ManifestEntry::Link { .. } => res.push(1),
+                ManifestEntry::Dir => res.push(2),
+            }
+            write_varint(&mut res, shared as u64);
+            write_bytes(&mut res, &path[shared..]);
+            match entry {
+                ManifestEntry::File {
+                    size,
+                    mtime,
+                    digest,
+                } => {
+                    write_varint(&mut res, *size);
+                    write_varint(&mut res, *mtime);
+                    // Digests we can't store in binary form are dropped
+                    match digest.as_deref().and_then(hash::from_hex) {
+                        Some(digest) => write_bytes(&mut res, &digest),
+                        None => write_varint(&mut res, 0),
+                    }
+                }
+                ManifestEntry::Link { target } => {
+                    write_bytes(&mut res, &escape::path_bytes(target))
+                }
+                ManifestEntry::Dir => (),
+            }
+            previous = path;
+        }
+        res
+    }
+
+    pub fn from_index(contents: &[u8]) -> Result<Manifest, String> {
+        let truncated = || "truncated index".to_string();
+        let mut input = contents
+            .strip_prefix(INDEX_MAGIC)
+            .ok_or_else(|| "not a rusync index".to_string())?;
+        let version = read_varint(&mut input).ok_or_else(truncated)?;
+        if version != INDEX_VERSION {
+            return Err(format!("unsupported index version: {}", version));
+        }
+        let count = read_varint(&mut input).ok_or_else(truncated)?;
+        let mut manifest = Manifest::default();
+        let mut previous: Vec<u8> = vec![];
+        for _ in 0..count {
+            let (kind, rest) = input.split_first().ok_or_else(truncated)?;
+            input = rest;
+            let shared = read_varint(&mut input).ok_or_else(truncated)? as usize;
+            if shared > previous.len() {
+                return Err("invalid path".to_string());
+            }
+            let mut path = previous[..shared].to_vec();
+            path.extend_from_slice(read_bytes(&mut input).ok_or_else(truncated)?);
+            let entry = match kind {
+                0 => {
+                    let size = read_varint(&mut input).ok_or_else(truncated)?;
+                    let mtime = read_varint(&mut input).ok_or_else(truncated)?;
+                    let digest = read_bytes(&mut input).ok_or_else(truncated)?;
+                    ManifestEntry::File {
+                        size,
+                        mtime,
+                        digest: if digest.is_empty() {
+                            None
+                        } else {
+                            Some(hash::to_hex(digest))
+                        },
+                    }
+                }
+                1 => {
+                    let target = read_bytes(&mut input).ok_or_else(truncated)?;
+                    ManifestEntry::Link {
+                        target: escape::path_from_bytes(target.to_vec()),
+                    }
+                }
+                2 => ManifestEntry::Dir,
+                kind => return Err(format!("unknown entry kind: {}", kind)),
+            };
+            manifest
+                .entries
+                .insert(escape::path_from_bytes(path.clone()), entry);
+            previous = path;
+        }
+        if !input.is_empty() {
+            return Err("unexpected data after the last entry".to_string());
+        }
+        Ok(manifest)
+    }
+
+    pub fn parse(contents: &str) -> Result<Manifest, String> {
+        let mut lines = contents.lines();
+        let unescape: fn(&str) -> Result<PathBuf, String> = match lines.next() {
+            Some(HEADER) => escape::unescape_path,
+            Some(OLD_HEADER) => |path: &str| Ok(PathBuf::from(path)),
+            _ => return Err(format!("expected '{}' on the first line", HEADER)),
+        };
+        let mut manifest = Manifest::default();
+        for (i, line) in lines.enumerate() {
+            let invalid = || format!("invalid line {}: {:?}", i + 2, line);
+            let fields: Vec<&str> = line.split('\t').collect();
+            let (path, entry) = match fields.as_slice() {
+                ["file", size, mtime, digest, path] => {
+                    let digest = match *digest {
+                        "-" => None,
+                        digest => Some(digest.to_string()),
+                    };
+                    let entry = ManifestEntry::File {
+                        size: size.parse().map_err(|_| invalid())?,
+                        mtime: mtime.parse().map_err(|_| invalid())?,
+                        digest,
+                    };
+                    (path, entry)
+                }
+                ["link", target, path] => (
+                    path,
+                    ManifestEntry::Link {
+                        target: unescape(target).map_err(|_| invalid())?,
+                    },
+                ),
+                ["dir", path] => (path, ManifestEntry::Dir),
+                _ => return Err(invalid()),
+            };
+            let path = unescape(path).map_err(|_| invalid())?;
+            manifest.entries.insert(path, entry);
+        }
+        Ok(manifest)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut res = format!("{}\n", HEADER);
+        for (path, entry) in &self.entries {
+            let path = escape::escape_path(path);
+            let line = match entry {
+                ManifestEntry::File {
+                    size,
+                    mtime,
+                    digest,
+                } => format!(
+                    "file\t{}\t{}\t{}\t{}\n",
+                    size,
+                    mtime,
+                    digest.as_deref().unwrap_or("-"),
+                    path
+                ),
+                ManifestEntry::Link { target } => {
+                    format!("link\t{}\t{}\n", escape::escape_path(target), path)
+                }
+                ManifestEntry::Dir => format!("dir\t{}\n", path),
+            };
+            res.push_str(&line);
+        }
+        res
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = input.split_first()?;
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_varint(input)? as usize;
+    if len > input.len() {
+        return None;
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Some(bytes)
+}
+
+/// What syncing `src` to `dest` would change, sorted by path. Files are
+/// compared by contents if both sides have digests, by size and
+/// modification time otherwise, like the default policy
+pub fn diff(src: &Manifest, dest: &Manifest) -> Vec<(PlannedChange, PathBuf)> {
+    let mut changes = vec![];
+    for (path, src_entry) in &src.entries {
+        let dest_entry = dest.entries.get(path);
+        let change = match (src_entry, dest_entry) {
+            (src_entry, Some(dest_entry)) if src_entry.kind() != dest_entry.kind() => {
+                changes.push((PlannedChange::Remove, path.clone()));
+                create(src_entry)
+            }
+            (src_entry, None) => create(src_entry),
+            (
+                ManifestEntry::File {
+                    size,
+                    mtime,
+                    digest,
+                },
+                Some(ManifestEntry::File {
+                    size: dest_size,
+                    mtime: dest_mtime,
+                    digest: dest_digest,
+                }),
+            ) => {
+                let differs = match (digest, dest_digest) {
+                    (Some(digest), Some(dest_digest)) => digest != dest_digest,
+                    _ => size != dest_size || mtime > dest_mtime,
+                };
+                if !differs {
+                    continue;
+                }
+                PlannedChange::UpdateFile
+            }
+            (
+                ManifestEntry::Link { target },
+                Some(ManifestEntry::Link {
+                    target: dest_target,
+                }),
+            ) if target != dest_target => PlannedChange::UpdateLink,
+            _ => continue,
+        };
+        changes.push((change, path.clone()));
+    }
+    for path in dest.entries.keys() {
+        if !src.entries.contains_key(path) {
+            changes.push((PlannedChange::Remove, path.clone()));
+        }
+    }
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    changes
+}
+
+fn create(entry: &ManifestEntry) -> PlannedChange {
+    match entry {
+        ManifestEntry::File { .. } => PlannedChange::CopyFile,
+        ManifestEntry::Link { .. } => PlannedChange::CreateLink,
+        ManifestEntry::Dir => PlannedChange::CreateDir,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn round_trip() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-manifest")?;
+        let root = tmp_dir.path();
+        fs::create_dir(root.join("sub"))?;
+        fs::write(root.join("sub/one.txt"), "one")?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("sub/one.txt", root.join("link"))?;
+
+        let manifest = Manifest::from_tree(root, true).unwrap();
+        assert_eq!(manifest.entries[Path::new("sub")], ManifestEntry::Dir);
+        match &manifest.entries[Path::new("sub/one.txt")] {
+            ManifestEntry::File { size, digest, .. } => {
+                assert_eq!(*size, 3);
+                assert!(digest.is_some());
+            }
+            other => panic!("unexpected entry: {:?}", other),
+        }
+
+        let parsed = Manifest::parse(&manifest.to_text()).unwrap();
+        assert_eq!(parsed.entries, manifest.entries);
+        Ok(())
+    }
+
+    #[test]
+    fn index_round_trip() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-manifest")?;
+        let root = tmp_dir.path();
+        fs::create_dir_all(root.join("sub/deeper"))?;
+        fs::write(root.join("sub/one.txt"), "one")?;
+        fs::write(root.join("sub/deeper/two.txt"), "two")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            std::os::unix::fs::symlink("sub/one.txt", root.join("link"))?;
+            // Not valid UTF-8
+            let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+            fs::write(root.join("sub").join(name), "three")?;
+        }
+
+        for with_digests in &[false, true] {
+            let manifest = Manifest::from_tree(root, *with_digests).unwrap();
+            let index = manifest.to_index();
+            assert!(index.len() < manifest.to_text().len());
+            let parsed = Manifest::from_index(&index).unwrap();
+            assert_eq!(parsed.entries, manifest.entries);
+
+            let index_path = tmp_dir.path().join("index.bin");
+            fs::write(&index_path, &index)?;
+            assert_eq!(
+                Manifest::read(&index_path).unwrap().entries,
+                manifest.entries
+            );
+            fs::remove_file(&index_path)?;
+        }
+        assert!(Manifest::from_index(b"rusync-index\0\x01\x05").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn diff_manifests() {
+        let src = Manifest::parse(
+            "rusync-manifest 2\n\
+             dir\ta\n\
+             file\t3\t20\t-\ta/new.txt\n\
+             file\t3\t20\t-\ta/same.txt\n\
+             file\t3\t30\t-\ta/newer.txt\n\
+             link\tother\tlink\n",
+        )
+        .unwrap();
+        let dest = Manifest::parse(
+            "rusync-manifest 2\n\
+             dir\ta\n\
+             file\t3\t20\t-\ta/same.txt\n\
+             file\t3\t20\t-\ta/newer.txt\n\
+             dir\tlink\n\
+             file\t3\t20\t-\told.txt\n",
+        )
+        .unwrap();
+        let changes: Vec<(PlannedChange, String)> = diff(&src, &dest)
+            .into_iter()
+            .map(|(change, path)| (change, path.to_string_lossy().to_string()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (PlannedChange::CopyFile, "a/new.txt".to_string()),
+                (PlannedChange::UpdateFile, "a/newer.txt".to_string()),
+                (PlannedChange::Remove, "link".to_string()),
+                (PlannedChange::CreateLink, "link".to_string()),
+                (PlannedChange::Remove, "old.txt".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_manifests() {
+        assert!(Manifest::parse("not a manifest\n").is_err());
+        assert!(Manifest::parse("rusync-manifest 2\nfile\tbig\t0\t-\tfoo\n").is_err());
+        assert!(Manifest::parse("rusync-manifest 2\nsocket\tfoo\n").is_err());
+        assert!(Manifest::parse("rusync-manifest 2\ndir\tbad\\q\n").is_err());
+    }
+
+    #[test]
+    fn escape_paths() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-manifest")?;
+        let root = tmp_dir.path();
+        fs::write(root.join("tab\there"), "one")?;
+        fs::write(root.join("new\nline"), "two")?;
+        fs::write(root.join("back\\slash"), "three")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            // Not valid UTF-8
+            let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+            fs::write(root.join(name), "four")?;
+            std::os::unix::fs::symlink("tab\there", root.join("link\tto"))?;
+        }
+
+        let manifest = Manifest::from_tree(root, false).unwrap();
+        let text = manifest.to_text();
+        assert_eq!(text.lines().count(), manifest.entries.len() + 1);
+        let parsed = Manifest::parse(&text).unwrap();
+        assert_eq!(parsed.entries, manifest.entries);
+
+        // Old manifests are read as they are
+        let old = Manifest::parse("rusync-manifest 1\ndir\tback\\slash\n").unwrap();
+        assert!(old.entries.contains_key(Path::new("back\\slash")));
+        Ok(())
+    }
+}
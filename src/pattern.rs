@@ -0,0 +1,152 @@
+//! pattern
+//!
+//! Glob patterns matched against paths relative to the source.
+//!
+//! * `*` matches any sequence of characters except `/`
+//! * `**` matches any sequence of characters, including `/`
+//! * `?` matches any single character except `/`
+//!
+//! Patterns without a `/` are matched against each component of the path,
+//! so `*.iso` matches `foo.iso` as well as `isos/foo.iso`. Other patterns
+//! are matched against the start of the path, so `photos/2018` matches
+//! `photos/2018` and everything it contains.
+//! A trailing `/` restricts the pattern to directories (and their contents).
+
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    glob: String,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        let dir_only = pattern.ends_with('/');
+        let glob = pattern.trim_end_matches('/');
+        let anchored = glob.contains('/');
+        let glob = glob.trim_start_matches('/');
+        Pattern {
+            glob: glob.to_string(),
+            anchored,
+            dir_only,
+        }
+    }
+
+    /// Returns true if `rel_path`, or one of its parent directories,
+    /// matches the pattern
+    pub fn matches(&self, rel_path: &Path) -> bool {
+        let components: Vec<String> = rel_path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+        // Directory-only patterns cannot match the last component, because
+        // we do not know whether it is a directory
+        let candidates = if self.dir_only {
+            components.len().saturating_sub(1)
+        } else {
+            components.len()
+        };
+        if self.anchored {
+            (1..=candidates).any(|n| glob_match(&self.glob, &components[..n].join("/")))
+        } else {
+            components[..candidates]
+                .iter()
+                .any(|c| glob_match(&self.glob, c))
+        }
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let prefix = if self.anchored && !self.glob.contains('/') {
+            "/"
+        } else {
+            ""
+        };
+        let suffix = if self.dir_only { "/" } else { "" };
+        write!(f, "{}{}{}", prefix, self.glob, suffix)
+    }
+}
+
+fn glob_match(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&glob, &text)
+}
+
+fn match_from(glob: &[char], text: &[char]) -> bool {
+    match glob.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            let double = glob.get(1) == Some(&'*');
+            let rest = if double { &glob[2..] } else { &glob[1..] };
+            // Try to match the rest of the glob at every possible position
+            for i in 0..=text.len() {
+                if match_from(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == '/' && !double {
+                    return false;
+                }
+            }
+            false
+        }
+        Some('?') => match text.first() {
+            Some(c) if *c != '/' => match_from(&glob[1..], &text[1..]),
+            _ => false,
+        },
+        Some(g) => match text.first() {
+            Some(c) if c == g => match_from(&glob[1..], &text[1..]),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Pattern::new(pattern).matches(Path::new(path))
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.iso", "foo.iso"));
+        assert!(!glob_match("*.iso", "foo.iso.part"));
+        assert!(glob_match("f?o", "foo"));
+        assert!(!glob_match("*", "a/b"));
+        assert!(glob_match("**", "a/b"));
+        assert!(glob_match("a/**/z", "a/b/c/z"));
+        assert!(glob_match("*cache*", "my_cache_dir"));
+    }
+
+    #[test]
+    fn test_unanchored_patterns() {
+        assert!(matches("*.iso", "foo.iso"));
+        assert!(matches("*.iso", "isos/foo.iso"));
+        assert!(!matches("*.iso", "foo.txt"));
+        // Every component is tried, so contents of directories match too
+        assert!(matches("cache", "home/cache/file.txt"));
+    }
+
+    #[test]
+    fn test_anchored_patterns() {
+        assert!(matches("photos/2018", "photos/2018"));
+        assert!(matches("photos/2018", "photos/2018/01/img.jpg"));
+        assert!(!matches("photos/2018", "old/photos/2018"));
+        assert!(matches("/top.txt", "top.txt"));
+        assert!(!matches("/top.txt", "a_dir/top.txt"));
+        assert!(matches("*/cache/*", "home/cache/file.txt"));
+    }
+
+    #[test]
+    fn test_dir_only_patterns() {
+        assert!(matches("latest/", "latest/file.txt"));
+        assert!(!matches("latest/", "latest"));
+        assert!(!matches("latest/", "a_dir/file.txt"));
+    }
+}
@@ -0,0 +1,134 @@
+//! probe
+//!
+//! Find out what the destination file system supports before syncing,
+//! by creating (and removing) a few entries in it.
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use filetime::FileTime;
+
+use crate::error::Error;
+use crate::fsops;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    /// False if `foo` and `FOO` are the same entry
+    pub case_sensitive: bool,
+    pub symlinks: bool,
+    /// Maximum length of a file name, in bytes, if known
+    pub max_name_len: Option<usize>,
+    /// How precisely modification times are stored: 1 ns when they
+    /// are kept as is, 1 s or 2 s (FAT) otherwise
+    pub mtime_granularity: Duration,
+}
+
+/// Probe `dir`, which must exist. None if nothing can be written to it:
+/// it can't be probed then, and whatever needs to change in it will fail
+pub fn probe(dir: &Path) -> Result<Option<Capabilities>, Error> {
+    let name = fsops::temp_name("probe");
+    let path = dir.join(&name);
+    if fs::write(&path, "").is_err() {
+        return Ok(None);
+    }
+    let res = probe_with(dir, &name);
+    let _ = fs::remove_file(&path);
+    res.map(Some)
+}
+
+fn probe_with(dir: &Path, name: &str) -> Result<Capabilities, Error> {
+    let path = dir.join(name);
+    let case_sensitive = !dir.join(name.to_uppercase()).exists();
+    let symlinks = probe_symlinks(dir, name);
+    let max_name_len = max_name_len(dir);
+
+    // Odd, for FAT, and before 2038, for 32-bit times
+    let expected = FileTime::from_seconds_since_1970(2_000_000_001, 123_456_789);
+    filetime::set_file_times(&path, expected, expected).map_err(|e| {
+        Error::from_io(
+            &format!("Could not set modification time of {:?}: {}", path, e),
+            &e,
+        )
+    })?;
+    let metadata = fs::metadata(&path).map_err(|e| {
+        Error::from_io(&format!("Could not read metadata of {:?}: {}", path, e), &e)
+    })?;
+    let actual = FileTime::from_last_modification_time(&metadata);
+    let mtime_granularity = if actual == expected {
+        Duration::from_nanos(1)
+    } else if actual.seconds_relative_to_1970() == expected.seconds_relative_to_1970() {
+        Duration::from_secs(1)
+    } else {
+        Duration::from_secs(2)
+    };
+
+    Ok(Capabilities {
+        case_sensitive,
+        symlinks,
+        max_name_len,
+        mtime_granularity,
+    })
+}
+
+#[cfg(unix)]
+fn probe_symlinks(dir: &Path, name: &str) -> bool {
+    let link_path = dir.join(format!("{}-link", name));
+    let res = std::os::unix::fs::symlink(name, &link_path).is_ok();
+    let _ = fs::remove_file(&link_path);
+    res
+}
+
+#[cfg(windows)]
+fn probe_symlinks(_dir: &Path, _name: &str) -> bool {
+    // Depends on privileges rather than on the file system
+    true
+}
+
+/// Maximum length of a file name in `dir`, if known. Unlike `probe()`,
+/// nothing is written to `dir`
+#[cfg(unix)]
+pub fn max_name_len(dir: &Path) -> Option<usize> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let res = unsafe { libc::pathconf(dir.as_ptr(), libc::_PC_NAME_MAX) };
+    if res > 0 {
+        Some(res as usize)
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+pub fn max_name_len(_dir: &Path) -> Option<usize> {
+    // NTFS (and FAT with long names) allow 255 UTF-16 code units
+    Some(255)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn probe_tmp_dir() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-probe")?;
+        let capabilities = probe(tmp_dir.path()).unwrap().unwrap();
+
+        // Whatever /tmp is, it's a Linux file system
+        assert!(capabilities.case_sensitive);
+        assert!(capabilities.symlinks);
+        assert_eq!(capabilities.max_name_len, Some(255));
+        assert!(capabilities.mtime_granularity < Duration::from_secs(1));
+        // Nothing is left behind
+        assert_eq!(fs::read_dir(tmp_dir.path())?.count(), 0);
+
+        // Can't be written to
+        assert_eq!(probe(&tmp_dir.path().join("missing")).unwrap(), None);
+        Ok(())
+    }
+}
@@ -1,12 +1,79 @@
-use crate::fsops::SyncOutcome;
-use crate::sync::Stats;
+use crate::fsops::{Removal, SyncOutcome};
+use crate::sync::{PhaseTimings, Stats};
+
+/// A change that a dry run found would be made to the destination
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PlannedChange {
+    CopyFile,
+    /// An existing file would be overwritten (audits tell why instead,
+    /// see `ReplaceContents` and `ReplaceMetadata`)
+    UpdateFile,
+    /// An existing file would be overwritten with different contents (audits only)
+    ReplaceContents,
+    /// An existing file would be overwritten, but contents are the
+    /// same: only its metadata differs (audits only)
+    ReplaceMetadata,
+    /// Permissions or ownership of an up-to-date file would be fixed (audits only)
+    UpdateMetadata,
+    CreateDir,
+    CreateLink,
+    UpdateLink,
+    /// A directory renamed in the source would be renamed instead of copied again
+    RenameDir,
+    Remove,
+}
+
+impl PlannedChange {
+    /// Short name, as printed by dry runs
+    pub fn name(self) -> &'static str {
+        match self {
+            PlannedChange::CopyFile => "copy",
+            PlannedChange::UpdateFile => "update",
+            PlannedChange::ReplaceContents => "content",
+            PlannedChange::ReplaceMetadata => "mtime",
+            PlannedChange::UpdateMetadata => "meta",
+            PlannedChange::CreateDir => "mkdir",
+            PlannedChange::CreateLink => "link",
+            PlannedChange::UpdateLink => "relink",
+            PlannedChange::RenameDir => "rename",
+            PlannedChange::Remove => "delete",
+        }
+    }
+}
 
 #[doc(hidden)]
 pub enum ProgressMessage {
     DoneSyncing(SyncOutcome),
+    Planned(PlannedChange, String),
+    DirCreated(String),
+    /// A directory of the source is in the destination
+    DirSynced(String),
+    DirRenamed {
+        from: String,
+        to: String,
+    },
+    Removed(Removal),
+    Timings(PhaseTimings),
+    Warning(String),
+    ErrorSkipped(String),
+    ErrorIgnored(String),
+    /// A file changed on both sides, with how it was resolved
+    Conflict(String),
+    /// SHA-256 of a source file, computed while it was read
+    Hashed {
+        description: String,
+        digest: String,
+    },
+    Stopped(String),
+    Verified(String),
+    Transferred {
+        description: String,
+        size: u64,
+    },
     StartSync(String),
     Todo {
         num_files: u64,
+        num_dirs: u64,
         total_size: usize,
     },
     Syncing {
@@ -14,8 +81,19 @@ pub enum ProgressMessage {
         size: usize,
         done: usize,
     },
+    Deleting {
+        index: usize,
+        total: usize,
+        description: String,
+    },
+    FixingMetadata {
+        index: usize,
+        total: usize,
+        description: String,
+    },
 }
 
+#[derive(Clone, Debug)]
 pub struct Progress {
     /// Name of the file being transferred
     pub current_file: String,
@@ -31,8 +109,16 @@ pub struct Progress {
     pub index: usize,
     /// Total number of files to transfer
     pub num_files: usize,
+    /// Number of files, symlinks and directories synced so far, whether
+    /// something had to be done or not
+    pub entries_done: usize,
+    /// Total number of files, symlinks and directories to sync (this
+    /// may change during transfer)
+    pub num_entries: usize,
     /// Estimated time remaining for the transfer, in seconds
     pub eta: usize,
+    /// Label of the sync, see `SyncOptions::label`
+    pub label: Option<String>,
 }
 
 /// Trait for implementing rusync progress details
@@ -50,10 +136,33 @@ pub trait ProgressInfo {
     #[allow(unused_variables)]
     fn done_syncing(&self) {}
 
+    /// Something went wrong, but the transfer can go on
+    #[allow(unused_variables)]
+    fn warning(&self, message: &str) {}
+
+    /// During a dry run, `description` would be changed by a real sync
+    #[allow(unused_variables)]
+    fn planned(&self, change: PlannedChange, description: &str) {}
+
+    /// `description` was read while syncing, and its SHA-256 is `digest`
+    /// (see `SyncOptions::hash_files`)
+    #[allow(unused_variables)]
+    fn hashed(&self, digest: &str, description: &str) {}
+
     /// Callback for the detailed progress
     #[allow(unused_variables)]
     fn progress(&self, progress: &Progress) {}
 
+    /// Once everything is copied, `description` is the `index`-th of the
+    /// `total` extraneous entries being removed from the destination
+    #[allow(unused_variables)]
+    fn deleting(&self, index: usize, total: usize, description: &str) {}
+
+    /// Once everything is copied, times or ownership of `description`, the
+    /// `index`-th of the `total` directories, are being fixed
+    #[allow(unused_variables)]
+    fn fixing_metadata(&self, index: usize, total: usize, description: &str) {}
+
     /// The transfer between `source` and `destination` is done. Details
     /// of the transfer in the Stats struct
     #[allow(unused_variables)]
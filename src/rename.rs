@@ -0,0 +1,84 @@
+//! rename
+//!
+//! With `SyncOptions::delete`, find directories of the destination that
+//! were renamed in the source, so that they can be renamed instead of
+//! copied again and deleted. Used by both actual syncs and dry runs.
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::error::Error;
+use crate::fsops;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::sync::SyncOptions;
+
+/// Actual directories in `root.join(parent)`, relative to `root`
+pub fn subdirs(root: &Path, parent: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(root.join(parent)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .map(|e| parent.join(e.file_name()))
+        .collect()
+}
+
+/// `src_dir` is missing in the destination, at `rel_path`. If it was renamed
+/// in the source, its old name is still in the destination, about to be
+/// deleted: return it. Only siblings are looked at, given in `dest_dirs`;
+/// `dest_tree` reads the contents of one of them
+pub fn find_old_name<F>(
+    source: &Path,
+    src_dir: &Path,
+    dest_dirs: Vec<PathBuf>,
+    dest_tree: F,
+    opts: &SyncOptions,
+) -> Result<Option<PathBuf>, Error>
+where
+    F: Fn(&Path) -> Result<Manifest, Error>,
+{
+    let candidates: Vec<PathBuf> = dest_dirs
+        .into_iter()
+        .filter(|p| fs::symlink_metadata(source.join(p)).is_err() && opts.is_selected(p))
+        .collect();
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+    let src_tree = Manifest::from_tree(src_dir, false)?;
+    if src_tree.entries.is_empty() {
+        return Ok(None);
+    }
+    for candidate in candidates {
+        // Unreadable candidates are left to be deleted
+        match dest_tree(&candidate) {
+            Ok(ref dest_tree) if same_tree(&src_tree, dest_tree, opts) => {
+                return Ok(Some(candidate))
+            }
+            _ => continue,
+        }
+    }
+    Ok(None)
+}
+
+// True if syncing `src` to `dest` would not change anything
+fn same_tree(src: &Manifest, dest: &Manifest, opts: &SyncOptions) -> bool {
+    if src.entries.len() != dest.entries.len() {
+        return false;
+    }
+    src.entries.iter().all(
+        |(path, src_entry)| match (src_entry, dest.entries.get(path)) {
+            (
+                ManifestEntry::File { size, mtime, .. },
+                Some(ManifestEntry::File {
+                    size: dest_size,
+                    mtime: dest_mtime,
+                    ..
+                }),
+            ) => size == dest_size && !fsops::is_newer(*mtime, *dest_mtime, opts),
+            (src_entry, Some(dest_entry)) => src_entry == dest_entry,
+            (_, None) => false,
+        },
+    )
+}
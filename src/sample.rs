@@ -0,0 +1,57 @@
+//! sample
+//!
+//! Pick a random percentage of items, without keeping track of them
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Sampler {
+    percent: f64,
+    state: u64,
+}
+
+impl Sampler {
+    pub fn new(percent: f64) -> Sampler {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Sampler::with_seed(percent, seed)
+    }
+
+    fn with_seed(percent: f64, seed: u64) -> Sampler {
+        Sampler {
+            percent,
+            // xorshift gets stuck on 0
+            state: seed | 1,
+        }
+    }
+
+    /// True for about `percent` % of the calls
+    pub fn pick(&mut self) -> bool {
+        // xorshift64*, good enough to spread the picks
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let value = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        let ratio = (value >> 11) as f64 / (1_u64 << 53) as f64;
+        ratio * 100.0 < self.percent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        let count = |percent| {
+            let mut sampler = Sampler::with_seed(percent, 42);
+            (0..10_000).filter(|_| sampler.pick()).count()
+        };
+        assert_eq!(count(0.0), 0);
+        assert_eq!(count(100.0), 10_000);
+        let ten_percent = count(10.0);
+        assert!(ten_percent > 800 && ten_percent < 1200, "{}", ten_percent);
+    }
+}
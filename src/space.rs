@@ -0,0 +1,146 @@
+//! space
+//!
+//! Check that the destination has enough free space and inodes
+//! before starting to sync.
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::error::Error;
+use crate::sync::SyncOptions;
+
+/// What syncing needs in the destination
+#[derive(Debug, Default, PartialEq)]
+struct Needed {
+    bytes: u64,
+    inodes: u64,
+}
+
+/// Fail if the destination file system does not have enough free bytes or
+/// inodes for what is missing or bigger in the source. Does nothing if the
+/// file system does not tell
+pub fn check(source: &Path, destination: &Path, opts: &SyncOptions) -> Result<(), Error> {
+    let needed = needed(source, destination, opts)?;
+    // The destination may not have been created yet
+    let existing = destination
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(destination);
+    let (free_bytes, free_inodes) = match available(existing) {
+        Some(available) => available,
+        None => return Ok(()),
+    };
+    if needed.bytes > free_bytes {
+        return Err(Error::new(&format!(
+            "Not enough space in {:?}: {} bytes needed, {} available",
+            destination, needed.bytes, free_bytes
+        )));
+    }
+    if let Some(free_inodes) = free_inodes {
+        if needed.inodes > free_inodes {
+            return Err(Error::new(&format!(
+                "Not enough inodes in {:?}: {} files, symlinks and directories to create, room for {}",
+                destination, needed.inodes, free_inodes
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn needed(source: &Path, destination: &Path, opts: &SyncOptions) -> Result<Needed, Error> {
+    let mut needed = Needed::default();
+    let mut subdirs = vec![PathBuf::new()];
+    while let Some(rel_dir) = subdirs.pop() {
+        let dir = source.join(&rel_dir);
+        let entries = fs::read_dir(&dir).map_err(|e| {
+            Error::from_io(&format!("Could not read directory {:?}: {}", dir, e), &e)
+        })?;
+        for entry in entries {
+            let entry = entry.map_err(|e| {
+                Error::from_io(&format!("Could not read entry in {:?}: {}", dir, e), &e)
+            })?;
+            let rel_path = rel_dir.join(entry.file_name());
+            let metadata = fs::symlink_metadata(entry.path()).map_err(|e| {
+                Error::from_io(
+                    &format!("Could not read metadata of {:?}: {}", entry.path(), e),
+                    &e,
+                )
+            })?;
+            if metadata.is_dir() && opts.should_walk(&rel_path) {
+                subdirs.push(rel_path.clone());
+            }
+            if !opts.is_selected(&rel_path) {
+                continue;
+            }
+            let dest_size = match fs::symlink_metadata(destination.join(&rel_path)) {
+                Ok(dest_metadata) => Some(dest_metadata.len()),
+                Err(_) => None,
+            };
+            if dest_size.is_none() {
+                needed.inodes += 1;
+            }
+            if metadata.is_file() {
+                needed.bytes += metadata.len().saturating_sub(dest_size.unwrap_or(0));
+            }
+        }
+    }
+    Ok(needed)
+}
+
+/// Free bytes and inodes (if the file system has a fixed number of them)
+#[cfg(unix)]
+fn available(path: &Path) -> Option<(u64, Option<u64>)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    let free_bytes = stats.f_bavail as u64 * stats.f_frsize as u64;
+    // Some file systems (like btrfs) allocate inodes dynamically and report 0
+    let free_inodes = if stats.f_files > 0 {
+        Some(stats.f_favail as u64)
+    } else {
+        None
+    };
+    Some((free_bytes, free_inodes))
+}
+
+#[cfg(windows)]
+fn available(_path: &Path) -> Option<(u64, Option<u64>)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn only_count_what_is_missing() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new("test-rusync-space")?;
+        let src = tmp_dir.path().join("src");
+        let dest = tmp_dir.path().join("dest");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub/new.txt"), "new")?;
+        fs::write(src.join("grown.txt"), "0123456789")?;
+        fs::create_dir_all(&dest)?;
+        fs::write(dest.join("grown.txt"), "0123")?;
+
+        let needed = needed(&src, &dest, &SyncOptions::new()).unwrap();
+        // sub and sub/new.txt
+        assert_eq!(
+            needed,
+            Needed {
+                bytes: 3 + 6,
+                inodes: 2
+            }
+        );
+        assert!(check(&src, &dest, &SyncOptions::new()).is_ok());
+        Ok(())
+    }
+}
@@ -1,16 +1,26 @@
 extern crate colored;
 
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime};
 
+use crate::access;
+use crate::access::{AccessProblem, ChangeRecorder};
 use crate::entry::Entry;
 use crate::error::Error;
 use crate::fsops;
 use crate::fsops::SyncOutcome::*;
+use crate::pattern::Pattern;
 use crate::progress::{ProgressInfo, ProgressMessage};
+use crate::space;
+use crate::workers::PlanWorker;
 use crate::workers::ProgressWorker;
+use crate::workers::StoreWorker;
 use crate::workers::SyncWorker;
 use crate::workers::WalkWorker;
 
@@ -18,6 +28,8 @@ use crate::workers::WalkWorker;
 pub struct Stats {
     /// Number of files in the source
     pub num_files: u64,
+    /// Number of directories in the source
+    pub num_dirs: u64,
     /// Sum of the sizes of all the files in the source
     pub total_size: usize,
 
@@ -33,12 +45,72 @@ pub struct Stats {
     pub symlink_created: u64,
     /// Number of symlinks updated in the destination folder
     pub symlink_updated: u64,
+    /// Number of symlinks that were not created because they were not safe
+    pub symlink_skipped: u64,
+    /// Number of symlinks left as is because they already had the right
+    /// target (these are also counted in `up_to_date`)
+    pub symlink_up_to_date: u64,
+
+    /// Number of directories created in the destination folder
+    pub dirs_created: u64,
+    /// Paths of the directories created, relative to the destination folder
+    pub created_dirs: Vec<String>,
+    /// Number of directories of the destination that were renamed, because
+    /// they were renamed in the source (see `SyncOptions::delete`)
+    pub dirs_renamed: u64,
+
+    /// Number of extraneous files removed from the destination folder
+    pub files_removed: u64,
+    /// Number of extraneous symlinks removed from the destination folder
+    pub symlinks_removed: u64,
+    /// Number of extraneous directories removed from the destination folder
+    pub dirs_removed: u64,
+    /// Paths of the directories removed, relative to the destination folder
+    pub removed_dirs: Vec<String>,
+    /// Paths of every file, symlink and directory removed, relative to
+    /// the destination folder, in the order they were removed
+    pub removed: Vec<String>,
+
+    /// Breakdown of the files copied
+    pub transfers: TransferStats,
+
+    /// Time spent in each phase of the sync
+    pub timings: PhaseTimings,
+
+    /// Number of copied files that were read again to check their contents
+    /// (see `SyncOptions::verify_sample`)
+    pub verified: u64,
+
+    /// Number of entries that could not be synced, and were skipped
+    /// because the `ErrorPolicy` said so
+    pub errors_skipped: u64,
+
+    /// Number of entries that could not be synced, and were skipped
+    /// because they match `SyncOptions::ignore_errors_from`
+    pub errors_ignored: u64,
+
+    /// Number of files changed in both the source and the destination
+    /// since the last sync (see `SyncOptions::on_conflict`)
+    pub conflicts: u64,
+
+    /// True if nothing was changed, and the other fields describe
+    /// what a real sync would have done
+    pub dry_run: bool,
+
+    /// Copy of `SyncOptions::label`
+    pub label: Option<String>,
+
+    /// Set when the sync stopped before the end of the source because a
+    /// limit (such as `SyncOptions::max_files`) was reached, with the reason.
+    /// Running the same sync again carries on where this one stopped
+    pub stopped: Option<String>,
 }
 
 impl Stats {
     pub fn new() -> Stats {
         Stats {
             num_files: 0,
+            num_dirs: 0,
             total_size: 0,
 
             num_synced: 0,
@@ -47,6 +119,33 @@ impl Stats {
 
             symlink_created: 0,
             symlink_updated: 0,
+            symlink_skipped: 0,
+            symlink_up_to_date: 0,
+
+            dirs_created: 0,
+            created_dirs: vec![],
+            dirs_renamed: 0,
+
+            files_removed: 0,
+            symlinks_removed: 0,
+            dirs_removed: 0,
+            removed_dirs: vec![],
+            removed: vec![],
+
+            transfers: TransferStats::default(),
+            timings: PhaseTimings::default(),
+
+            verified: 0,
+
+            errors_skipped: 0,
+            errors_ignored: 0,
+            conflicts: 0,
+
+            dry_run: false,
+
+            label: None,
+
+            stopped: None,
         }
     }
 
@@ -58,29 +157,592 @@ impl Stats {
             UpToDate => self.up_to_date += 1,
             SymlinkUpdated => self.symlink_updated += 1,
             SymlinkCreated => self.symlink_created += 1,
+            SymlinkSkipped => self.symlink_skipped += 1,
+            SymlinkUpToDate => {
+                self.up_to_date += 1;
+                self.symlink_up_to_date += 1;
+            }
+            Interrupted | DestinationKept => (),
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn add_dir_created(&mut self, description: &str) {
+        self.dirs_created += 1;
+        self.created_dirs.push(description.to_string());
+    }
+
+    #[doc(hidden)]
+    pub fn add_removal(&mut self, removal: &fsops::Removal) {
+        let description = match removal {
+            fsops::Removal::File(description) => {
+                self.files_removed += 1;
+                description
+            }
+            fsops::Removal::Symlink(description) => {
+                self.symlinks_removed += 1;
+                description
+            }
+            fsops::Removal::Dir(description) => {
+                self.dirs_removed += 1;
+                self.removed_dirs.push(description.clone());
+                description
+            }
+        };
+        self.removed.push(description.clone());
+    }
+}
+
+/// What a sync would do, as found by `Syncer::plan()`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Plan {
+    pub files_to_copy: u64,
+    /// Total size of the files to copy. Some of it may not be transferred,
+    /// depending on the policy (see `Policy::Append` for instance)
+    pub bytes_to_copy: u64,
+    /// Symlinks created or updated
+    pub symlinks_to_write: u64,
+    pub dirs_to_create: u64,
+    /// Directories renamed in the source, renamed instead of copied again
+    pub dirs_to_rename: u64,
+    pub files_to_delete: u64,
+    pub symlinks_to_delete: u64,
+    pub dirs_to_delete: u64,
+}
+
+impl Plan {
+    fn from_stats(stats: &Stats) -> Plan {
+        Plan {
+            files_to_copy: stats.copied,
+            bytes_to_copy: stats.transfers.total_bytes(),
+            symlinks_to_write: stats.symlink_created + stats.symlink_updated,
+            dirs_to_create: stats.dirs_created,
+            dirs_to_rename: stats.dirs_renamed,
+            files_to_delete: stats.files_removed,
+            symlinks_to_delete: stats.symlinks_removed,
+            dirs_to_delete: stats.dirs_removed,
+        }
+    }
+
+    pub fn entries_to_delete(&self) -> u64 {
+        self.files_to_delete + self.symlinks_to_delete + self.dirs_to_delete
+    }
+
+    /// True if syncing would not change anything
+    pub fn is_empty(&self) -> bool {
+        *self == Plan::default()
+    }
+}
+
+/// Number of files and their total size
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Tally {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl Tally {
+    fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.bytes += size;
+    }
+}
+
+/// Lower bounds of the buckets used in `TransferStats::by_size`
+pub const SIZE_BUCKETS: [u64; 5] = [0, 1 << 10, 1 << 20, 100 << 20, 1 << 30];
+
+const NUM_LARGEST: usize = 10;
+
+/// What the copied files were
+#[derive(Default)]
+pub struct TransferStats {
+    /// By extension (lowercase, without the dot). Files
+    /// without an extension are counted under ""
+    pub by_extension: BTreeMap<String, Tally>,
+    /// By size, indexed by the lower bound of each bucket (see `SIZE_BUCKETS`)
+    pub by_size: BTreeMap<u64, Tally>,
+    /// The 10 largest files copied, with their sizes, largest first
+    pub largest: Vec<(String, u64)>,
+}
+
+impl TransferStats {
+    /// Total size of the files copied
+    pub fn total_bytes(&self) -> u64 {
+        self.by_size.values().map(|t| t.bytes).sum()
+    }
+
+    #[doc(hidden)]
+    pub fn add(&mut self, description: &str, size: u64) {
+        let extension = Path::new(description)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        self.by_extension.entry(extension).or_default().add(size);
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .rev()
+            .find(|b| size >= **b)
+            .cloned()
+            .unwrap_or(0);
+        self.by_size.entry(bucket).or_default().add(size);
+
+        let index = self
+            .largest
+            .iter()
+            .position(|(_, s)| size > *s)
+            .unwrap_or(self.largest.len());
+        if index < NUM_LARGEST {
+            self.largest.insert(index, (description.to_string(), size));
+            self.largest.truncate(NUM_LARGEST);
+        }
+    }
+}
+
+/// Time spent in each phase of a sync. The source is walked while the
+/// entries found so far are synced (unless `SyncOptions::estimate_first`
+/// is set), so `scan` overlaps with `compare` and `copy`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PhaseTimings {
+    /// Walking the source
+    pub scan: Duration,
+    /// Syncing entries that did not need to be copied: files
+    /// found up to date, directories, skipped entries
+    pub compare: Duration,
+    /// Syncing files and symlinks that were copied or created
+    pub copy: Duration,
+    /// Removing extraneous entries from the destination
+    pub delete: Duration,
+    /// Fixing the times and ownership of directories
+    pub fix_metadata: Duration,
+}
+
+impl PhaseTimings {
+    #[doc(hidden)]
+    pub fn add(&mut self, other: &PhaseTimings) {
+        self.scan += other.scan;
+        self.compare += other.compare;
+        self.copy += other.copy;
+        self.delete += other.delete;
+        self.fix_metadata += other.fix_metadata;
+    }
+}
+
+/// How to rewrite absolute symlink targets pointing inside the source
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LinkRewrite {
+    /// Make the target relative to the symlink location
+    Relative,
+    /// Make the target point inside the destination
+    Reroot,
+}
+
+impl std::str::FromStr for LinkRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relative" => Ok(LinkRewrite::Relative),
+            "reroot" => Ok(LinkRewrite::Reroot),
+            _ => Err(format!(
+                "Invalid link rewrite mode: {} (expected 'relative' or 'reroot')",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with entries whose names are too long for the destination
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum LongNames {
+    /// Leave them out, with a warning
+    #[default]
+    Skip,
+    /// Shorten their names, with a warning
+    Truncate,
+}
+
+impl std::str::FromStr for LongNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(LongNames::Skip),
+            "truncate" => Ok(LongNames::Truncate),
+            _ => Err(format!(
+                "Invalid long names mode: {} (expected 'skip' or 'truncate')",
+                s
+            )),
         }
     }
 }
 
-#[derive(Copy, Clone, Default)]
+/// What to do with a file that changed in both the source and the
+/// destination since the last sync
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ConflictPolicy {
+    /// Replace the destination file, even if it is newer
+    #[default]
+    SourceWins,
+    /// Leave the destination file alone
+    DestWins,
+    /// Keep the file modified last, the source if both have the same time
+    NewerWins,
+    /// Keep the bigger file, the source if both have the same size
+    LargerWins,
+    /// Keep both: the destination file is renamed to
+    /// `<name>.rusync-conflict-<its modification time>`, then the source is
+    /// copied. Renamed files are kept, even with `delete`
+    RenameBoth,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "source-wins" => Ok(ConflictPolicy::SourceWins),
+            "dest-wins" => Ok(ConflictPolicy::DestWins),
+            "newer-wins" => Ok(ConflictPolicy::NewerWins),
+            "larger-wins" => Ok(ConflictPolicy::LargerWins),
+            "rename-both" => Ok(ConflictPolicy::RenameBoth),
+            _ => Err(format!(
+                "Invalid conflict policy: {} (expected 'source-wins', 'dest-wins', 'newer-wins', 'larger-wins' or 'rename-both')",
+                s
+            )),
+        }
+    }
+}
+
+/// How to decide whether a file needs to be copied, and how to copy it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Policy {
+    /// Copy if the source is more recent or has a different size
+    Default,
+    /// Copy only if the size differs, ignoring modification times
+    SizeOnly,
+    /// Copy only if the contents differ, ignoring modification times
+    Checksum,
+    /// Assume the destination is a prefix of the source, and only copy
+    /// what was appended since. Files that shrunk are copied again.
+    Append,
+}
+
+impl std::str::FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Policy::Default),
+            "size-only" => Ok(Policy::SizeOnly),
+            "checksum" => Ok(Policy::Checksum),
+            "append" => Ok(Policy::Append),
+            _ => Err(format!(
+                "Invalid policy: {} (expected 'default', 'size-only', 'checksum' or 'append')",
+                s
+            )),
+        }
+    }
+}
+
+/// Apply a policy to the files matching a pattern
+#[derive(Clone, Debug)]
+pub struct PolicyRule {
+    pub pattern: Pattern,
+    pub policy: Policy,
+}
+
+impl std::str::FromStr for PolicyRule {
+    type Err = String;
+
+    /// Parse something like `*.iso: size-only`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pos = s
+            .rfind(':')
+            .ok_or_else(|| format!("Invalid policy rule: {} (expected GLOB:POLICY)", s))?;
+        let pattern = s[..pos].trim();
+        if pattern.is_empty() {
+            return Err(format!("Invalid policy rule: {} (empty pattern)", s));
+        }
+        Ok(PolicyRule {
+            pattern: Pattern::new(pattern),
+            policy: s[pos + 1..].trim().parse()?,
+        })
+    }
+}
+
+/// Parse policy rules, one per line. Empty lines and lines starting with `#` are ignored.
+pub fn parse_policy_rules(text: &str) -> Result<Vec<PolicyRule>, String> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.parse())
+        .collect()
+}
+
+/// What to do after an entry could not be synced
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorAction {
+    /// Try again. The policy is called again if it fails again
+    Retry,
+    /// Go on with the next entry
+    Skip,
+    /// Stop the sync and return the error
+    Abort,
+}
+
+/// Decide what to do when syncing an entry fails, for instance
+/// by asking the user
+pub trait ErrorPolicy {
+    /// `path` is relative to the source. `attempt` is 1 the first time
+    /// it fails, 2 if it fails again after `ErrorAction::Retry`, and so on:
+    /// retrying forever an entry that always fails would never end the sync
+    fn on_error(&self, path: &Path, error: &Error, attempt: usize) -> ErrorAction;
+}
+
+#[derive(Clone, Default)]
 pub struct SyncOptions {
     /// Wether to preserve permissions of the source file after the destination is written.
     pub preserve_permissions: bool,
+    /// Wether to preserve owner and group of the source files and directories.
+    /// no-op on Windows
+    pub preserve_ownership: bool,
+    /// Added to the user and group ids when preserving ownership, for instance
+    /// to map them into the range of a user namespace when syncing to the
+    /// volume of a rootless container
+    pub chown_offset: u32,
+    /// Wether to preserve modification times of directories
+    pub dir_times: bool,
+    /// Wether to preserve modification times of the files that are copied
+    pub file_times: bool,
+    /// If set, permissions or ownership that could not be preserved do not abort
+    /// the sync. Instead, they are written to this file, one JSON object per line.
+    pub metadata_report: Option<PathBuf>,
+    /// If set, store the source in a deduplicated chunk repository located
+    /// in the destination, as a new snapshot with the given name, instead of
+    /// copying files as-is.
+    pub snapshot: Option<String>,
+    /// If not empty, only sync those paths (relative to the source)
+    pub only: Vec<PathBuf>,
+    /// Follow rsync's convention regarding trailing slashes: `src/` syncs the
+    /// contents of `src` into the destination, while `src` syncs `src` itself,
+    /// as `<destination>/src`
+    pub rsync_trailing_slash: bool,
+    /// Make destination files copy-on-write clones of the source files when
+    /// the file system supports it (Linux only), falling back to regular copies.
+    /// Files that changed are cloned again, which only costs a metadata update.
+    pub reflink: bool,
+    /// Delete files, symlinks and directories from the destination that do not
+    /// exist in the source. Ignored when `snapshot` is set.
+    /// When a directory is missing in the destination, but one of its siblings
+    /// there is going to be deleted and has the same contents, it is renamed
+    /// instead: this is what happens when a directory is renamed in the source
+    pub delete: bool,
+    /// If set, rewrite absolute symlink targets that point inside the source, so
+    /// that the destination tree stays consistent when mounted elsewhere
+    pub rewrite_links: Option<LinkRewrite>,
+    /// Skip (with a warning) symlinks whose targets would point outside the destination
+    pub safe_links: bool,
+    /// When an entry is a directory, a symlink or a file in the source, but has
+    /// an other type in the destination, remove the destination entry so that it
+    /// can be replaced. Otherwise, such conflicts abort the sync
+    pub force: bool,
+    /// Per-file policies. The first rule whose pattern matches the path
+    /// (relative to the source) is used, other files use `Policy::Default`
+    pub policies: Vec<PolicyRule>,
+    /// Walk the whole source before copying anything, so that progress and
+    /// ETA are based on the real totals from the start
+    pub estimate_first: bool,
+    /// Do not change anything, only report what would be done
+    pub dry_run: bool,
+    /// During a dry run, compare the source against this manifest instead of
+    /// the destination (see `manifest::Manifest`)
+    pub manifest: Option<PathBuf>,
+    /// Like `dry_run`, but also compare the contents of files that would be
+    /// replaced, and report permissions and ownership that would be fixed.
+    /// Cannot be used with `manifest`
+    pub audit: bool,
+    /// If set, do not create, update or remove more than this many
+    /// entries per second in the destination. They are evenly spaced,
+    /// even after slow copies
+    pub max_files_per_sec: Option<u32>,
+    /// Called when a file, symlink or directory cannot be synced. If not set,
+    /// the first error aborts the sync. Errors while walking the source,
+    /// deleting extraneous entries or fixing directories always abort
+    pub error_policy: Option<Arc<dyn ErrorPolicy + Send + Sync>>,
+    /// Copied in every `Progress` and in the final `Stats`, to tell syncs
+    /// apart when several of them report to the same place
+    pub label: Option<String>,
+    /// If set, files bigger than this many bytes are copied so that an
+    /// interrupted copy can resume from the last checkpoint instead of
+    /// starting over. A checkpoint is written every `checkpoint_interval` bytes
+    pub checkpoint_interval: Option<u64>,
+    /// If set, do not start copying new files once this many bytes have been
+    /// copied. The file being copied when the limit is reached is finished,
+    /// extraneous entries are not deleted, and `Stats::stopped` is set.
+    /// Dry runs stop planning at the same point. Ignored for snapshots
+    pub max_bytes: Option<u64>,
+    /// Like `max_bytes`, but for the number of files copied
+    pub max_files: Option<u64>,
+    /// Like `max_bytes`, but for the time spent since the sync started.
+    /// Ignored for dry runs
+    pub max_duration: Option<Duration>,
+    /// If set, and set to true while syncing (from a signal handler, for
+    /// instance), stop like when a limit is reached, with `Stats::stopped`
+    /// set to "interrupted". Files copied with checkpoints stop right away
+    /// and resume from there next time; other files are finished first.
+    /// Ignored for dry runs and snapshots
+    pub interrupt: Option<Arc<AtomicBool>>,
+    /// If set, after a file is copied, read it again and compare its hash
+    /// with the source, for a random sample of this percentage of the copied
+    /// files. A difference is an error
+    pub verify_sample: Option<f64>,
+    /// If set, files are first copied in this directory, then moved to the
+    /// destination. Moving is cheap only when it is on the same file system
+    /// as the destination. Can not be used with `checkpoint_interval`, since
+    /// interrupted copies resume from what is in the destination
+    pub temp_dir: Option<PathBuf>,
+    /// Modification times that differ by no more than this are considered
+    /// equal. If not set, the granularity of the destination file system
+    /// is used (for instance, 2 seconds on FAT)
+    pub modify_window: Option<Duration>,
+    /// Entries matching one of these patterns (relative to the source) are
+    /// synced before all the others, so that they are safe even if the sync
+    /// is interrupted. The other entries are kept in memory until the whole
+    /// source has been walked
+    pub first: Vec<Pattern>,
+    /// Entries matching one of these patterns (relative to the source) that
+    /// can't be synced are skipped with a warning, before `error_policy` is
+    /// asked, and counted in `Stats::errors_ignored`. Errors while walking
+    /// the source still stop the sync, since with `delete` what could not
+    /// be seen would be removed
+    pub ignore_errors_from: Vec<Pattern>,
+    /// Before syncing, walk the source and check that the destination has
+    /// enough free space and inodes for what is missing there, to fail early
+    /// rather than in the middle of the sync. Ignored for dry runs and snapshots
+    pub check_space: bool,
+    /// What to do with entries whose names are longer than what the
+    /// destination file system allows
+    pub long_names: LongNames,
+    /// For symlinks pointing outside the source, check that the copy points
+    /// to a file with the same contents, and warn if it does not
+    pub checksum_symlinks: bool,
+    /// During the first seconds of the sync, try bigger and bigger buffers
+    /// to copy files, and keep the fastest one. Files copied with
+    /// reflinks or the append and checksum policies are not concerned
+    pub auto_tune: bool,
+    /// Next to each file copied, write the hashes of its blocks (see the
+    /// blocks module), so that the next sync with the checksum policy can
+    /// compare without reading the destination. Sidecars are kept as long
+    /// as their file is, even with `delete`
+    pub block_sidecars: bool,
+    /// When the last sync between the source and the destination ended. Files
+    /// modified after that on both sides are conflicts, resolved according to
+    /// `on_conflict` and counted in `Stats::conflicts`. If not set, files are
+    /// compared as usual. Dry runs tell how conflicts would be resolved. Ignored
+    /// for snapshots
+    pub last_sync: Option<SystemTime>,
+    pub on_conflict: ConflictPolicy,
+    /// Compute the SHA-256 of source files while they are copied, or compared
+    /// block by block with the checksum policy, and report it with
+    /// `ProgressInfo::hashed`. Cloned files are read to be hashed. Other
+    /// files (up to date, or appended to) are not read in full, so are not
+    /// reported
+    pub hash_files: bool,
 }
 
 impl SyncOptions {
     pub fn new() -> SyncOptions {
         SyncOptions {
             preserve_permissions: true,
+            preserve_ownership: false,
+            chown_offset: 0,
+            dir_times: false,
+            file_times: false,
+            metadata_report: None,
+            snapshot: None,
+            only: vec![],
+            rsync_trailing_slash: false,
+            reflink: false,
+            delete: false,
+            rewrite_links: None,
+            safe_links: false,
+            force: false,
+            policies: vec![],
+            estimate_first: false,
+            dry_run: false,
+            manifest: None,
+            audit: false,
+            max_files_per_sec: None,
+            error_policy: None,
+            label: None,
+            checkpoint_interval: None,
+            max_bytes: None,
+            max_files: None,
+            max_duration: None,
+            interrupt: None,
+            verify_sample: None,
+            temp_dir: None,
+            modify_window: None,
+            first: vec![],
+            ignore_errors_from: vec![],
+            check_space: false,
+            long_names: LongNames::Skip,
+            checksum_symlinks: false,
+            auto_tune: false,
+            block_sidecars: false,
+            last_sync: None,
+            on_conflict: ConflictPolicy::SourceWins,
+            hash_files: false,
         }
     }
+
+    pub(crate) fn policy_for(&self, rel_path: &Path) -> Policy {
+        self.policies
+            .iter()
+            .find(|rule| rule.pattern.matches(rel_path))
+            .map_or(Policy::Default, |rule| rule.policy)
+    }
+
+    pub(crate) fn interrupted(&self) -> bool {
+        self.interrupt
+            .as_ref()
+            .map_or(false, |i| i.load(Ordering::SeqCst))
+    }
+
+    // Why no more files must be copied, if max_files or max_bytes is reached
+    pub(crate) fn copy_limit_reached(
+        &self,
+        files_copied: u64,
+        bytes_copied: u64,
+    ) -> Option<String> {
+        match (self.max_files, self.max_bytes) {
+            (Some(max_files), _) if files_copied >= max_files => {
+                Some(format!("{} files copied", files_copied))
+            }
+            (_, Some(max_bytes)) if bytes_copied >= max_bytes => {
+                Some(format!("{} bytes copied", bytes_copied))
+            }
+            _ => None,
+        }
+    }
+
+    // Returns true if the path is one of the selected paths, or inside one of them
+    pub(crate) fn is_selected(&self, rel_path: &Path) -> bool {
+        self.only.is_empty() || self.only.iter().any(|p| rel_path.starts_with(p))
+    }
+
+    // Like is_selected(), but also return true if the directory contains
+    // one of the selected paths, because we need to walk it to get there
+    pub(crate) fn should_walk(&self, rel_path: &Path) -> bool {
+        self.is_selected(rel_path) || self.only.iter().any(|p| p.starts_with(rel_path))
+    }
 }
 
 pub struct Syncer {
     source: PathBuf,
     destination: PathBuf,
     options: SyncOptions,
-    progress_info: Box<ProgressInfo + Send>,
+    progress_info: Box<dyn ProgressInfo + Send>,
 }
 
 impl Syncer {
@@ -88,33 +750,109 @@ impl Syncer {
         source: &Path,
         destination: &Path,
         options: SyncOptions,
-        progress_info: Box<ProgressInfo + Send>,
+        progress_info: Box<dyn ProgressInfo + Send>,
     ) -> Syncer {
+        let destination = if options.rsync_trailing_slash {
+            rsync_destination(source, destination)
+        } else {
+            destination.to_path_buf()
+        };
         Syncer {
             source: source.to_path_buf(),
-            destination: destination.to_path_buf(),
+            destination,
             progress_info,
             options,
         }
     }
 
+    /// Find out what `sync()` would do, without changing anything. Audits
+    /// (see `SyncOptions::audit`) are done as well if set
+    pub fn plan(mut self) -> Result<Plan, Error> {
+        if !self.options.audit {
+            self.options.dry_run = true;
+        }
+        let stats = self.sync()?;
+        Ok(Plan::from_stats(&stats))
+    }
+
+    /// Find the paths of the destination that `sync()` would need to change
+    /// but is not allowed to, without changing anything
+    pub fn check_access(mut self) -> Result<Vec<AccessProblem>, Error> {
+        self.options.dry_run = true;
+        self.options.audit = false;
+        let destination = self.destination.clone();
+        let changes = Arc::new(Mutex::new(vec![]));
+        self.progress_info = Box::new(ChangeRecorder {
+            changes: changes.clone(),
+            inner: self.progress_info,
+        });
+        self.sync()?;
+        let changes = changes.lock().unwrap();
+        Ok(access::problems(&destination, &changes))
+    }
+
     pub fn sync(self) -> Result<Stats, Error> {
-        let (walker_entry_output, syncer_input) = channel::<Entry>();
+        let dry_run = self.options.dry_run || self.options.audit;
+        if self.options.manifest.is_some() && !self.options.dry_run {
+            return Err(Error::new("A manifest can only be used for dry runs"));
+        }
+        if self.options.manifest.is_some() && self.options.audit {
+            return Err(Error::new("Audits need the destination, not a manifest"));
+        }
+        if self.options.snapshot.is_some() && dry_run {
+            return Err(Error::new("Dry runs are not supported for snapshots"));
+        }
+        if self.options.checkpoint_interval.is_some() && self.options.temp_dir.is_some() {
+            return Err(Error::new(
+                "Checkpoints can not be used with a temporary directory",
+            ));
+        }
+        if self.options.check_space && !dry_run && self.options.snapshot.is_none() {
+            space::check(&self.source, &self.destination, &self.options)?;
+        }
+        let (walker_entry_output, syncer_input) = channel::<Result<Entry, Error>>();
         let (walker_stats_output, progress_input) = channel::<ProgressMessage>();
         let progress_output = walker_stats_output.clone();
 
-        let walk_worker = WalkWorker::new(&self.source, walker_entry_output, walker_stats_output);
-        let sync_worker = SyncWorker::new(
+        let options = self.options;
+        let walk_worker = WalkWorker::new(
             &self.source,
-            &self.destination,
-            syncer_input,
-            progress_output,
+            &options,
+            walker_entry_output,
+            walker_stats_output,
         );
-        let progress_worker = ProgressWorker::new(progress_input, self.progress_info);
-        let options = self.options;
+        let progress_worker = ProgressWorker::new(progress_input, self.progress_info, &options);
 
         let walker_thread = thread::spawn(move || walk_worker.start());
-        let syncer_thread = thread::spawn(move || sync_worker.start(options));
+        let syncer_thread = match options.snapshot.clone() {
+            None if dry_run => {
+                let plan_worker = PlanWorker::new(
+                    &self.source,
+                    &self.destination,
+                    syncer_input,
+                    progress_output,
+                );
+                thread::spawn(move || plan_worker.start(&options))
+            }
+            Some(snapshot) => {
+                let store_worker = StoreWorker::new(
+                    &self.source,
+                    &self.destination,
+                    syncer_input,
+                    progress_output,
+                );
+                thread::spawn(move || store_worker.start(&snapshot))
+            }
+            None => {
+                let sync_worker = SyncWorker::new(
+                    &self.source,
+                    &self.destination,
+                    syncer_input,
+                    progress_output,
+                );
+                thread::spawn(move || sync_worker.start(options))
+            }
+        };
         let progress_thread = thread::spawn(|| progress_worker.start());
 
         walker_thread
@@ -133,3 +871,89 @@ impl Syncer {
         progress_result
     }
 }
+
+fn has_trailing_slash(path: &Path) -> bool {
+    let as_str = path.to_string_lossy();
+    as_str.ends_with('/') || (cfg!(windows) && as_str.ends_with('\\'))
+}
+
+// Where the source should go when following rsync's convention
+fn rsync_destination(source: &Path, destination: &Path) -> PathBuf {
+    if has_trailing_slash(source) {
+        return destination.to_path_buf();
+    }
+    // No file name means something like `.` or `..`, in which
+    // case rsync also syncs the contents
+    match source.file_name() {
+        Some(name) => destination.join(name),
+        None => destination.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_rsync_destination() {
+        let dest = Path::new("dest");
+        assert_eq!(rsync_destination(Path::new("src/"), dest), dest);
+        assert_eq!(
+            rsync_destination(Path::new("src"), dest),
+            Path::new("dest/src")
+        );
+        assert_eq!(
+            rsync_destination(Path::new("/path/to/src"), dest),
+            Path::new("dest/src")
+        );
+        assert_eq!(rsync_destination(Path::new("."), dest), dest);
+        assert_eq!(rsync_destination(Path::new(".."), dest), dest);
+    }
+
+    #[test]
+    fn test_transfer_stats() {
+        let mut stats = TransferStats::default();
+        for i in 0..12 {
+            stats.add(&format!("file{}.TXT", i), i * 1000);
+        }
+        stats.add("big.iso", 2 << 30);
+        stats.add("Makefile", 10);
+
+        assert_eq!(
+            stats.by_extension["txt"],
+            Tally {
+                count: 12,
+                bytes: 66_000
+            }
+        );
+        assert_eq!(stats.by_extension[""].count, 1);
+        assert_eq!(stats.by_size[&0].count, 3);
+        assert_eq!(stats.by_size[&1024].count, 10);
+        assert_eq!(stats.by_size[&(1 << 30)].count, 1);
+        assert_eq!(stats.largest.len(), 10);
+        assert_eq!(stats.largest[0], ("big.iso".to_string(), 2 << 30));
+        assert_eq!(stats.largest[1], ("file11.TXT".to_string(), 11_000));
+        assert_eq!(stats.largest[9].0, "file3.TXT");
+    }
+
+    #[test]
+    fn test_policy_rules() {
+        let rules =
+            parse_policy_rules("# big files\n*.iso: size-only\n\n*.db:checksum\nlogs/: append\n")
+                .unwrap();
+        let mut options = SyncOptions::new();
+        options.policies = rules;
+        assert_eq!(options.policy_for(Path::new("a.iso")), Policy::SizeOnly);
+        assert_eq!(options.policy_for(Path::new("data/b.db")), Policy::Checksum);
+        assert_eq!(
+            options.policy_for(Path::new("logs/app.txt")),
+            Policy::Append
+        );
+        assert_eq!(options.policy_for(Path::new("c.txt")), Policy::Default);
+
+        assert!(parse_policy_rules("*.iso size-only").is_err());
+        assert!(parse_policy_rules("*.iso: fast").is_err());
+        assert!(parse_policy_rules(": checksum").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! template
+//!
+//! Expand placeholders like `{date}` in destination paths, so that
+//! something like `backups/{hostname}/{date}` can be used directly
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::escape;
+
+/// Values used to replace the placeholders
+pub struct Placeholders {
+    /// `{date}`, as YYYY-MM-DD
+    pub date: String,
+    /// `{time}`, as HH-MM-SS (no colons, so that it can be used on any file system)
+    pub time: String,
+    /// `{hostname}`
+    pub hostname: String,
+}
+
+impl Placeholders {
+    /// Values for the current date and time and the local machine
+    pub fn current() -> Result<Placeholders, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| Error::new(&format!("Could not get current time: {}", e)))?;
+        let (year, month, day, hours, minutes, seconds) = local_time(now.as_secs() as i64);
+        Ok(Placeholders {
+            date: format!("{:04}-{:02}-{:02}", year, month, day),
+            time: format!("{:02}-{:02}-{:02}", hours, minutes, seconds),
+            hostname: hostname()?,
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "date" => Some(&self.date),
+            "time" => Some(&self.time),
+            "hostname" => Some(&self.hostname),
+            _ => None,
+        }
+    }
+}
+
+/// Replace every `{name}` in `template` by its value. Only the known
+/// placeholders are replaced, any other brace is kept as is; `{{` gives a
+/// literal `{`, for names that would otherwise be replaced
+pub fn expand(template: &str, placeholders: &Placeholders) -> String {
+    String::from_utf8(expand_bytes(template.as_bytes(), placeholders))
+        .expect("only ASCII braces are replaced")
+}
+
+// Like expand(), for paths that may not be valid UTF-8
+fn expand_bytes(template: &[u8], placeholders: &Placeholders) -> Vec<u8> {
+    let mut res = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.iter().position(|b| *b == b'{') {
+        res.extend_from_slice(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(escaped) = after.strip_prefix(b"{") {
+            res.push(b'{');
+            rest = escaped;
+            continue;
+        }
+        let value = after.iter().position(|b| *b == b'}').and_then(|end| {
+            let name = std::str::from_utf8(&after[..end]).ok()?;
+            placeholders.get(name).map(|v| (end, v))
+        });
+        match value {
+            Some((end, value)) => {
+                res.extend_from_slice(value.as_bytes());
+                rest = &after[end + 1..];
+            }
+            None => {
+                res.push(b'{');
+                rest = after;
+            }
+        }
+    }
+    res.extend_from_slice(rest);
+    res
+}
+
+/// Expand placeholders in `path`, using the current date, time and host name
+pub fn expand_path(path: &Path) -> Result<PathBuf, Error> {
+    let bytes = escape::path_bytes(path);
+    if !bytes.contains(&b'{') {
+        return Ok(path.to_path_buf());
+    }
+    let placeholders = Placeholders::current()?;
+    Ok(escape::path_from_bytes(expand_bytes(&bytes, &placeholders)))
+}
+
+#[cfg(unix)]
+pub(crate) fn local_time(epoch: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let time = epoch as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&time, &mut tm) };
+    (
+        i64::from(tm.tm_year) + 1900,
+        (tm.tm_mon + 1) as u32,
+        tm.tm_mday as u32,
+        tm.tm_hour as u32,
+        tm.tm_min as u32,
+        tm.tm_sec as u32,
+    )
+}
+
+#[cfg(windows)]
+pub(crate) fn local_time(epoch: i64) -> (i64, u32, u32, u32, u32, u32) {
+    utc_time(epoch)
+}
+
+// Convert seconds since the epoch to a UTC date, using the algorithm from
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+#[cfg(any(windows, test))]
+fn utc_time(epoch: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = epoch.div_euclid(86400);
+    let secs = epoch.rem_euclid(86400) as u32;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+#[cfg(unix)]
+fn hostname() -> Result<String, Error> {
+    let mut buffer = vec![0u8; 256];
+    let res = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if res != 0 {
+        return Err(Error::new(&format!(
+            "Could not get host name: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    Ok(String::from_utf8_lossy(&buffer[..len]).to_string())
+}
+
+#[cfg(windows)]
+fn hostname() -> Result<String, Error> {
+    std::env::var("COMPUTERNAME")
+        .map_err(|e| Error::new(&format!("Could not get host name: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn test_placeholders() -> Placeholders {
+        Placeholders {
+            date: "2018-10-01".to_string(),
+            time: "12-30-00".to_string(),
+            hostname: "tardis".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let placeholders = test_placeholders();
+        assert_eq!(
+            expand("backups/{hostname}/{date}", &placeholders),
+            "backups/tardis/2018-10-01"
+        );
+        assert_eq!(
+            expand("{date}_{time}", &placeholders),
+            "2018-10-01_12-30-00"
+        );
+        assert_eq!(expand("no/placeholders", &placeholders), "no/placeholders");
+    }
+
+    #[test]
+    fn keep_other_braces() {
+        let placeholders = test_placeholders();
+        assert_eq!(expand("backup{1}", &placeholders), "backup{1}");
+        assert_eq!(expand("backups/{date", &placeholders), "backups/{date");
+        assert_eq!(expand("{{date}/{date}", &placeholders), "{date}/2018-10-01");
+        assert_eq!(expand("a}b{", &placeholders), "a}b{");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn expand_raw_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Not valid UTF-8
+        assert_eq!(
+            expand_bytes(b"caf\xe9/{date}", &test_placeholders()),
+            b"caf\xe9/2018-10-01".to_vec()
+        );
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9"));
+        assert_eq!(expand_path(path).unwrap(), path);
+    }
+
+    #[test]
+    fn test_utc_time() {
+        assert_eq!(utc_time(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(utc_time(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(utc_time(1_538_397_000), (2018, 10, 1, 12, 30, 0));
+    }
+}
@@ -0,0 +1,97 @@
+//! throttle
+//!
+//! Limit how many operations are done per second
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Throttle {
+    /// Between the starts of two operations, None if not limited
+    interval: Option<Duration>,
+    start: Instant,
+    /// When the next operation may start, since `start`
+    next: Duration,
+}
+
+impl Throttle {
+    pub fn new(per_second: u32) -> Throttle {
+        let interval = match per_second {
+            0 => None,
+            // Rounded up, so that there's never one too many
+            n => {
+                let nanos = 1_000_000_000 / u64::from(n);
+                let rounded = u64::from(1_000_000_000 % n != 0);
+                Some(Duration::from_nanos(nanos + rounded))
+            }
+        };
+        Throttle {
+            interval,
+            start: Instant::now(),
+            next: Duration::from_secs(0),
+        }
+    }
+
+    /// Call before each operation. Sleeps if operations are done too fast
+    pub fn wait(&mut self) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let (delay, next) = schedule(self.next, interval, self.start.elapsed());
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        self.next = next;
+    }
+}
+
+// How long to wait before starting an operation `elapsed` after the start,
+// if it may start at `next`, and when the one after may start. It is always
+// `interval` after this one, however long ago the previous one was, so
+// that there's no more than one operation per `interval` even after a
+// pause (a token bucket holding a single token)
+fn schedule(next: Duration, interval: Duration, elapsed: Duration) -> (Option<Duration>, Duration) {
+    let start = next.max(elapsed);
+    let delay = start
+        .checked_sub(elapsed)
+        .filter(|d| *d > Duration::from_secs(0));
+    (delay, start + interval)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let ms = Duration::from_millis;
+        // First operation is never delayed
+        assert_eq!(schedule(ms(0), ms(100), ms(0)), (None, ms(100)));
+        assert_eq!(schedule(ms(100), ms(100), ms(0)), (Some(ms(100)), ms(200)));
+        assert_eq!(
+            schedule(ms(500), ms(100), ms(200)),
+            (Some(ms(300)), ms(600))
+        );
+        // We're late, so no need to wait
+        assert_eq!(schedule(ms(500), ms(100), ms(800)), (None, ms(900)));
+    }
+
+    #[test]
+    fn no_burst_after_idle() {
+        let ms = Duration::from_millis;
+        // One operation, then nothing for a few seconds
+        let (_, mut next) = schedule(ms(0), ms(100), ms(0));
+        let mut elapsed = ms(5000);
+        let mut started = vec![];
+        for _ in 0..10 {
+            let (delay, after) = schedule(next, ms(100), elapsed);
+            elapsed += delay.unwrap_or_default();
+            started.push(elapsed);
+            next = after;
+        }
+        // Still 10 per second, not the 50 that were "missed"
+        assert_eq!(started[0], ms(5000));
+        assert_eq!(started[9], ms(5900));
+    }
+}
@@ -0,0 +1,135 @@
+//! tune
+//!
+//! Pick the size of the buffer used to copy files: during the first
+//! seconds of a sync, try bigger and bigger buffers, and keep the
+//! fastest one
+
+use std::time::{Duration, Instant};
+
+pub const MIN_BUFFER_SIZE: usize = 64 * 1024;
+pub const MAX_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+// Sizes are only tried during this long, then the best one is kept
+const TUNING_PERIOD: Duration = Duration::from_secs(10);
+
+// Each size is measured over at least this many bytes
+const WINDOW_BYTES: u64 = 32 * 1024 * 1024;
+
+// A bigger buffer is only worth it if it is at least this much faster
+const MIN_GAIN: f64 = 1.05;
+
+pub struct BufferTuner {
+    size: usize,
+    start: Instant,
+    window_bytes: u64,
+    window_time: Duration,
+    /// Best size so far, with its throughput in bytes per second
+    best: Option<(usize, f64)>,
+    done: bool,
+}
+
+impl BufferTuner {
+    pub fn new(initial_size: usize) -> BufferTuner {
+        BufferTuner {
+            size: initial_size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE),
+            start: Instant::now(),
+            window_bytes: 0,
+            window_time: Duration::from_secs(0),
+            best: None,
+            done: false,
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+
+    /// Call after each file copied with `buffer_size()`
+    pub fn record(&mut self, bytes: u64, elapsed: Duration) {
+        if self.done {
+            return;
+        }
+        if self.start.elapsed() >= TUNING_PERIOD {
+            self.done = true;
+            if let Some((size, _)) = self.best {
+                self.size = size;
+            }
+            return;
+        }
+        // The time spent on small files is mostly spent opening
+        // and closing them, whatever the size of the buffer
+        if bytes < 4 * self.size as u64 {
+            return;
+        }
+        self.window_bytes += bytes;
+        self.window_time += elapsed;
+        if self.window_bytes < WINDOW_BYTES {
+            return;
+        }
+        let seconds = self.window_time.as_secs_f64().max(1e-6);
+        let throughput = self.window_bytes as f64 / seconds;
+        self.window_bytes = 0;
+        self.window_time = Duration::from_secs(0);
+        let (best, size, done) = next_size(self.size, self.best, throughput);
+        self.best = Some(best);
+        self.size = size;
+        self.done = done;
+    }
+}
+
+// Given the throughput measured with `size` and the best size so far, return
+// the new best size, the size to use next, and whether to stop trying
+fn next_size(
+    size: usize,
+    best: Option<(usize, f64)>,
+    throughput: f64,
+) -> ((usize, f64), usize, bool) {
+    match best {
+        Some(best) if throughput < best.1 * MIN_GAIN => (best, best.0, true),
+        _ if size * 2 > MAX_BUFFER_SIZE => ((size, throughput), size, true),
+        _ => ((size, throughput), size * 2, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_next_size() {
+        let k = 1024;
+        // Nothing to compare with yet
+        assert_eq!(
+            next_size(64 * k, None, 100.0),
+            ((64 * k, 100.0), 128 * k, false)
+        );
+        // Faster: keep growing
+        assert_eq!(
+            next_size(128 * k, Some((64 * k, 100.0)), 150.0),
+            ((128 * k, 150.0), 256 * k, false)
+        );
+        // Not faster enough: go back to the best one
+        assert_eq!(
+            next_size(256 * k, Some((128 * k, 150.0)), 155.0),
+            ((128 * k, 150.0), 128 * k, true)
+        );
+        // Can't grow any more
+        assert_eq!(
+            next_size(MAX_BUFFER_SIZE, Some((MAX_BUFFER_SIZE / 2, 100.0)), 200.0),
+            ((MAX_BUFFER_SIZE, 200.0), MAX_BUFFER_SIZE, true)
+        );
+    }
+
+    #[test]
+    fn ignore_small_files() {
+        let mut tuner = BufferTuner::new(0);
+        assert_eq!(tuner.buffer_size(), MIN_BUFFER_SIZE);
+        for _ in 0..1000 {
+            tuner.record(1024, Duration::from_millis(1));
+        }
+        assert_eq!(tuner.buffer_size(), MIN_BUFFER_SIZE);
+        tuner.record(WINDOW_BYTES, Duration::from_millis(100));
+        assert_eq!(tuner.buffer_size(), 2 * MIN_BUFFER_SIZE);
+    }
+}
@@ -1,7 +1,11 @@
+mod plan_worker;
 mod progress_worker;
+mod store_worker;
 mod sync_worker;
 mod walk_worker;
 
+pub use self::plan_worker::PlanWorker;
 pub use self::progress_worker::ProgressWorker;
+pub use self::store_worker::StoreWorker;
 pub use self::sync_worker::SyncWorker;
 pub use self::walk_worker::WalkWorker;
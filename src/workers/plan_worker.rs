@@ -0,0 +1,576 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::blocks;
+use crate::conflict;
+use crate::conflict::{Resolution, Version};
+use crate::entry::Entry;
+use crate::error::Error;
+use crate::fsops;
+use crate::fsops::{Removal, SyncOutcome};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::probe;
+use crate::progress::{PlannedChange, ProgressMessage};
+use crate::rename;
+use crate::sync::{ConflictPolicy, Policy, SyncOptions};
+
+// What the source is compared to
+enum Destination {
+    Live(PathBuf),
+    Manifest(Manifest),
+}
+
+impl Destination {
+    fn lookup(&self, rel_path: &Path, with_digest: bool) -> Result<Option<ManifestEntry>, Error> {
+        match self {
+            Destination::Live(root) => ManifestEntry::from_path(&root.join(rel_path), with_digest),
+            Destination::Manifest(manifest) => Ok(manifest.entries.get(rel_path).cloned()),
+        }
+    }
+
+    fn entries(&self) -> Result<Vec<(PathBuf, ManifestEntry)>, Error> {
+        let entries = match self {
+            Destination::Live(root) if !root.is_dir() => vec![],
+            Destination::Live(root) => Manifest::from_tree(root, false)?
+                .entries
+                .into_iter()
+                .collect(),
+            Destination::Manifest(manifest) => manifest
+                .entries
+                .iter()
+                .map(|(path, entry)| (path.clone(), entry.clone()))
+                .collect(),
+        };
+        Ok(entries)
+    }
+
+    // Directories in `parent`
+    fn subdirs(&self, parent: &Path) -> Vec<PathBuf> {
+        match self {
+            Destination::Live(root) => rename::subdirs(root, parent),
+            Destination::Manifest(manifest) => manifest
+                .entries
+                .iter()
+                .filter(|(path, entry)| {
+                    **entry == ManifestEntry::Dir && path.parent() == Some(parent)
+                })
+                .map(|(path, _)| path.clone())
+                .collect(),
+        }
+    }
+
+    // Contents of `rel_dir`, relative to it
+    fn tree(&self, rel_dir: &Path) -> Result<Manifest, Error> {
+        match self {
+            Destination::Live(root) => Manifest::from_tree(&root.join(rel_dir), false),
+            Destination::Manifest(manifest) => {
+                let mut tree = Manifest::default();
+                for (path, entry) in &manifest.entries {
+                    match path.strip_prefix(rel_dir) {
+                        Ok(rel_path) if !rel_path.as_os_str().is_empty() => {
+                            tree.entries.insert(rel_path.to_path_buf(), entry.clone());
+                        }
+                        _ => (),
+                    }
+                }
+                Ok(tree)
+            }
+        }
+    }
+}
+
+/// Same as SyncWorker, but only report what would be done
+pub struct PlanWorker {
+    input: Receiver<Result<Entry, Error>>,
+    output: Sender<ProgressMessage>,
+    source: PathBuf,
+    destination: PathBuf,
+    abs_source: PathBuf,
+    abs_destination: PathBuf,
+    source_paths: HashSet<PathBuf>,
+    // Directories that exist in the destination, or would be created
+    known_dirs: HashSet<PathBuf>,
+    // Destination entries that would be removed because of type conflicts
+    replaced: HashSet<PathBuf>,
+    // Directories that would be renamed, as (old name, new name)
+    renamed: Vec<(PathBuf, PathBuf)>,
+    max_name_len: Option<usize>,
+    files_copied: u64,
+    bytes_copied: u64,
+}
+
+impl PlanWorker {
+    pub fn new(
+        source: &Path,
+        destination: &Path,
+        input: Receiver<Result<Entry, Error>>,
+        output: Sender<ProgressMessage>,
+    ) -> PlanWorker {
+        PlanWorker {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            abs_source: fsops::absolute_path(source),
+            abs_destination: fsops::absolute_path(destination),
+            input,
+            output,
+            source_paths: HashSet::new(),
+            known_dirs: HashSet::new(),
+            replaced: HashSet::new(),
+            renamed: vec![],
+            max_name_len: None,
+            files_copied: 0,
+            bytes_copied: 0,
+        }
+    }
+
+    pub fn start(mut self, opts: &SyncOptions) -> Result<(), Error> {
+        let destination = match opts.manifest {
+            Some(ref manifest_path) => Destination::Manifest(Manifest::read(manifest_path)?),
+            None => Destination::Live(self.destination.clone()),
+        };
+        if let Destination::Live(ref root) = destination {
+            // The destination may not have been created yet
+            self.max_name_len = root
+                .ancestors()
+                .find(|p| p.is_dir())
+                .and_then(probe::max_name_len);
+        }
+        while let Ok(entry) = self.input.recv() {
+            let entry = entry?;
+            // Stop where the sync would, see SyncWorker::limit_reached()
+            if let Some(reason) = opts.copy_limit_reached(self.files_copied, self.bytes_copied) {
+                return self.send(ProgressMessage::Stopped(reason));
+            }
+            let rel_path = fsops::get_rel_path(entry.path(), &self.source)?;
+            let rel_path = match self.dest_rel_path(&rel_path, opts)? {
+                None => continue,
+                Some(rel_path) => rel_path,
+            };
+            fsops::add_source_path(&mut self.source_paths, &rel_path);
+            self.plan_conflict(&destination, &entry, &rel_path, opts)?;
+            if entry.is_dir() {
+                if opts.delete && self.lookup(&destination, &rel_path, false)?.is_none() {
+                    self.plan_rename(&destination, &entry, &rel_path, opts)?;
+                }
+                self.plan_dirs(&destination, &rel_path)?;
+                continue;
+            }
+            if let Some(parent) = rel_path.parent() {
+                self.plan_dirs(&destination, parent)?;
+            }
+            let outcome = self.plan(&destination, &entry, &rel_path, opts)?;
+            if outcome == SyncOutcome::FileCopied {
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                self.files_copied += 1;
+                self.bytes_copied += size;
+                self.send(ProgressMessage::Transferred {
+                    description: rel_path.to_string_lossy().to_string(),
+                    size,
+                })?;
+            }
+            self.send(ProgressMessage::DoneSyncing(outcome))?;
+        }
+        if opts.delete {
+            self.plan_removals(&destination, opts)?;
+        }
+        Ok(())
+    }
+
+    // Same as SyncWorker::resolve_conflict()
+    fn plan_conflict(
+        &mut self,
+        destination: &Destination,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<(), Error> {
+        let dest_kind = match self.lookup(destination, rel_path, false)? {
+            None => return Ok(()),
+            Some(entry) => entry.kind(),
+        };
+        let src_meta = src_entry.metadata().expect("src_meta should not be None");
+        let src_kind = fsops::Kind::of(src_meta);
+        if src_kind == dest_kind {
+            return Ok(());
+        }
+        if !opts.force {
+            return Err(fsops::type_conflict(
+                src_entry.description(),
+                src_kind,
+                dest_kind,
+            ));
+        }
+        let desc = rel_path.to_string_lossy().to_string();
+        let removal = match dest_kind {
+            fsops::Kind::File => Removal::File(desc),
+            fsops::Kind::Symlink => Removal::Symlink(desc),
+            fsops::Kind::Dir => Removal::Dir(desc),
+        };
+        self.planned(PlannedChange::Remove, rel_path)?;
+        self.send(ProgressMessage::Removed(removal))?;
+        self.replaced.insert(rel_path.to_path_buf());
+        Ok(())
+    }
+
+    // Same as SyncWorker::dest_rel_path()
+    fn dest_rel_path(&self, rel_path: &Path, opts: &SyncOptions) -> Result<Option<PathBuf>, Error> {
+        let (res, warning) = fsops::dest_rel_path(rel_path, self.max_name_len, opts.long_names);
+        if let Some(message) = warning {
+            self.send(ProgressMessage::Warning(message))?;
+        }
+        Ok(res)
+    }
+
+    // Same as SyncWorker::detect_rename()
+    fn plan_rename(
+        &mut self,
+        destination: &Destination,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<(), Error> {
+        let parent = rel_path.parent().unwrap_or_else(|| Path::new(""));
+        let dest_dirs = destination
+            .subdirs(&self.current_path(parent))
+            .into_iter()
+            .filter(|d| {
+                !self.replaced.contains(d) && !self.renamed.iter().any(|(from, _)| from == d)
+            })
+            .collect();
+        let old_name = rename::find_old_name(
+            &self.source,
+            src_entry.path(),
+            dest_dirs,
+            |dir| destination.tree(dir),
+            opts,
+        )?;
+        let old_name = match old_name {
+            None => return Ok(()),
+            Some(old_name) => old_name,
+        };
+        self.planned(PlannedChange::RenameDir, rel_path)?;
+        self.send(ProgressMessage::DirRenamed {
+            from: old_name.to_string_lossy().to_string(),
+            to: rel_path.to_string_lossy().to_string(),
+        })?;
+        self.renamed.push((old_name, rel_path.to_path_buf()));
+        Ok(())
+    }
+
+    // Where `rel_path` is in the destination before the sync, taking
+    // renamed directories into account
+    fn current_path(&self, rel_path: &Path) -> PathBuf {
+        for (from, to) in &self.renamed {
+            if let Ok(rest) = rel_path.strip_prefix(to) {
+                return from.join(rest);
+            }
+        }
+        rel_path.to_path_buf()
+    }
+
+    // Where a path of the destination would be after the sync
+    fn new_path(&self, rel_path: &Path) -> PathBuf {
+        for (from, to) in &self.renamed {
+            if let Ok(rest) = rel_path.strip_prefix(from) {
+                return to.join(rest);
+            }
+        }
+        rel_path.to_path_buf()
+    }
+
+    // Look up an entry in the destination, taking replaced and
+    // renamed entries into account
+    fn lookup(
+        &self,
+        destination: &Destination,
+        rel_path: &Path,
+        with_digest: bool,
+    ) -> Result<Option<ManifestEntry>, Error> {
+        if rel_path.ancestors().any(|a| self.replaced.contains(a)) {
+            return Ok(None);
+        }
+        destination.lookup(&self.current_path(rel_path), with_digest)
+    }
+
+    fn send(&self, message: ProgressMessage) -> Result<(), Error> {
+        self.output
+            .send(message)
+            .map_err(|e| Error::new(&format!("Could not send: {}", e)))
+    }
+
+    fn planned(&self, change: PlannedChange, rel_path: &Path) -> Result<(), Error> {
+        let desc = rel_path.to_string_lossy().to_string();
+        self.send(ProgressMessage::Planned(change, desc))
+    }
+
+    fn plan_dirs(&mut self, destination: &Destination, rel_dir: &Path) -> Result<(), Error> {
+        let mut ancestors: Vec<&Path> = rel_dir
+            .ancestors()
+            .filter(|a| !a.as_os_str().is_empty())
+            .collect();
+        ancestors.reverse();
+        for dir in ancestors {
+            if self.known_dirs.contains(dir) {
+                continue;
+            }
+            self.known_dirs.insert(dir.to_path_buf());
+            if self.lookup(destination, dir, false)? == Some(ManifestEntry::Dir) {
+                continue;
+            }
+            self.planned(PlannedChange::CreateDir, dir)?;
+            self.send(ProgressMessage::DirCreated(
+                dir.to_string_lossy().to_string(),
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn plan(
+        &self,
+        destination: &Destination,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<SyncOutcome, Error> {
+        let is_link = src_entry.is_link().expect("src.is_link should not be None");
+        if is_link {
+            return self.plan_link(destination, src_entry, rel_path, opts);
+        }
+        let must_copy = match self.plan_changes(destination, src_entry, rel_path, opts)? {
+            None => false,
+            Some(Resolution::CopySource) => true,
+            Some(Resolution::KeepDestination) => return Ok(SyncOutcome::DestinationKept),
+            // The destination would be out of the way
+            Some(Resolution::RenameDestination) => {
+                self.planned(PlannedChange::CopyFile, rel_path)?;
+                return Ok(SyncOutcome::FileCopied);
+            }
+        };
+        if must_copy || self.needs_copy(destination, src_entry, rel_path, opts)? {
+            let change = if opts.audit {
+                self.audit_replacement(destination, src_entry, rel_path)?
+            } else {
+                match self.lookup(destination, rel_path, false)? {
+                    Some(ManifestEntry::File { .. }) => PlannedChange::UpdateFile,
+                    _ => PlannedChange::CopyFile,
+                }
+            };
+            self.planned(change, rel_path)?;
+            return Ok(SyncOutcome::FileCopied);
+        }
+        let dest_path = self.destination.join(self.current_path(rel_path));
+        if opts.audit && metadata_differs(src_entry, &dest_path, opts)? {
+            self.planned(PlannedChange::UpdateMetadata, rel_path)?;
+        }
+        Ok(SyncOutcome::UpToDate)
+    }
+
+    // Same as SyncWorker::resolve_changes()
+    fn plan_changes(
+        &self,
+        destination: &Destination,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<Option<Resolution>, Error> {
+        let last_sync = match opts.last_sync {
+            Some(last_sync) => last_sync,
+            None => return Ok(None),
+        };
+        let dest_version = match self.lookup(destination, rel_path, false)? {
+            Some(ManifestEntry::File { size, mtime, .. }) => Version { mtime, size },
+            _ => return Ok(None),
+        };
+        let src_meta = src_entry.metadata().expect("src_meta should not be None");
+        let resolution = match conflict::resolve(
+            opts.on_conflict,
+            last_sync,
+            Version::of(src_meta),
+            dest_version,
+        ) {
+            Some(resolution) => resolution,
+            None => return Ok(None),
+        };
+        let outcome = match resolution {
+            Resolution::CopySource => "would replace it with the source".to_string(),
+            Resolution::KeepDestination => "would keep the destination".to_string(),
+            Resolution::RenameDestination => {
+                let renamed = conflict::renamed_path(rel_path, dest_version);
+                let name = renamed.file_name().unwrap_or_default().to_string_lossy();
+                format!("would rename the destination to {}", name)
+            }
+        };
+        let message = format!(
+            "{} changed in both the source and the destination since the last sync, {}",
+            rel_path.to_string_lossy(),
+            outcome
+        );
+        self.send(ProgressMessage::Conflict(message))?;
+        Ok(Some(resolution))
+    }
+
+    // Find out why an existing file would be replaced
+    fn audit_replacement(
+        &self,
+        destination: &Destination,
+        src_entry: &Entry,
+        rel_path: &Path,
+    ) -> Result<PlannedChange, Error> {
+        let size = match self.lookup(destination, rel_path, false)? {
+            Some(ManifestEntry::File { size, .. }) => size,
+            _ => return Ok(PlannedChange::CopyFile),
+        };
+        let src_size = src_entry.metadata().map(|m| m.len()).unwrap_or(0);
+        if size != src_size {
+            return Ok(PlannedChange::ReplaceContents);
+        }
+        let dest_entry = Entry::new(
+            &rel_path.to_string_lossy(),
+            &self.destination.join(self.current_path(rel_path)),
+        );
+        if fsops::file_digest(src_entry)? != fsops::file_digest(&dest_entry)? {
+            Ok(PlannedChange::ReplaceContents)
+        } else {
+            Ok(PlannedChange::ReplaceMetadata)
+        }
+    }
+
+    fn needs_copy(
+        &self,
+        destination: &Destination,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<bool, Error> {
+        let policy = opts.policy_for(rel_path);
+        let (size, mtime, digest) =
+            match self.lookup(destination, rel_path, policy == Policy::Checksum)? {
+                Some(ManifestEntry::File {
+                    size,
+                    mtime,
+                    digest,
+                }) => (size, mtime, digest),
+                _ => return Ok(true),
+            };
+        let src_meta = src_entry.metadata().expect("src_meta should not be None");
+        let different_size = size != src_meta.len();
+        let more_recent = fsops::is_newer(fsops::precise_mtime(src_meta), mtime, opts);
+        let res = match policy {
+            Policy::Default => more_recent || different_size,
+            Policy::SizeOnly | Policy::Append => different_size,
+            // Manifests written without --checksums have no digests,
+            // so fall back to the default comparison
+            Policy::Checksum => match digest {
+                Some(digest) => different_size || fsops::file_digest(src_entry)? != digest,
+                None => more_recent || different_size,
+            },
+        };
+        Ok(res)
+    }
+
+    fn plan_link(
+        &self,
+        destination: &Destination,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<SyncOutcome, Error> {
+        let src_target = fsops::read_link(src_entry)?;
+        let dest_target = fsops::rewrite_link_target(
+            &src_target,
+            rel_path,
+            &self.abs_source,
+            &self.abs_destination,
+            opts.rewrite_links,
+        );
+        if opts.safe_links && fsops::link_escapes(&dest_target, rel_path, &self.abs_destination) {
+            let message = format!(
+                "Would skip {}: target {} is outside the destination",
+                rel_path.to_string_lossy(),
+                dest_target.to_string_lossy()
+            );
+            self.send(ProgressMessage::Warning(message))?;
+            return Ok(SyncOutcome::SymlinkSkipped);
+        }
+        match self.lookup(destination, rel_path, false)? {
+            Some(ManifestEntry::Link { ref target }) if *target == dest_target => {
+                Ok(SyncOutcome::SymlinkUpToDate)
+            }
+            Some(ManifestEntry::Link { .. }) => {
+                self.planned(PlannedChange::UpdateLink, rel_path)?;
+                Ok(SyncOutcome::SymlinkUpdated)
+            }
+            None => {
+                self.planned(PlannedChange::CreateLink, rel_path)?;
+                Ok(SyncOutcome::SymlinkCreated)
+            }
+            Some(_) => Err(Error::new(&format!(
+                "Refusing to replace existing path {} by symlink",
+                rel_path.to_string_lossy()
+            ))),
+        }
+    }
+
+    fn plan_removals(&self, destination: &Destination, opts: &SyncOptions) -> Result<(), Error> {
+        let mut entries = destination.entries()?;
+        // Contents of directories first, like when actually removing them
+        entries.sort_by(|a, b| b.0.cmp(&a.0));
+        for (rel_path, entry) in entries {
+            // Entries of renamed directories are moved, not removed
+            let rel_path = self.new_path(&rel_path);
+            if self.source_paths.contains(&rel_path)
+                || !opts.is_selected(&rel_path)
+                || (opts.block_sidecars && blocks::is_needed(&rel_path, &self.source_paths))
+                || (opts.on_conflict == ConflictPolicy::RenameBoth
+                    && conflict::is_needed(&rel_path, &self.source_paths))
+            {
+                continue;
+            }
+            let desc = rel_path.to_string_lossy().to_string();
+            let removal = match entry {
+                ManifestEntry::File { .. } => Removal::File(desc),
+                ManifestEntry::Link { .. } => Removal::Symlink(desc),
+                ManifestEntry::Dir => Removal::Dir(desc),
+            };
+            self.planned(PlannedChange::Remove, &rel_path)?;
+            self.send(ProgressMessage::Removed(removal))?;
+        }
+        Ok(())
+    }
+}
+
+// True if syncing would change permissions or ownership of the destination
+#[cfg(unix)]
+fn metadata_differs(
+    src_entry: &Entry,
+    dest_path: &Path,
+    opts: &SyncOptions,
+) -> Result<bool, Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let src_meta = src_entry.metadata().expect("src_meta should not be None");
+    let dest_meta = std::fs::symlink_metadata(dest_path).map_err(|e| {
+        Error::from_io(
+            &format!("Could not read metadata of {:?}: {}", dest_path, e),
+            &e,
+        )
+    })?;
+    let mode_differs = (src_meta.mode() & 0o7777) != (dest_meta.mode() & 0o7777);
+    let expected_owner = (
+        src_meta.uid().saturating_add(opts.chown_offset),
+        src_meta.gid().saturating_add(opts.chown_offset),
+    );
+    let owner_differs = expected_owner != (dest_meta.uid(), dest_meta.gid());
+    Ok((opts.preserve_permissions && mode_differs) || (opts.preserve_ownership && owner_differs))
+}
+
+#[cfg(windows)]
+fn metadata_differs(
+    _src_entry: &Entry,
+    _dest_path: &Path,
+    _opts: &SyncOptions,
+) -> Result<bool, Error> {
+    Ok(false)
+}
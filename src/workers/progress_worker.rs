@@ -1,67 +1,155 @@
 use std::sync::mpsc::Receiver;
 use std::time::Instant;
 
+use crate::fsops::SyncOutcome;
 use crate::progress::{Progress, ProgressInfo, ProgressMessage};
-use crate::sync::Stats;
+use crate::sync::{Stats, SyncOptions};
 
 pub struct ProgressWorker {
     input: Receiver<ProgressMessage>,
-    progress_info: Box<ProgressInfo + Send>,
+    progress_info: Box<dyn ProgressInfo + Send>,
+    dry_run: bool,
+    label: Option<String>,
 }
 
 impl ProgressWorker {
     pub fn new(
         input: Receiver<ProgressMessage>,
-        progress_info: Box<ProgressInfo + Send>,
+        progress_info: Box<dyn ProgressInfo + Send>,
+        options: &SyncOptions,
     ) -> ProgressWorker {
         ProgressWorker {
             input,
             progress_info,
+            dry_run: options.dry_run || options.audit,
+            label: options.label.clone(),
         }
     }
 
     pub fn start(self) -> Stats {
         let mut stats = Stats::new();
-        let mut file_done = 0;
-        let mut current_file = String::from("");
-        let mut index = 0;
-        let mut total_done = 0;
+        stats.dry_run = self.dry_run;
+        stats.label = self.label.clone();
+        let mut progress = Progress {
+            current_file: String::new(),
+            file_done: 0,
+            file_size: 0,
+            total_done: 0,
+            total_size: 0,
+            index: 0,
+            num_files: 0,
+            entries_done: 0,
+            num_entries: 0,
+            eta: 0,
+            label: self.label.clone(),
+        };
         let now = Instant::now();
-        for progress in self.input.iter() {
-            match progress {
+        for message in self.input.iter() {
+            match message {
                 ProgressMessage::Todo {
                     num_files,
+                    num_dirs,
                     total_size,
                 } => {
                     stats.num_files = num_files;
+                    stats.num_dirs = num_dirs;
                     stats.total_size = total_size;
+                    progress.num_files = num_files as usize;
+                    progress.num_entries = (num_files + num_dirs) as usize;
+                    progress.total_size = total_size;
                 }
                 ProgressMessage::StartSync(x) => {
                     self.progress_info.new_file(&x);
-                    current_file = x;
-                    index += 1;
+                    progress.current_file = x;
+                    progress.file_done = 0;
+                    progress.file_size = 0;
+                    progress.index += 1;
                 }
                 ProgressMessage::DoneSyncing(x) => {
                     self.progress_info.done_syncing();
+                    progress.entries_done += 1;
+                    // Copies already reported their progress as they went, but
+                    // the rest would go unnoticed
+                    if x != SyncOutcome::FileCopied {
+                        progress.eta = eta(&now, &progress);
+                        self.progress_info.progress(&progress);
+                    }
                     stats.add_outcome(&x);
-                    file_done = 0;
+                }
+                ProgressMessage::DirSynced(x) => {
+                    progress.current_file = x;
+                    progress.file_done = 0;
+                    progress.file_size = 0;
+                    progress.entries_done += 1;
+                    progress.eta = eta(&now, &progress);
+                    self.progress_info.progress(&progress);
+                }
+                ProgressMessage::Planned(change, x) => {
+                    self.progress_info.planned(change, &x);
+                }
+                ProgressMessage::DirRenamed { .. } => {
+                    stats.dirs_renamed += 1;
+                }
+                ProgressMessage::DirCreated(x) => {
+                    stats.add_dir_created(&x);
+                }
+                ProgressMessage::Removed(x) => {
+                    stats.add_removal(&x);
+                }
+                ProgressMessage::Warning(x) => {
+                    self.progress_info.warning(&x);
+                }
+                ProgressMessage::ErrorSkipped(x) => {
+                    stats.errors_skipped += 1;
+                    self.progress_info.warning(&x);
+                }
+                ProgressMessage::ErrorIgnored(x) => {
+                    stats.errors_ignored += 1;
+                    self.progress_info.warning(&x);
+                }
+                ProgressMessage::Conflict(x) => {
+                    stats.conflicts += 1;
+                    self.progress_info.warning(&x);
+                }
+                ProgressMessage::Hashed {
+                    description,
+                    digest,
+                } => {
+                    self.progress_info.hashed(&digest, &description);
+                }
+                ProgressMessage::Transferred { description, size } => {
+                    stats.transfers.add(&description, size);
+                }
+                ProgressMessage::Verified(_) => {
+                    stats.verified += 1;
+                }
+                ProgressMessage::Stopped(x) => {
+                    stats.stopped = Some(x);
+                }
+                ProgressMessage::Timings(x) => {
+                    stats.timings.add(&x);
+                }
+                ProgressMessage::Deleting {
+                    index,
+                    total,
+                    description,
+                } => {
+                    self.progress_info.deleting(index, total, &description);
+                }
+                ProgressMessage::FixingMetadata {
+                    index,
+                    total,
+                    description,
+                } => {
+                    self.progress_info
+                        .fixing_metadata(index, total, &description);
                 }
                 ProgressMessage::Syncing { done, size, .. } => {
-                    file_done += done;
-                    total_done += done;
-                    let elapsed = now.elapsed().as_secs() as usize;
-                    let eta = ((elapsed * stats.total_size) / total_done) - elapsed;
-                    let detailed_progress = Progress {
-                        file_done,
-                        file_size: size,
-                        total_done,
-                        total_size: stats.total_size,
-                        index,
-                        num_files: stats.num_files as usize,
-                        current_file: current_file.clone(),
-                        eta,
-                    };
-                    self.progress_info.progress(&detailed_progress);
+                    progress.file_done += done;
+                    progress.file_size = size;
+                    progress.total_done += done;
+                    progress.eta = eta(&now, &progress);
+                    self.progress_info.progress(&progress);
                 }
             }
         }
@@ -69,3 +157,12 @@ impl ProgressWorker {
         stats
     }
 }
+
+// Estimated time remaining, in seconds, from the bytes transferred so far
+fn eta(start: &Instant, progress: &Progress) -> usize {
+    if progress.total_done == 0 {
+        return 0;
+    }
+    let elapsed = start.elapsed().as_secs() as usize;
+    ((elapsed * progress.total_size) / progress.total_done).saturating_sub(elapsed)
+}
@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::chunk_store::{ChunkStore, SnapshotRecord};
+use crate::entry::Entry;
+use crate::error::Error;
+use crate::fsops;
+use crate::fsops::SyncOutcome;
+use crate::progress::ProgressMessage;
+
+pub struct StoreWorker {
+    input: Receiver<Result<Entry, Error>>,
+    output: Sender<ProgressMessage>,
+    source: PathBuf,
+    destination: PathBuf,
+}
+
+impl StoreWorker {
+    pub fn new(
+        source: &Path,
+        destination: &Path,
+        input: Receiver<Result<Entry, Error>>,
+        output: Sender<ProgressMessage>,
+    ) -> StoreWorker {
+        StoreWorker {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            input,
+            output,
+        }
+    }
+
+    pub fn start(self, snapshot: &str) -> Result<(), Error> {
+        let store = ChunkStore::open(&self.destination)?;
+        let snapshot_path = store.snapshot_path(snapshot)?;
+        if snapshot_path.exists() {
+            return Err(Error::new(&format!(
+                "Snapshot {} already exists in {:?}",
+                snapshot, self.destination
+            )));
+        }
+        let mut records = vec![];
+        for entry in self.input.iter() {
+            let entry = entry?;
+            // Recorded so that empty directories and their metadata are kept
+            if entry.is_dir() {
+                let src_meta = entry.metadata().expect("src_meta should not be None");
+                records.push(SnapshotRecord::Dir {
+                    path: fsops::get_rel_path(entry.path(), &self.source)?,
+                    mode: file_mode(src_meta),
+                    mtime: fsops::precise_mtime(src_meta),
+                });
+                continue;
+            }
+            let (sync_outcome, record) = self.store(&store, &entry)?;
+            records.push(record);
+            let progress = ProgressMessage::DoneSyncing(sync_outcome);
+            self.output
+                .send(progress)
+                .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+        }
+        store.write_snapshot(snapshot, &records)
+    }
+
+    fn store(
+        &self,
+        store: &ChunkStore,
+        src_entry: &Entry,
+    ) -> Result<(SyncOutcome, SnapshotRecord), Error> {
+        let path = fsops::get_rel_path(src_entry.path(), &self.source)?;
+        let description = path.to_string_lossy().to_string();
+        let _ = self.output.send(ProgressMessage::StartSync(description));
+
+        let is_link = src_entry.is_link().expect("src.is_link should not be None");
+        if is_link {
+            let target = std::fs::read_link(src_entry.path()).map_err(|e| {
+                Error::from_io(
+                    &format!("Could not read link {}: {}", src_entry.description(), e),
+                    &e,
+                )
+            })?;
+            let record = SnapshotRecord::Link { path, target };
+            return Ok((SyncOutcome::SymlinkCreated, record));
+        }
+
+        let (chunks, wrote_data) = store.store_file(&self.output, src_entry)?;
+        let src_meta = src_entry.metadata().expect("src_meta should not be None");
+        let record = SnapshotRecord::File {
+            path,
+            mode: file_mode(src_meta),
+            mtime: fsops::precise_mtime(src_meta),
+            size: src_meta.len(),
+            chunks,
+        };
+        let outcome = if wrote_data {
+            SyncOutcome::FileCopied
+        } else {
+            SyncOutcome::UpToDate
+        };
+        Ok((outcome, record))
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(windows)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
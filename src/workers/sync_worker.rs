@@ -1,77 +1,886 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
 
+use crate::blocks;
+use crate::blocks::BlockHasher;
+use crate::conflict;
+use crate::conflict::{Resolution, Version};
 use crate::entry::Entry;
 use crate::error::Error;
 use crate::fsops;
-use crate::fsops::SyncOutcome;
+use crate::fsops::{MetadataLoss, Removal, SyncOutcome};
+use crate::manifest::Manifest;
+use crate::probe;
 use crate::progress::ProgressMessage;
-use crate::sync::SyncOptions;
+use crate::rename;
+use crate::sample::Sampler;
+use crate::sync::{ConflictPolicy, ErrorAction, PhaseTimings, Policy, SyncOptions};
+use crate::throttle::Throttle;
+use crate::tune::BufferTuner;
 
 pub struct SyncWorker {
-    input: Receiver<Entry>,
+    input: Receiver<Result<Entry, Error>>,
     output: Sender<ProgressMessage>,
     source: PathBuf,
     destination: PathBuf,
+    // Absolute versions of the above, used when looking at symlink targets
+    abs_source: PathBuf,
+    abs_destination: PathBuf,
+    metadata_losses: Vec<(String, MetadataLoss)>,
+    // Directories whose metadata must be fixed once their contents are synced
+    // (with their relative paths in the destination)
+    dirs_to_fix: Vec<(Entry, PathBuf)>,
+    // Relative paths of every entry found in the source, used
+    // to find extraneous entries in the destination
+    source_paths: HashSet<PathBuf>,
+    // Relative paths of the directories known to exist in the destination,
+    // so that we don't have to check for every file
+    known_dirs: HashSet<PathBuf>,
+    throttle: Option<Throttle>,
+    tuner: Option<BufferTuner>,
+    timings: PhaseTimings,
+    // What the destination supports, see probe_destination()
+    symlinks_supported: bool,
+    max_name_len: Option<usize>,
+    // On case-insensitive destinations, lowercase relative paths
+    // of the entries synced so far
+    case_folded: Option<HashMap<String, PathBuf>>,
+    sampler: Option<Sampler>,
+    started: Instant,
+    files_copied: u64,
+    bytes_copied: u64,
 }
 
 impl SyncWorker {
     pub fn new(
         source: &Path,
         destination: &Path,
-        input: Receiver<Entry>,
+        input: Receiver<Result<Entry, Error>>,
         output: Sender<ProgressMessage>,
     ) -> SyncWorker {
         SyncWorker {
             source: source.to_path_buf(),
             destination: destination.to_path_buf(),
+            abs_source: fsops::absolute_path(source),
+            abs_destination: fsops::absolute_path(destination),
             input,
             output,
+            metadata_losses: vec![],
+            dirs_to_fix: vec![],
+            source_paths: HashSet::new(),
+            known_dirs: HashSet::new(),
+            throttle: None,
+            tuner: None,
+            timings: PhaseTimings::default(),
+            symlinks_supported: true,
+            max_name_len: None,
+            case_folded: None,
+            sampler: None,
+            started: Instant::now(),
+            files_copied: 0,
+            bytes_copied: 0,
         }
     }
 
-    pub fn start(self, opts: SyncOptions) -> Result<(), Error> {
-        for entry in self.input.iter() {
-            let sync_outcome = self.sync(&entry, opts)?;
+    pub fn start(mut self, mut opts: SyncOptions) -> Result<(), Error> {
+        self.probe_destination(&mut opts)?;
+        self.throttle = opts.max_files_per_sec.map(Throttle::new);
+        if opts.auto_tune {
+            self.tuner = Some(BufferTuner::new(fsops::BUFFER_SIZE));
+        }
+        self.sampler = opts.verify_sample.map(Sampler::new);
+        self.started = Instant::now();
+        if let Some(ref temp_dir) = opts.temp_dir {
+            self.check_temp_dir(temp_dir)?;
+        }
+        let res = self.sync_all(&opts);
+        let _ = self.output.send(ProgressMessage::Timings(self.timings));
+        // Write the report even if the sync was aborted, so that
+        // whatever was synced so far can be fixed up later
+        if let Some(ref report_path) = opts.metadata_report {
+            self.write_metadata_report(report_path)?;
+        }
+        res
+    }
+
+    fn sync_all(&mut self, opts: &SyncOptions) -> Result<(), Error> {
+        while let Ok(entry) = self.input.recv() {
+            let entry = entry?;
+            if let Some(reason) = self.limit_reached(opts) {
+                self.output
+                    .send(ProgressMessage::Stopped(reason))
+                    .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+                // Part of the source was not seen, so nothing can be deleted
+                return self.fix_dirs(opts);
+            }
+            self.wait();
+            let rel_path = fsops::get_rel_path(entry.path(), &self.source)?;
+            let rel_path = match self.dest_rel_path(&rel_path, opts) {
+                None => continue,
+                Some(rel_path) => rel_path,
+            };
+            self.check_case(&rel_path);
+            if opts.delete {
+                fsops::add_source_path(&mut self.source_paths, &rel_path);
+            }
+            let start = Instant::now();
+            let sync_outcome = self.sync_entry(&entry, &rel_path, opts)?;
+            match sync_outcome {
+                Some(SyncOutcome::FileCopied)
+                | Some(SyncOutcome::SymlinkCreated)
+                | Some(SyncOutcome::SymlinkUpdated) => self.timings.copy += start.elapsed(),
+                _ => self.timings.compare += start.elapsed(),
+            }
+            let sync_outcome = match sync_outcome {
+                None => continue,
+                Some(outcome) => outcome,
+            };
+            if sync_outcome == SyncOutcome::Interrupted {
+                self.output
+                    .send(ProgressMessage::Stopped("interrupted".to_string()))
+                    .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+                return self.fix_dirs(opts);
+            }
+            if sync_outcome == SyncOutcome::FileCopied {
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                self.files_copied += 1;
+                self.bytes_copied += size;
+                let rel_path = fsops::get_rel_path(entry.path(), &self.source)?;
+                let progress = ProgressMessage::Transferred {
+                    description: rel_path.to_string_lossy().to_string(),
+                    size,
+                };
+                self.output
+                    .send(progress)
+                    .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+            }
             let progress = ProgressMessage::DoneSyncing(sync_outcome);
             self.output
                 .send(progress)
                 .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
         }
+        // Note: we only get there if the whole source was walked
+        // without errors, so it's safe to delete what we did not see
+        if opts.delete {
+            self.delete_extraneous(opts)?;
+        }
+        self.fix_dirs(opts)
+    }
+
+    // Sync a directory or a file, asking the error policy what to do on failure.
+    // Returns None for directories and skipped entries
+    fn sync_entry(
+        &mut self,
+        entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<Option<SyncOutcome>, Error> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let res = if entry.is_dir() {
+                self.sync_dir(entry, rel_path, opts).map(|_| None)
+            } else {
+                self.sync(entry, rel_path, opts).map(Some)
+            };
+            let err = match res {
+                Ok(outcome) => return Ok(outcome),
+                Err(err) => err,
+            };
+            let rel_path = fsops::get_rel_path(entry.path(), &self.source)?;
+            if opts.ignore_errors_from.iter().any(|p| p.matches(&rel_path)) {
+                let message = format!("Ignored error on {}: {}", entry.description(), err);
+                self.output
+                    .send(ProgressMessage::ErrorIgnored(message))
+                    .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+                return Ok(None);
+            }
+            let policy = match opts.error_policy {
+                None => return Err(err),
+                Some(ref policy) => policy,
+            };
+            match policy.on_error(&rel_path, &err, attempt) {
+                ErrorAction::Retry => continue,
+                ErrorAction::Skip => {
+                    let message = format!("Skipped {}: {}", entry.description(), err);
+                    self.output
+                        .send(ProgressMessage::ErrorSkipped(message))
+                        .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+                    return Ok(None);
+                }
+                ErrorAction::Abort => return Err(err),
+            }
+        }
+    }
+
+    // Adjust to what the destination supports, so that we don't
+    // fail on every file that needs something it can't do
+    fn probe_destination(&mut self, opts: &mut SyncOptions) -> Result<(), Error> {
+        fs::create_dir_all(&self.destination).map_err(|e| {
+            Error::from_io(
+                &format!("Could not create destination {:?}: {}", self.destination, e),
+                &e,
+            )
+        })?;
+        let capabilities = match probe::probe(&self.destination)? {
+            Some(capabilities) => capabilities,
+            None => {
+                self.max_name_len = probe::max_name_len(&self.destination);
+                return Ok(());
+            }
+        };
+        if capabilities.mtime_granularity >= Duration::from_secs(1) && opts.modify_window.is_none()
+        {
+            opts.modify_window = Some(capabilities.mtime_granularity);
+        }
+        if !capabilities.symlinks {
+            let message = "The destination does not support symlinks, they will be skipped";
+            let _ = self
+                .output
+                .send(ProgressMessage::Warning(message.to_string()));
+        }
+        if !capabilities.case_sensitive {
+            self.case_folded = Some(HashMap::new());
+        }
+        self.symlinks_supported = capabilities.symlinks;
+        self.max_name_len = capabilities.max_name_len;
         Ok(())
     }
 
-    fn create_missing_dest_dirs(&self, rel_path: &Path) -> Result<(), Error> {
-        let parent_rel_path = rel_path
-            .parent()
-            .ok_or_else(|| Error::new(&format!("Could not get parent path of {:?}", rel_path)))?;
-        let to_create = self.destination.join(parent_rel_path);
-        let create_result = fs::create_dir_all(&to_create);
-        if let Err(e) = create_result {
+    // Where the entry at `rel_path` in the source goes in the destination,
+    // according to opts.long_names. None if it must be skipped
+    fn dest_rel_path(&self, rel_path: &Path, opts: &SyncOptions) -> Option<PathBuf> {
+        let (res, warning) = fsops::dest_rel_path(rel_path, self.max_name_len, opts.long_names);
+        if let Some(message) = warning {
+            let _ = self.output.send(ProgressMessage::Warning(message));
+        }
+        res
+    }
+
+    // Warn when two entries of the source would be the
+    // same entry in a case-insensitive destination
+    fn check_case(&mut self, rel_path: &Path) {
+        let case_folded = match self.case_folded {
+            Some(ref mut case_folded) => case_folded,
+            None => return,
+        };
+        let folded = rel_path.to_string_lossy().to_lowercase();
+        match case_folded.get(&folded) {
+            Some(other) if other != rel_path => {
+                let message = format!(
+                    "{} and {} only differ by case, and will overwrite each other in the destination",
+                    other.to_string_lossy(),
+                    rel_path.to_string_lossy()
+                );
+                let _ = self.output.send(ProgressMessage::Warning(message));
+            }
+            Some(_) => (),
+            None => {
+                case_folded.insert(folded, rel_path.to_path_buf());
+            }
+        }
+    }
+
+    fn check_temp_dir(&self, temp_dir: &Path) -> Result<(), Error> {
+        if !temp_dir.is_dir() {
             return Err(Error::new(&format!(
-                "Could not create {:?}: {}",
-                to_create, e
+                "Temporary directory {:?} does not exist",
+                temp_dir
             )));
         }
+        // The destination may not have been created yet
+        let dest = self
+            .destination
+            .ancestors()
+            .find(|p| p.exists())
+            .unwrap_or(&self.destination);
+        if !fsops::same_file_system(temp_dir, dest) {
+            let message = format!(
+                "{:?} is not on the same file system as the destination: files will be written twice",
+                temp_dir
+            );
+            let _ = self.output.send(ProgressMessage::Warning(message));
+        }
         Ok(())
     }
 
-    fn sync(&self, src_entry: &Entry, opts: SyncOptions) -> Result<SyncOutcome, Error> {
-        let rel_path = fsops::get_rel_path(&src_entry.path(), &self.source)?;
-        self.create_missing_dest_dirs(&rel_path)?;
+    fn limit_reached(&self, opts: &SyncOptions) -> Option<String> {
+        if opts.interrupted() {
+            return Some("interrupted".to_string());
+        }
+        if let Some(reason) = opts.copy_limit_reached(self.files_copied, self.bytes_copied) {
+            return Some(reason);
+        }
+        match opts.max_duration {
+            Some(max_duration) if self.started.elapsed() >= max_duration => {
+                Some(format!("ran for {}s", self.started.elapsed().as_secs()))
+            }
+            _ => None,
+        }
+    }
+
+    // If the file changed on both sides since the last sync, decide which
+    // one to keep, and move the destination out of the way if asked to
+    fn resolve_changes(
+        &self,
+        src_entry: &Entry,
+        dest_path: &Path,
+        desc: &str,
+        opts: &SyncOptions,
+    ) -> Result<Option<Resolution>, Error> {
+        let last_sync = match opts.last_sync {
+            Some(last_sync) => last_sync,
+            None => return Ok(None),
+        };
+        let dest_meta = match fs::metadata(dest_path) {
+            Ok(dest_meta) => dest_meta,
+            Err(_) => return Ok(None),
+        };
+        let src_meta = src_entry.metadata().expect("src_meta should not be None");
+        let dest_version = Version::of(&dest_meta);
+        let resolution = match conflict::resolve(
+            opts.on_conflict,
+            last_sync,
+            Version::of(src_meta),
+            dest_version,
+        ) {
+            Some(resolution) => resolution,
+            None => return Ok(None),
+        };
+        let outcome = match resolution {
+            Resolution::CopySource => "replaced it with the source".to_string(),
+            Resolution::KeepDestination => "kept the destination".to_string(),
+            Resolution::RenameDestination => {
+                let renamed = conflict::renamed_path(dest_path, dest_version);
+                fs::rename(dest_path, &renamed).map_err(|e| {
+                    Error::from_io(
+                        &format!("Could not rename {:?} to {:?}: {}", dest_path, renamed, e),
+                        &e,
+                    )
+                })?;
+                let name = renamed.file_name().unwrap_or_default().to_string_lossy();
+                format!("renamed the destination to {}", name)
+            }
+        };
+        let message = format!(
+            "{} changed in both the source and the destination since the last sync, {}",
+            desc, outcome
+        );
+        self.output
+            .send(ProgressMessage::Conflict(message))
+            .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+        Ok(Some(resolution))
+    }
+
+    // Write the block hashes of files just written, and of files known to
+    // have the same contents as their source but no valid sidecar yet,
+    // from what was read while syncing them if it was the whole file
+    fn write_sidecar(
+        &self,
+        src_entry: &Entry,
+        dest_path: &Path,
+        outcome: &SyncOutcome,
+        hasher: Option<BlockHasher>,
+        opts: &SyncOptions,
+    ) -> Result<(), Error> {
+        let needed = match outcome {
+            SyncOutcome::FileCopied => true,
+            SyncOutcome::UpToDate => {
+                opts.policy_for(Path::new(src_entry.description())) == Policy::Checksum
+                    && blocks::read(dest_path).is_none()
+            }
+            _ => false,
+        };
+        if !needed {
+            return Ok(());
+        }
+        let size = fs::metadata(dest_path).map(|m| m.len()).ok();
+        match hasher {
+            Some(hasher) if Some(hasher.size()) == size => blocks::write(dest_path, hasher),
+            _ => blocks::write_from_source(src_entry.path(), dest_path),
+        }
+    }
+
+    // Read a file that was just copied again, and check
+    // it has the same contents as its source
+    fn verify(&self, src_entry: &Entry, dest_path: &Path, desc: &str) -> Result<(), Error> {
+        let dest_entry = Entry::new(desc, dest_path);
+        if fsops::file_digest(src_entry)? != fsops::file_digest(&dest_entry)? {
+            return Err(Error::new(&format!(
+                "Verification failed for {}: contents differ from the source after the copy",
+                desc
+            )));
+        }
+        self.output
+            .send(ProgressMessage::Verified(desc.to_string()))
+            .map_err(|e| Error::new(&format!("Could not send: {}", e)))
+    }
+
+    // Relative targets going outside the source may point to different
+    // files once copied: warn if their contents differ
+    fn check_link_contents(
+        &self,
+        src_entry: &Entry,
+        dest_path: &Path,
+        desc: &str,
+    ) -> Result<(), Error> {
+        let src_target = match fs::canonicalize(src_entry.path()) {
+            Ok(src_target) => src_target,
+            Err(_) => return Ok(()),
+        };
+        let inside_source = fs::canonicalize(&self.source)
+            .map(|source| src_target.starts_with(source))
+            .unwrap_or(false);
+        // Targets inside the source are synced too
+        if inside_source || !src_target.is_file() {
+            return Ok(());
+        }
+        let message = match fs::canonicalize(dest_path) {
+            Err(_) => format!("{} points to nothing in the destination", desc),
+            Ok(ref dest_target) if *dest_target == src_target => return Ok(()),
+            Ok(dest_target) => {
+                let src_digest = fsops::file_digest(&Entry::new(desc, &src_target))?;
+                let dest_digest = fsops::file_digest(&Entry::new(desc, &dest_target))?;
+                if src_digest == dest_digest {
+                    return Ok(());
+                }
+                format!(
+                    "{} points to different contents in the destination: {} instead of {}",
+                    desc,
+                    dest_target.to_string_lossy(),
+                    src_target.to_string_lossy()
+                )
+            }
+        };
+        self.output
+            .send(ProgressMessage::Warning(message))
+            .map_err(|e| Error::new(&format!("Could not send: {}", e)))
+    }
+
+    fn wait(&mut self) {
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.wait();
+        }
+    }
+
+    fn delete_extraneous(&mut self, opts: &SyncOptions) -> Result<(), Error> {
+        let start = Instant::now();
+        // Find everything first, so that progress can be reported
+        let mut to_remove = vec![];
+        let mut subdirs = vec![PathBuf::new()];
+        while let Some(rel_dir) = subdirs.pop() {
+            let dest_dir = self.destination.join(&rel_dir);
+            if !dest_dir.is_dir() {
+                continue;
+            }
+            let entries = fs::read_dir(&dest_dir).map_err(|e| {
+                Error::from_io(
+                    &format!(
+                        "While looking for extraneous files, could not read directory {:?}: {}",
+                        dest_dir, e
+                    ),
+                    &e,
+                )
+            })?;
+            for entry in entries {
+                let entry = entry.map_err(|e| {
+                    Error::from_io(
+                        &format!(
+                            "While looking for extraneous files in {:?}, could not read entry: {}",
+                            dest_dir, e
+                        ),
+                        &e,
+                    )
+                })?;
+                let rel_path = rel_dir.join(entry.file_name());
+                let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                if self.source_paths.contains(&rel_path)
+                    || !opts.is_selected(&rel_path)
+                    || (opts.block_sidecars && blocks::is_needed(&rel_path, &self.source_paths))
+                    || (opts.on_conflict == ConflictPolicy::RenameBoth
+                        && conflict::is_needed(&rel_path, &self.source_paths))
+                {
+                    if is_dir && opts.should_walk(&rel_path) {
+                        subdirs.push(rel_path);
+                    }
+                    continue;
+                }
+                to_remove.push(rel_path);
+            }
+        }
+        let total = to_remove.len();
+        for (i, rel_path) in to_remove.iter().enumerate() {
+            let progress = ProgressMessage::Deleting {
+                index: i + 1,
+                total,
+                description: rel_path.to_string_lossy().to_string(),
+            };
+            self.output
+                .send(progress)
+                .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+            self.remove(rel_path)?;
+        }
+        self.timings.delete += start.elapsed();
+        Ok(())
+    }
+
+    // Remove a path from the destination. Directories are removed
+    // recursively, reporting each of their entries
+    fn remove(&mut self, rel_path: &Path) -> Result<(), Error> {
+        self.wait();
+        let dest_path = self.destination.join(rel_path);
+        let desc = rel_path.to_string_lossy().to_string();
+        let metadata = fs::symlink_metadata(&dest_path).map_err(|e| {
+            Error::from_io(&format!("Could not read metadata of {}: {}", desc, e), &e)
+        })?;
+        let removal = if metadata.is_dir() {
+            let entries = fs::read_dir(&dest_path).map_err(|e| {
+                Error::from_io(&format!("Could not read directory {}: {}", desc, e), &e)
+            })?;
+            for entry in entries {
+                let entry = entry.map_err(|e| {
+                    Error::from_io(&format!("Could not read entry in {}: {}", desc, e), &e)
+                })?;
+                self.remove(&rel_path.join(entry.file_name()))?;
+            }
+            fs::remove_dir(&dest_path)
+                .map_err(|e| Error::from_io(&format!("Could not remove {}: {}", desc, e), &e))?;
+            Removal::Dir(desc)
+        } else {
+            fs::remove_file(&dest_path)
+                .map_err(|e| Error::from_io(&format!("Could not remove {}: {}", desc, e), &e))?;
+            if metadata.file_type().is_symlink() {
+                Removal::Symlink(desc)
+            } else {
+                Removal::File(desc)
+            }
+        };
+        self.output
+            .send(ProgressMessage::Removed(removal))
+            .map_err(|e| Error::new(&format!("Could not send: {}", e)))
+    }
+
+    // Create a directory and its missing parents in the destination,
+    // reporting every directory that was created
+    fn create_dirs(&mut self, rel_dir: &Path) -> Result<(), Error> {
+        if self.known_dirs.contains(rel_dir) {
+            return Ok(());
+        }
+        let mut missing = vec![];
+        for ancestor in rel_dir.ancestors() {
+            if ancestor.as_os_str().is_empty()
+                || self.known_dirs.contains(ancestor)
+                || is_real_dir(&self.destination.join(ancestor))
+            {
+                break;
+            }
+            missing.push(ancestor);
+        }
+        let to_create = self.destination.join(rel_dir);
+        fs::create_dir_all(&to_create)
+            .map_err(|e| Error::from_io(&format!("Could not create {:?}: {}", to_create, e), &e))?;
+        for created in missing.iter().rev() {
+            let desc = created.to_string_lossy().to_string();
+            self.output
+                .send(ProgressMessage::DirCreated(desc))
+                .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+        }
+        for ancestor in rel_dir.ancestors() {
+            if !self.known_dirs.insert(ancestor.to_path_buf()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Make sure the destination entry, if any, has the same type as the source
+    // entry, by removing it if `force` is set
+    fn resolve_conflict(
+        &mut self,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<(), Error> {
+        let dest_meta = match fs::symlink_metadata(self.destination.join(rel_path)) {
+            Ok(dest_meta) => dest_meta,
+            Err(_) => return Ok(()),
+        };
+        let src_meta = src_entry.metadata().expect("src_meta should not be None");
+        let (src_kind, dest_kind) = (fsops::Kind::of(src_meta), fsops::Kind::of(&dest_meta));
+        if src_kind == dest_kind {
+            return Ok(());
+        }
+        if !opts.force {
+            return Err(fsops::type_conflict(
+                src_entry.description(),
+                src_kind,
+                dest_kind,
+            ));
+        }
+        self.remove(rel_path)?;
+        self.known_dirs.retain(|d| !d.starts_with(rel_path));
+        Ok(())
+    }
+
+    fn sync_dir(
+        &mut self,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<(), Error> {
+        self.resolve_conflict(src_entry, rel_path, opts)?;
+        if opts.delete && fs::symlink_metadata(self.destination.join(rel_path)).is_err() {
+            self.detect_rename(src_entry, rel_path, opts)?;
+        }
+        self.create_dirs(rel_path)?;
+        if opts.dir_times || opts.preserve_ownership {
+            self.dirs_to_fix
+                .push((src_entry.clone(), rel_path.to_path_buf()));
+        }
+        let desc = rel_path.to_string_lossy().to_string();
+        self.output
+            .send(ProgressMessage::DirSynced(desc))
+            .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+        Ok(())
+    }
+
+    // `rel_path` is missing in the destination: rename the directory
+    // it used to be, if any, instead of copying everything again
+    fn detect_rename(
+        &mut self,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<(), Error> {
+        let parent = rel_path.parent().unwrap_or_else(|| Path::new(""));
+        let dest_dirs = rename::subdirs(&self.destination, parent);
+        let old_name = rename::find_old_name(
+            &self.source,
+            src_entry.path(),
+            dest_dirs,
+            |dir| Manifest::from_tree(&self.destination.join(dir), false),
+            opts,
+        )?;
+        let old_name = match old_name {
+            None => return Ok(()),
+            Some(old_name) => old_name,
+        };
+        let from = self.destination.join(&old_name);
+        let to = self.destination.join(rel_path);
+        fs::rename(&from, &to).map_err(|e| {
+            Error::from_io(
+                &format!("Could not rename {:?} to {:?}: {}", from, to, e),
+                &e,
+            )
+        })?;
+        self.known_dirs.retain(|d| !d.starts_with(&old_name));
+        let progress = ProgressMessage::DirRenamed {
+            from: old_name.to_string_lossy().to_string(),
+            to: rel_path.to_string_lossy().to_string(),
+        };
+        self.output
+            .send(progress)
+            .map_err(|e| Error::new(&format!("Could not send: {}", e)))
+    }
+
+    // Setting metadata of a directory must be done after its
+    // contents are written, otherwise the modification time
+    // would be reset when creating files inside it
+    fn fix_dirs(&mut self, opts: &SyncOptions) -> Result<(), Error> {
+        if !opts.dir_times && !opts.preserve_ownership {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut dirs = std::mem::take(&mut self.dirs_to_fix);
+        if self.destination.is_dir() {
+            dirs.push((Entry::new(".", &self.source), PathBuf::new()));
+        }
+        // Deepest directories first
+        dirs.sort_by_key(|(_, rel_path)| std::cmp::Reverse(rel_path.components().count()));
+        let total = dirs.len();
+        for (i, (src_entry, rel_path)) in dirs.into_iter().enumerate() {
+            let progress = ProgressMessage::FixingMetadata {
+                index: i + 1,
+                total,
+                description: src_entry.description().to_string(),
+            };
+            self.output
+                .send(progress)
+                .map_err(|e| Error::new(&format!("Could not send: {}", e)))?;
+            let dest_path = self.destination.join(&rel_path);
+            let dest_entry = Entry::new(src_entry.description(), &dest_path);
+            #[cfg(unix)]
+            {
+                if opts.preserve_ownership {
+                    let loss = fsops::copy_ownership(&src_entry, &dest_entry, opts.chown_offset)?;
+                    self.on_metadata_loss(opts, &dest_entry, loss)?;
+                }
+            }
+            if opts.dir_times {
+                fsops::copy_mtime(&src_entry, &dest_entry)?;
+            }
+        }
+        self.timings.fix_metadata += start.elapsed();
+        Ok(())
+    }
+
+    fn write_metadata_report(&self, report_path: &Path) -> Result<(), Error> {
+        let mut contents = String::new();
+        for (description, loss) in &self.metadata_losses {
+            contents.push_str(&loss.to_json(description));
+            contents.push('\n');
+        }
+        fs::write(report_path, contents).map_err(|e| {
+            Error::from_io(
+                &format!(
+                    "Could not write metadata report to {:?}: {}",
+                    report_path, e
+                ),
+                &e,
+            )
+        })
+    }
+
+    // Metadata losses are fatal, unless we've been asked to report them
+    fn on_metadata_loss(
+        &mut self,
+        opts: &SyncOptions,
+        entry: &Entry,
+        loss: Option<MetadataLoss>,
+    ) -> Result<(), Error> {
+        let loss = match loss {
+            None => return Ok(()),
+            Some(loss) => loss,
+        };
+        if opts.metadata_report.is_none() {
+            return Err(loss.to_error(entry.description()));
+        }
+        self.metadata_losses
+            .push((entry.description().to_string(), loss));
+        Ok(())
+    }
+
+    fn create_missing_dest_dirs(&mut self, rel_path: &Path) -> Result<(), Error> {
+        let parent_rel_path = rel_path
+            .parent()
+            .ok_or_else(|| Error::new(&format!("Could not get parent path of {:?}", rel_path)))?;
+        self.create_dirs(parent_rel_path)
+    }
+
+    // `rel_path` is where `src_entry` goes in the destination
+    fn sync(
+        &mut self,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<SyncOutcome, Error> {
+        self.create_missing_dest_dirs(rel_path)?;
+        self.resolve_conflict(src_entry, rel_path, opts)?;
         let desc = rel_path.to_string_lossy();
 
-        let dest_path = self.destination.join(&rel_path);
+        let dest_path = self.destination.join(rel_path);
         let dest_entry = Entry::new(&desc, &dest_path);
-        let outcome = fsops::sync_entries(&self.output, &src_entry, &dest_entry)?;
+        let _ = self
+            .output
+            .send(ProgressMessage::StartSync(desc.to_string()));
+        let is_link = src_entry.is_link().expect("src.is_link should not be None");
+        let mut hashes = fsops::Hashes::new(opts);
+        let outcome = if is_link {
+            let src_target = fsops::read_link(src_entry)?;
+            let dest_target = fsops::rewrite_link_target(
+                &src_target,
+                rel_path,
+                &self.abs_source,
+                &self.abs_destination,
+                opts.rewrite_links,
+            );
+            if !self.symlinks_supported {
+                let message = format!(
+                    "Skipping symlink {}: not supported by the destination",
+                    desc
+                );
+                let _ = self.output.send(ProgressMessage::Warning(message));
+                return Ok(SyncOutcome::SymlinkSkipped);
+            }
+            if opts.safe_links && fsops::link_escapes(&dest_target, rel_path, &self.abs_destination)
+            {
+                let message = format!(
+                    "Skipping {}: target {} is outside the destination",
+                    desc,
+                    dest_target.to_string_lossy()
+                );
+                let _ = self.output.send(ProgressMessage::Warning(message));
+                return Ok(SyncOutcome::SymlinkSkipped);
+            }
+            let outcome = fsops::copy_link(src_entry, &dest_entry, &dest_target)?;
+            if opts.checksum_symlinks {
+                self.check_link_contents(src_entry, &dest_path, &desc)?;
+            }
+            outcome
+        } else {
+            match self.resolve_changes(src_entry, &dest_path, &desc, opts)? {
+                None => fsops::sync_file(
+                    &self.output,
+                    src_entry,
+                    &dest_entry,
+                    opts,
+                    self.tuner.as_mut(),
+                    &mut hashes,
+                )?,
+                Some(Resolution::KeepDestination) => return Ok(SyncOutcome::DestinationKept),
+                Some(_) => fsops::copy_file(
+                    &self.output,
+                    src_entry,
+                    &dest_entry,
+                    opts,
+                    self.tuner.as_mut(),
+                    &mut hashes,
+                )?,
+            }
+        };
+        if outcome == SyncOutcome::Interrupted {
+            return Ok(outcome);
+        }
+        // The file was written or replaced, so what we knew about it is stale
+        let dest_entry =
+            if outcome == SyncOutcome::FileCopied && (opts.temp_dir.is_some() || opts.file_times) {
+                Entry::new(&desc, &dest_path)
+            } else {
+                dest_entry
+            };
+        if outcome == SyncOutcome::FileCopied && opts.file_times {
+            fsops::copy_mtime(src_entry, &dest_entry)?;
+        }
+        if outcome == SyncOutcome::FileCopied && self.sampler.as_mut().map_or(false, Sampler::pick)
+        {
+            self.verify(src_entry, &dest_path, &desc)?;
+        }
         #[cfg(unix)]
         {
             if opts.preserve_permissions {
-                fsops::copy_permissions(&src_entry, &dest_entry)?;
+                let loss = fsops::copy_permissions(src_entry, &dest_entry)?;
+                self.on_metadata_loss(opts, &dest_entry, loss)?;
             }
+            if opts.preserve_ownership {
+                let loss = fsops::copy_ownership(src_entry, &dest_entry, opts.chown_offset)?;
+                self.on_metadata_loss(opts, &dest_entry, loss)?;
+            }
+        }
+        if opts.block_sidecars && !is_link {
+            self.write_sidecar(src_entry, &dest_path, &outcome, hashes.blocks, opts)?;
         }
         Ok(outcome)
     }
 }
+
+// Like Path::is_dir(), but without following symlinks, so
+// that we never write through a symlink in the destination
+fn is_real_dir(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|m| m.is_dir())
+        .unwrap_or(false)
+}
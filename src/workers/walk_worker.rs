@@ -3,59 +3,80 @@ use std::fs::DirEntry;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
+use std::time::Instant;
 
 use crate::entry::Entry;
 use crate::error::Error;
 use crate::fsops;
 use crate::progress::ProgressMessage;
+use crate::sync::{PhaseTimings, SyncOptions};
 
 pub struct WalkWorker {
-    entry_output: Sender<Entry>,
+    entry_output: Sender<Result<Entry, Error>>,
     progress_output: Sender<ProgressMessage>,
     source: PathBuf,
+    options: SyncOptions,
 }
 
 impl WalkWorker {
     pub fn new(
         source: &Path,
-        entry_output: Sender<Entry>,
+        options: &SyncOptions,
+        entry_output: Sender<Result<Entry, Error>>,
         progress_output: Sender<ProgressMessage>,
     ) -> WalkWorker {
         WalkWorker {
             entry_output,
             progress_output,
             source: source.to_path_buf(),
+            options: options.clone(),
         }
     }
 
     fn walk(&self) -> Result<(), Error> {
+        let start = Instant::now();
         let mut num_files = 0;
+        let mut num_dirs = 0;
         let mut total_size = 0;
+        // With estimate_first, entries are only sent once the walk is complete,
+        // so that totals are known before the first file is copied. Same thing
+        // for entries not matching the `first` patterns
+        let mut pending = vec![];
         let mut subdirs: Vec<PathBuf> = vec![self.source.to_path_buf()];
-        while !subdirs.is_empty() {
-            // We just checked that subdirs is *not* empty, so calling pop() is safe
-            let subdir = subdirs.pop().unwrap();
+        while let Some(subdir) = subdirs.pop() {
             let entries = fs::read_dir(&subdir).map_err(|e| {
-                Error::new(&format!(
-                    "While walking source, could not read directory {:?}: {}",
-                    subdir, e
-                ))
+                Error::from_io(
+                    &format!(
+                        "While walking source, could not read directory {:?}: {}",
+                        subdir, e
+                    ),
+                    &e,
+                )
             })?;
             for entry in entries {
                 if let Ok(entry) = entry {
                     let path = entry.path();
+                    let rel_path = fsops::get_rel_path(&path, &self.source)?;
                     if path.is_dir() {
-                        subdirs.push(path);
-                    } else {
-                        let meta = self.process_file(&entry)?;
+                        // Symlinks to directories are walked, but only
+                        // actual directories are created in the destination
+                        let is_real_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                        if is_real_dir && self.options.is_selected(&rel_path) {
+                            self.process_dir(&entry, &mut pending)?;
+                            num_dirs += 1;
+                            if !self.options.estimate_first {
+                                self.send_todo(num_files, num_dirs, total_size)?;
+                            }
+                        }
+                        if self.options.should_walk(&rel_path) {
+                            subdirs.push(path);
+                        }
+                    } else if self.options.is_selected(&rel_path) {
+                        let meta = self.process_file(&entry, &mut pending)?;
                         num_files += 1;
                         total_size += meta.len();
-                        let sent = self.progress_output.send(ProgressMessage::Todo {
-                            num_files,
-                            total_size: total_size as usize,
-                        });
-                        if sent.is_err() {
-                            return Err(Error::new("stats output chan is closed"));
+                        if !self.options.estimate_first {
+                            self.send_todo(num_files, num_dirs, total_size)?;
                         }
                     }
                 } else {
@@ -67,29 +88,85 @@ impl WalkWorker {
                 }
             }
         }
+        if self.options.estimate_first {
+            self.send_todo(num_files, num_dirs, total_size)?;
+        }
+        let timings = PhaseTimings {
+            scan: start.elapsed(),
+            ..Default::default()
+        };
+        let _ = self.progress_output.send(ProgressMessage::Timings(timings));
+        for entry in pending {
+            self.send_entry(entry)?;
+        }
         Ok(())
     }
 
-    fn process_file(&self, entry: &DirEntry) -> Result<fs::Metadata, Error> {
+    fn send_todo(&self, num_files: u64, num_dirs: u64, total_size: u64) -> Result<(), Error> {
+        self.progress_output
+            .send(ProgressMessage::Todo {
+                num_files,
+                num_dirs,
+                total_size: total_size as usize,
+            })
+            .map_err(|_| Error::new("stats output chan is closed"))
+    }
+
+    // True if the entry must wait until the end of the walk
+    fn defer(&self, rel_path: &Path) -> bool {
+        if self.options.estimate_first {
+            return true;
+        }
+        let first = &self.options.first;
+        !first.is_empty() && !first.iter().any(|p| p.matches(rel_path))
+    }
+
+    fn send_entry(&self, entry: Entry) -> Result<(), Error> {
+        self.entry_output.send(Ok(entry)).map_err(|e| {
+            Error::new(&format!(
+                "When walking source dir: could not send to sync worker: {}",
+                e
+            ))
+        })
+    }
+
+    fn process_file(
+        &self,
+        entry: &DirEntry,
+        pending: &mut Vec<Entry>,
+    ) -> Result<fs::Metadata, Error> {
         let rel_path = fsops::get_rel_path(&entry.path(), &self.source)?;
         let desc = rel_path.to_string_lossy();
         let src_entry = Entry::new(&desc, &entry.path());
         let metadata = src_entry.metadata().ok_or_else(|| {
             Error::new(&format!("Could not read metadata from {:?}", entry.path()))
         })?;
-        self.entry_output.send(src_entry.clone()).map_err(|e| {
-            Error::new(&format!(
-                "When walking source dir: could not send to progress worker: {}",
-                e
-            ))
-        })?;
-        Ok(metadata.clone())
+        let metadata = metadata.clone();
+        if self.defer(&rel_path) {
+            pending.push(src_entry);
+        } else {
+            self.send_entry(src_entry)?;
+        }
+        Ok(metadata)
+    }
+
+    fn process_dir(&self, entry: &DirEntry, pending: &mut Vec<Entry>) -> Result<(), Error> {
+        let rel_path = fsops::get_rel_path(&entry.path(), &self.source)?;
+        let desc = rel_path.to_string_lossy();
+        let src_entry = Entry::new(&desc, &entry.path());
+        if self.defer(&rel_path) {
+            pending.push(src_entry);
+            Ok(())
+        } else {
+            self.send_entry(src_entry)
+        }
     }
 
     pub fn start(&self) {
-        let outcome = &self.walk();
-        if outcome.is_err() {
-            // Send err to output
+        // Walk errors are forwarded to the sync worker, so that it can abort
+        // instead of treating an incomplete walk as a complete one
+        if let Err(e) = self.walk() {
+            let _ = self.entry_output.send(Err(e));
         }
     }
 }
@@ -22,7 +22,7 @@ fn assert_same_contents(a: &Path, b: &Path) {
     assert!(a.exists(), "{:?} does not exist", a);
     assert!(b.exists(), "{:?} does not exist", b);
     let status = Command::new("diff")
-        .args(&[a, b])
+        .args([a, b])
         .status()
         .expect("Failed to execute process");
     assert!(status.success(), "{:?} and {:?} differ", a, b)
@@ -30,7 +30,7 @@ fn assert_same_contents(a: &Path, b: &Path) {
 
 #[cfg(unix)]
 fn is_executable(path: &Path) -> bool {
-    let metadata = std::fs::metadata(&path)
+    let metadata = std::fs::metadata(path)
         .unwrap_or_else(|e| panic!("Could not get metadata of {:?}: {}", path, e));
     let permissions = metadata.permissions();
     let mode = permissions.mode();
@@ -40,7 +40,7 @@ fn is_executable(path: &Path) -> bool {
 #[cfg(unix)]
 fn assert_executable(path: &Path) {
     assert!(
-        is_executable(&path),
+        is_executable(path),
         "{:?} does not appear to be executable",
         path
     );
@@ -48,14 +48,14 @@ fn assert_executable(path: &Path) {
 
 #[cfg(unix)]
 fn assert_not_executable(path: &Path) {
-    assert!(!is_executable(&path), "{:?} appears to be executable", path);
+    assert!(!is_executable(path), "{:?} appears to be executable", path);
 }
 
 fn setup_test(tmp_path: &Path) -> (PathBuf, PathBuf) {
     let src_path = tmp_path.join("src");
     let dest_path = tmp_path.join("dest");
     let status = Command::new("cp")
-        .args(&["-R", "tests/data", &src_path.to_string_lossy()])
+        .args(["-R", "tests/data", &src_path.to_string_lossy()])
         .status()
         .expect("Failed to execute process");
     assert!(status.success());
@@ -63,13 +63,13 @@ fn setup_test(tmp_path: &Path) -> (PathBuf, PathBuf) {
 }
 
 fn make_recent(path: &Path) -> io::Result<()> {
-    let metadata = fs::metadata(&path)?;
+    let metadata = fs::metadata(path)?;
     let atime = FileTime::from_last_access_time(&metadata);
     let mtime = FileTime::from_last_modification_time(&metadata);
     let mut epoch = mtime.seconds_relative_to_1970();
     epoch += 1;
     let mtime = FileTime::from_seconds_since_1970(epoch, 0);
-    filetime::set_file_times(&path, atime, mtime)?;
+    filetime::set_file_times(path, atime, mtime)?;
     Ok(())
 }
 
@@ -79,13 +79,13 @@ impl ProgressInfo for DummyProgressInfo {}
 fn new_test_syncer(src: &Path, dest: &Path) -> rusync::Syncer {
     let dummy_progress_info = DummyProgressInfo {};
     let options = rusync::SyncOptions::new();
-    rusync::Syncer::new(&src, &dest, options, Box::new(dummy_progress_info))
+    rusync::Syncer::new(src, dest, options, Box::new(dummy_progress_info))
 }
 
 #[test]
 fn fresh_copy() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let syncer = new_test_syncer(&src_path, &dest_path);
     let outcome = syncer.sync();
     assert!(outcome.is_ok());
@@ -106,7 +106,7 @@ fn fresh_copy() -> Result<(), std::io::Error> {
 #[test]
 fn skip_up_to_date_files() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let syncer = new_test_syncer(&src_path, &dest_path);
 
     let stats = syncer.sync().unwrap();
@@ -121,16 +121,37 @@ fn skip_up_to_date_files() -> Result<(), std::io::Error> {
     Ok(())
 }
 
+#[test]
+fn modify_window() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    make_recent(&src_path.join("top.txt"))?;
+    let mut options = rusync::SyncOptions::new();
+    options.modify_window = Some(std::time::Duration::from_secs(2));
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 0);
+    Ok(())
+}
+
 #[test]
 #[cfg(unix)]
 fn preserve_permissions() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let syncer = new_test_syncer(&src_path, &dest_path);
     syncer.sync().unwrap();
 
     let dest_exe = &dest_path.join("a_dir/foo.exe");
-    assert_executable(&dest_exe);
+    assert_executable(dest_exe);
     Ok(())
 }
 
@@ -138,7 +159,7 @@ fn preserve_permissions() -> Result<(), std::io::Error> {
 #[cfg(unix)]
 fn do_not_preserve_permissions() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let mut options = rusync::SyncOptions::new();
     options.preserve_permissions = false;
     let syncer = rusync::Syncer::new(
@@ -150,14 +171,14 @@ fn do_not_preserve_permissions() -> Result<(), std::io::Error> {
     syncer.sync().unwrap();
 
     let dest_exe = &dest_path.join("a_dir/foo.exe");
-    assert_not_executable(&dest_exe);
+    assert_not_executable(dest_exe);
     Ok(())
 }
 
 #[test]
 fn rewrite_partially_written_files() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let src_top = src_path.join("top.txt");
     let expected = fs::read_to_string(&src_top)?;
 
@@ -177,7 +198,7 @@ fn rewrite_partially_written_files() -> Result<(), std::io::Error> {
 #[test]
 fn dest_read_only() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     fs::create_dir_all(&dest_path)?;
 
     let dest_top = dest_path.join("top.txt");
@@ -201,9 +222,9 @@ fn dest_read_only() -> Result<(), std::io::Error> {
 #[cfg(unix)]
 fn broken_link_in_src() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new("test-rusync")?;
-    let (src_path, dest_path) = setup_test(&tmp_dir.path());
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
     let src_broken_link = &src_path.join("broken");
-    unix::fs::symlink("no-such", &src_broken_link)?;
+    unix::fs::symlink("no-such", src_broken_link)?;
 
     let syncer = new_test_syncer(&src_path, &dest_path);
     let result = syncer.sync();
@@ -214,3 +235,1627 @@ fn broken_link_in_src() -> Result<(), std::io::Error> {
     assert!(result.is_ok());
     Ok(())
 }
+
+#[test]
+fn snapshots_share_chunks() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+
+    let mut options = rusync::SyncOptions::new();
+    options.snapshot = Some("first".to_string());
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options.clone(),
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert!(stats.copied > 0);
+    assert!(dest_path.join("snapshots/first").exists());
+
+    options.snapshot = Some("second".to_string());
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options.clone(),
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 0);
+
+    let snapshot = fs::read_to_string(dest_path.join("snapshots/second"))?;
+    assert!(snapshot.contains("top.txt"));
+
+    // Snapshots are never overwritten
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    assert!(syncer.sync().is_err());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_snapshots() -> Result<(), std::io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir(src_path.join("empty"))?;
+    fs::write(src_path.join("tab\tand\nnew line"), "odd name")?;
+    let not_utf8 = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+    fs::write(src_path.join(not_utf8), "latin-1")?;
+    let mut options = rusync::SyncOptions::new();
+    options.snapshot = Some("first".to_string());
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    let store = rusync::chunk_store::ChunkStore::open_existing(&dest_path).unwrap();
+    let snapshots = store.snapshots().unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].name, "first");
+    assert_eq!(snapshots[0].files, 7);
+
+    let restored = tmp_dir.path().join("restored");
+    store.restore("first", &restored).unwrap();
+    let status = Command::new("diff")
+        .args([Path::new("-r"), &src_path, &restored])
+        .status()
+        .expect("Failed to execute process");
+    assert!(status.success());
+    assert!(restored.join("empty").is_dir());
+    assert!(fs::symlink_metadata(restored.join("a_dir/link_to_one"))?.is_symlink());
+    for path in &["top.txt", "empty"] {
+        let src_meta = fs::metadata(src_path.join(path))?;
+        let restored_meta = fs::metadata(restored.join(path))?;
+        assert_eq!(src_meta.modified()?, restored_meta.modified()?);
+    }
+
+    // Only into new or empty directories
+    assert!(store.restore("first", &restored).is_err());
+    Ok(())
+}
+
+#[test]
+fn sync_only_selected_paths() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut options = rusync::SyncOptions::new();
+    options.only = vec![PathBuf::from("b_dir/c_dir"), PathBuf::from("top.txt")];
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 2);
+    assert!(dest_path.join("top.txt").exists());
+    assert!(dest_path.join("b_dir/c_dir/three.txt").exists());
+    assert!(!dest_path.join("a_dir").exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn write_metadata_report() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let report_path = tmp_dir.path().join("report.json");
+    let mut options = rusync::SyncOptions::new();
+    options.preserve_ownership = true;
+    options.metadata_report = Some(report_path.clone());
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    // We own every file in the source, so nothing should have been lost
+    let report = fs::read_to_string(&report_path)?;
+    assert_eq!(report, "");
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn shift_ownership() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let report_path = tmp_dir.path().join("report.json");
+    let mut options = rusync::SyncOptions::new();
+    options.preserve_ownership = true;
+    options.chown_offset = 100_000;
+    options.metadata_report = Some(report_path.clone());
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    // Only root can give files away: otherwise, the report
+    // says what the owner should have been
+    let src_meta = fs::metadata(src_path.join("top.txt"))?;
+    let expected = (src_meta.uid() + 100_000, src_meta.gid() + 100_000);
+    let dest_meta = fs::metadata(dest_path.join("top.txt"))?;
+    let report = fs::read_to_string(&report_path)?;
+    if report.is_empty() {
+        assert_eq!((dest_meta.uid(), dest_meta.gid()), expected);
+    } else {
+        let expected = format!("\"expected\": \"{}:{}\"", expected.0, expected.1);
+        assert!(report.contains(&expected));
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn report_lost_owners() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let report_path = tmp_dir.path().join("report.json");
+    let mut options = rusync::SyncOptions::new();
+    options.preserve_ownership = true;
+    // No owner can be shifted that far
+    options.chown_offset = u32::MAX;
+    options.metadata_report = Some(report_path.clone());
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    // The files were still copied, and the report says why their owner wasn't
+    assert!(dest_path.join("top.txt").exists());
+    let src_meta = fs::metadata(src_path.join("top.txt"))?;
+    let shifted = |id: u32| u64::from(id) + u64::from(u32::MAX);
+    let expected = format!(
+        "{{\"path\": \"top.txt\", \"attribute\": \"owner\", \
+         \"expected\": \"{}:{}\", \"actual\": null, \
+         \"reason\": \"out of range once shifted by {}\"}}",
+        shifted(src_meta.uid()),
+        shifted(src_meta.gid()),
+        u32::MAX
+    );
+    let report = fs::read_to_string(&report_path)?;
+    assert!(report.lines().any(|l| l == expected), "{}", report);
+    assert!(report
+        .lines()
+        .all(|l| l.contains("\"reason\": \"out of range once shifted by")));
+    Ok(())
+}
+
+#[test]
+fn cow_dest_falls_back_to_copies() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut options = rusync::SyncOptions::new();
+    options.reflink = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    // Whether or not the file system supports reflinks, contents should be the same
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    assert_same_contents(
+        &src_path.join("a_dir/foo.exe"),
+        &dest_path.join("a_dir/foo.exe"),
+    );
+    Ok(())
+}
+
+#[test]
+fn create_empty_dirs() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(src_path.join("empty"))?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    assert!(dest_path.join("empty").is_dir());
+    Ok(())
+}
+
+#[test]
+fn preserve_dir_times() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let old = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    let src_c_dir = src_path.join("b_dir/c_dir");
+    filetime::set_file_times(&src_c_dir, old, old)?;
+    filetime::set_file_times(&src_path, old, old)?;
+
+    let mut options = rusync::SyncOptions::new();
+    options.dir_times = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    for dest_dir in &[dest_path.join("b_dir/c_dir"), dest_path.clone()] {
+        let metadata = fs::metadata(dest_dir)?;
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        assert_eq!(mtime.seconds_relative_to_1970(), 1_000_000_000);
+    }
+    Ok(())
+}
+
+#[test]
+fn preserve_file_times() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let old = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    filetime::set_file_times(src_path.join("top.txt"), old, old)?;
+
+    let mut options = rusync::SyncOptions::new();
+    options.file_times = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options.clone(),
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    let metadata = fs::metadata(dest_path.join("top.txt"))?;
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    assert_eq!(mtime.seconds_relative_to_1970(), 1_000_000_000);
+
+    // Still up to date
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 0);
+    Ok(())
+}
+
+#[test]
+fn delete_extraneous_entries() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.dirs_created, 3);
+    let mut created_dirs = stats.created_dirs.clone();
+    created_dirs.sort();
+    assert_eq!(created_dirs, vec!["a_dir", "b_dir", "b_dir/c_dir"]);
+
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+    fs::create_dir_all(dest_path.join("b_dir/extra_dir/sub"))?;
+    fs::write(dest_path.join("b_dir/extra_dir/sub/file.txt"), "extra")?;
+
+    let mut options = rusync::SyncOptions::new();
+    options.delete = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.files_removed, 2);
+    assert_eq!(stats.dirs_removed, 2);
+    assert_eq!(
+        stats.removed_dirs,
+        vec!["b_dir/extra_dir/sub", "b_dir/extra_dir"]
+    );
+    let mut removed = stats.removed.clone();
+    removed.sort();
+    assert_eq!(
+        removed,
+        vec![
+            "b_dir/extra_dir",
+            "b_dir/extra_dir/sub",
+            "b_dir/extra_dir/sub/file.txt",
+            "extra.txt"
+        ]
+    );
+    assert!(!dest_path.join("extra.txt").exists());
+    assert!(!dest_path.join("b_dir/extra_dir").exists());
+    assert!(dest_path.join("b_dir/c_dir/three.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn rename_dirs_renamed_in_source() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    fs::rename(src_path.join("b_dir"), src_path.join("renamed"))?;
+    let new_syncer = || {
+        let mut options = rusync::SyncOptions::new();
+        options.delete = true;
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        )
+    };
+    // Dry runs find the same rename
+    let plan = new_syncer().plan().unwrap();
+    assert_eq!(plan.dirs_to_rename, 1);
+    assert_eq!(plan.files_to_copy, 0);
+    assert_eq!(plan.dirs_to_create, 0);
+    assert_eq!(plan.files_to_delete, 0);
+    assert_eq!(plan.dirs_to_delete, 0);
+
+    let stats = new_syncer().sync().unwrap();
+
+    assert_eq!(stats.dirs_renamed, 1);
+    assert_eq!(stats.copied, 0);
+    assert_eq!(stats.files_removed, 0);
+    assert!(!dest_path.join("b_dir").exists());
+    assert_same_contents(
+        &src_path.join("renamed/c_dir/three.txt"),
+        &dest_path.join("renamed/c_dir/three.txt"),
+    );
+    Ok(())
+}
+
+#[test]
+fn delete_only_in_selected_paths() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(dest_path.join("a_dir"))?;
+    fs::write(dest_path.join("a_dir/extra.txt"), "extra")?;
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+
+    let mut options = rusync::SyncOptions::new();
+    options.delete = true;
+    options.only = vec![PathBuf::from("a_dir")];
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    assert!(!dest_path.join("a_dir/extra.txt").exists());
+    assert!(dest_path.join("extra.txt").exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn do_not_delete_when_walk_fails() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    // A source directory we cannot read must not be
+    // mistaken for an empty one
+    let unreadable = src_path.join("b_dir/c_dir");
+    fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o000))?;
+    if fs::read_dir(&unreadable).is_ok() {
+        // Running as root: nothing to test
+        return Ok(());
+    }
+    let mut options = rusync::SyncOptions::new();
+    options.delete = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let result = syncer.sync();
+    fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o755))?;
+
+    assert!(result.is_err());
+    assert!(dest_path.join("b_dir/c_dir/three.txt").exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn rewrite_absolute_links() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_link = src_path.join("b_dir/link_to_top");
+    unix::fs::symlink(src_path.join("top.txt"), &src_link)?;
+
+    let mut options = rusync::SyncOptions::new();
+    options.rewrite_links = Some(rusync::sync::LinkRewrite::Relative);
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    let target = fs::read_link(dest_path.join("b_dir/link_to_top"))?;
+    assert_eq!(target.to_string_lossy(), "../top.txt");
+    Ok(())
+}
+
+struct WarningRecorder {
+    warnings: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl ProgressInfo for WarningRecorder {
+    fn warning(&self, message: &str) {
+        self.warnings.lock().unwrap().push(message.to_string());
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn checksum_symlinks_pointing_outside() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, _) = setup_test(tmp_dir.path());
+    // ../shared.txt is not the same file once in the destination
+    let dest_path = tmp_dir.path().join("backup/dest");
+    fs::write(tmp_dir.path().join("shared.txt"), "original")?;
+    fs::create_dir_all(&dest_path)?;
+    fs::write(tmp_dir.path().join("backup/shared.txt"), "something else")?;
+    unix::fs::symlink("../shared.txt", src_path.join("shared"))?;
+
+    let warnings = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut options = rusync::SyncOptions::new();
+    options.checksum_symlinks = true;
+    let info = WarningRecorder {
+        warnings: warnings.clone(),
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options.clone(), Box::new(info));
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.symlink_created, 2);
+    {
+        let warnings = warnings.lock().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("shared points to different contents"));
+    }
+
+    // Symlinks with the right target are left alone, but still checked
+    let info = WarningRecorder {
+        warnings: warnings.clone(),
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(info));
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.symlink_up_to_date, 2);
+    assert_eq!(stats.symlink_updated, 0);
+    assert_eq!(warnings.lock().unwrap().len(), 2);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn skip_unsafe_links() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    unix::fs::symlink("../../outside", src_path.join("a_dir/escaping"))?;
+    unix::fs::symlink("/etc/passwd", src_path.join("absolute"))?;
+
+    let mut options = rusync::SyncOptions::new();
+    options.safe_links = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.symlink_skipped, 2);
+    assert_eq!(stats.symlink_created, 1);
+    assert!(fs::symlink_metadata(dest_path.join("a_dir/escaping")).is_err());
+    assert!(fs::symlink_metadata(dest_path.join("absolute")).is_err());
+    assert!(dest_path.join("a_dir/link_to_one").exists());
+    Ok(())
+}
+
+#[test]
+fn per_file_policies() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::write(src_path.join("disk.iso"), "old image")?;
+    fs::write(src_path.join("app.db"), "old rows")?;
+    fs::write(src_path.join("app.log"), "line 1\n")?;
+    let mut options = rusync::SyncOptions::new();
+    options.policies =
+        rusync::sync::parse_policy_rules("*.iso: size-only\n*.db: checksum\n*.log: append")
+            .unwrap();
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options.clone(),
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    // Same size: skipped with size-only, even though the source is more recent
+    fs::write(src_path.join("disk.iso"), "new image")?;
+    make_recent(&src_path.join("disk.iso"))?;
+    // Same size and more recent destination: only checksum notices the change
+    fs::write(src_path.join("app.db"), "new rows")?;
+    let future = FileTime::from_seconds_since_1970(4_000_000_000, 0);
+    filetime::set_file_times(dest_path.join("app.db"), future, future)?;
+    // Only the new lines are copied, the beginning of the destination is kept
+    fs::write(dest_path.join("app.log"), "LINE 1\n")?;
+    fs::write(src_path.join("app.log"), "line 1\nline 2\n")?;
+
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 2);
+    assert_eq!(fs::read_to_string(dest_path.join("disk.iso"))?, "old image");
+    assert_eq!(fs::read_to_string(dest_path.join("app.db"))?, "new rows");
+    assert_eq!(
+        fs::read_to_string(dest_path.join("app.log"))?,
+        "LINE 1\nline 2\n"
+    );
+    Ok(())
+}
+
+// Keeps every progress update
+struct RecordingProgressInfo {
+    updates: std::sync::Arc<std::sync::Mutex<Vec<rusync::progress::Progress>>>,
+}
+
+impl ProgressInfo for RecordingProgressInfo {
+    fn progress(&self, progress: &rusync::progress::Progress) {
+        self.updates.lock().unwrap().push(progress.clone());
+    }
+}
+
+struct PhaseRecorder {
+    phases: std::sync::Arc<std::sync::Mutex<Vec<(String, usize, usize)>>>,
+}
+
+impl ProgressInfo for PhaseRecorder {
+    fn deleting(&self, index: usize, total: usize, description: &str) {
+        let mut phases = self.phases.lock().unwrap();
+        phases.push((format!("delete {}", description), index, total));
+    }
+
+    fn fixing_metadata(&self, index: usize, total: usize, description: &str) {
+        let mut phases = self.phases.lock().unwrap();
+        phases.push((format!("fix {}", description), index, total));
+    }
+}
+
+#[test]
+fn report_delete_and_metadata_phases() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(dest_path.join("extra_dir/sub"))?;
+    fs::write(dest_path.join("extra_dir/sub/file.txt"), "extra")?;
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+
+    let phases = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut options = rusync::SyncOptions::new();
+    options.delete = true;
+    options.dir_times = true;
+    let info = PhaseRecorder {
+        phases: phases.clone(),
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(info));
+    syncer.sync().unwrap();
+
+    let phases = phases.lock().unwrap();
+    let deleted: Vec<_> = phases
+        .iter()
+        .filter(|(name, _, _)| name.starts_with("delete"))
+        .collect();
+    // Only the top-level extraneous entries are counted, in
+    // whatever order they were found
+    assert_eq!(deleted.len(), 2);
+    assert!(deleted.iter().all(|(_, _, total)| *total == 2));
+    assert_eq!((deleted[0].1, deleted[1].1), (1, 2));
+    let mut names: Vec<_> = deleted.iter().map(|(name, _, _)| name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, vec!["delete extra.txt", "delete extra_dir"]);
+    // a_dir, b_dir, b_dir/c_dir and the destination itself
+    let fixed: Vec<_> = phases
+        .iter()
+        .filter(|(name, _, _)| name.starts_with("fix"))
+        .collect();
+    assert_eq!(fixed.len(), 4);
+    assert_eq!(fixed.last().unwrap(), &&("fix .".to_string(), 4, 4));
+    Ok(())
+}
+
+#[test]
+fn estimate_first_reports_real_totals() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let updates = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut options = rusync::SyncOptions::new();
+    options.estimate_first = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(RecordingProgressInfo {
+            updates: updates.clone(),
+        }),
+    );
+    let stats = syncer.sync().unwrap();
+
+    let updates = updates.lock().unwrap();
+    assert!(!updates.is_empty());
+    for progress in updates.iter() {
+        assert_eq!(progress.num_files as u64, stats.num_files);
+        assert_eq!(progress.total_size, stats.total_size);
+    }
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+fn count_every_entry_in_progress() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+
+    // Nothing is copied, but directories, the symlink and
+    // up-to-date files are still reported
+    let updates = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut options = rusync::SyncOptions::new();
+    options.estimate_first = true;
+    let info = RecordingProgressInfo {
+        updates: updates.clone(),
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(info));
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 0);
+    assert_eq!(stats.num_dirs, 3);
+
+    let counts: Vec<_> = updates
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|p| (p.entries_done, p.num_entries))
+        .collect();
+    let expected: Vec<_> = (1..=9).map(|i| (i, 9)).collect();
+    assert_eq!(counts, expected);
+    Ok(())
+}
+
+#[test]
+fn sync_priority_entries_first() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut options = rusync::SyncOptions::new();
+    options.first = vec![
+        rusync::pattern::Pattern::new("*.exe"),
+        rusync::pattern::Pattern::new("c_dir/"),
+    ];
+    // Only the priority files fit
+    options.max_files = Some(2);
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 2);
+    assert!(dest_path.join("a_dir/foo.exe").exists());
+    assert!(dest_path.join("b_dir/c_dir/three.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn dry_run_does_not_change_anything() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut options = rusync::SyncOptions::new();
+    options.dry_run = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert!(stats.dry_run);
+    assert_eq!(stats.copied, 5);
+    assert_eq!(stats.dirs_created, 3);
+    assert!(!dest_path.exists());
+    Ok(())
+}
+
+#[test]
+fn dry_run_against_manifest() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    // Pretend the destination was on a drive that is now unplugged
+    let manifest = rusync::manifest::Manifest::from_tree(&dest_path, false).unwrap();
+    let manifest_path = tmp_dir.path().join("dest.manifest");
+    fs::write(&manifest_path, manifest.to_text())?;
+    fs::remove_dir_all(&dest_path)?;
+
+    fs::write(src_path.join("new.txt"), "new")?;
+    make_recent(&src_path.join("top.txt"))?;
+    fs::remove_file(src_path.join("b_dir/c_dir/three.txt"))?;
+
+    let mut options = rusync::SyncOptions::new();
+    options.dry_run = true;
+    options.delete = true;
+    options.manifest = Some(manifest_path);
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 2);
+    assert_eq!(stats.dirs_created, 0);
+    assert_eq!(stats.files_removed, 1);
+    assert_eq!(stats.removed, vec!["b_dir/c_dir/three.txt"]);
+    assert!(!dest_path.exists());
+    Ok(())
+}
+
+#[test]
+fn stop_when_interrupted() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let interrupt = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let mut options = rusync::SyncOptions::new();
+    options.interrupt = Some(interrupt.clone());
+    options.delete = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options.clone(),
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.stopped, Some("interrupted".to_string()));
+    assert_eq!(stats.copied, 0);
+
+    interrupt.store(false, std::sync::atomic::Ordering::SeqCst);
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.stopped, None);
+    assert_same_contents(&src_path, &dest_path);
+    Ok(())
+}
+
+#[test]
+fn block_sidecars_replace_reading_destination() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::write(src_path.join("app.db"), "old rows")?;
+    let mut options = rusync::SyncOptions::new();
+    options.policies = rusync::sync::parse_policy_rules("*.db: checksum").unwrap();
+    options.block_sidecars = true;
+    options.delete = true;
+    let sync = |options: &rusync::SyncOptions| {
+        let syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options.clone(),
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.sync().unwrap()
+    };
+    sync(&options);
+    assert!(dest_path.join(".app.db.rusync-blocks").exists());
+    assert!(dest_path.join(".top.txt.rusync-blocks").exists());
+
+    fs::write(src_path.join("app.db"), "new rows")?;
+    let stats = sync(&options);
+    assert_eq!(stats.copied, 1);
+    assert_eq!(fs::read_to_string(dest_path.join("app.db"))?, "new rows");
+
+    // Change the destination behind rusync's back: since the sidecar is
+    // trusted, the destination is not read, and the change goes unnoticed
+    let dest_db = dest_path.join("app.db");
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(&dest_db)?);
+    fs::write(&dest_db, "XXX rows")?;
+    filetime::set_file_times(&dest_db, mtime, mtime)?;
+    let stats = sync(&options);
+    assert_eq!(stats.copied, 0);
+    assert_eq!(fs::read_to_string(&dest_db)?, "XXX rows");
+
+    // Sidecars go away with their file
+    fs::remove_file(src_path.join("app.db"))?;
+    let stats = sync(&options);
+    assert_eq!(stats.files_removed, 2);
+    assert!(!dest_path.join(".app.db.rusync-blocks").exists());
+    assert!(dest_path.join(".top.txt.rusync-blocks").exists());
+    Ok(())
+}
+
+#[test]
+fn time_each_phase() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    fs::write(dest_path.join("extraneous.txt"), "extraneous")?;
+    let mut options = rusync::SyncOptions::new();
+    options.delete = true;
+    options.dir_times = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    let zero = std::time::Duration::from_secs(0);
+    let timings = stats.timings;
+    assert!(timings.scan > zero);
+    assert!(timings.compare > zero);
+    assert!(timings.copy > zero);
+    assert!(timings.delete > zero);
+    assert!(timings.fix_metadata > zero);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn ignore_errors_from_patterns() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    // Sockets can't be copied
+    let _listener = std::os::unix::net::UnixListener::bind(src_path.join("a_dir/agent.sock"))?;
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    assert!(syncer.sync().is_err());
+
+    let mut options = rusync::SyncOptions::new();
+    options.ignore_errors_from = vec![rusync::pattern::Pattern::new("*.sock")];
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors_ignored, 1);
+    assert_eq!(stats.errors_skipped, 0);
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join("b_dir/c_dir/three.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn resolve_conflicts_since_last_sync() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+    let last_sync = std::time::SystemTime::now();
+    // File times are not as precise as the clock
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let sync = |on_conflict, dry_run| {
+        fs::write(src_path.join("top.txt"), "changed in the source").unwrap();
+        fs::write(dest_path.join("top.txt"), "changed in the destination").unwrap();
+        let mut options = rusync::SyncOptions::new();
+        options.delete = true;
+        options.dry_run = dry_run;
+        options.last_sync = Some(last_sync);
+        options.on_conflict = on_conflict;
+        let syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        syncer.sync().unwrap()
+    };
+
+    // Dry runs tell what would be done
+    let stats = sync(rusync::sync::ConflictPolicy::DestWins, true);
+    assert_eq!(stats.conflicts, 1);
+    assert_eq!(stats.copied, 0);
+    let stats = sync(rusync::sync::ConflictPolicy::RenameBoth, true);
+    assert_eq!(stats.conflicts, 1);
+    assert_eq!(stats.copied, 1);
+    assert_eq!(
+        fs::read_dir(&dest_path)?.count(),
+        fs::read_dir(&src_path)?.count()
+    );
+
+    let stats = sync(rusync::sync::ConflictPolicy::DestWins, false);
+    assert_eq!(stats.conflicts, 1);
+    assert_eq!(stats.copied, 0);
+    assert_eq!(
+        fs::read_to_string(dest_path.join("top.txt"))?,
+        "changed in the destination"
+    );
+
+    let stats = sync(rusync::sync::ConflictPolicy::LargerWins, false);
+    assert_eq!(stats.conflicts, 1);
+    assert_eq!(stats.copied, 0);
+
+    let stats = sync(rusync::sync::ConflictPolicy::RenameBoth, false);
+    assert_eq!(stats.conflicts, 1);
+    assert_eq!(stats.copied, 1);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    let renamed: Vec<String> = fs::read_dir(&dest_path)?
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|n| n.starts_with("top.txt.rusync-conflict-"))
+        .collect();
+    assert_eq!(renamed.len(), 1);
+    assert_eq!(
+        fs::read_to_string(dest_path.join(&renamed[0]))?,
+        "changed in the destination"
+    );
+
+    // Nothing changed in the destination since the last sync
+    let mut options = rusync::SyncOptions::new();
+    options.last_sync = Some(std::time::SystemTime::now());
+    options.on_conflict = rusync::sync::ConflictPolicy::DestWins;
+    fs::write(src_path.join("top.txt"), "changed in the source again")?;
+    make_recent(&src_path.join("top.txt"))?;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.conflicts, 0);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+struct RecordingHashInfo {
+    hashes: std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>,
+}
+
+impl ProgressInfo for RecordingHashInfo {
+    fn hashed(&self, digest: &str, description: &str) {
+        let mut hashes = self.hashes.lock().unwrap();
+        hashes.push((digest.to_string(), description.to_string()));
+    }
+}
+
+#[test]
+fn hash_files_while_copying() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::write(src_path.join("abc.txt"), "abc")?;
+    let hashes = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let sync = || {
+        let mut options = rusync::SyncOptions::new();
+        options.hash_files = true;
+        options.checkpoint_interval = Some(100_000);
+        let progress_info = RecordingHashInfo {
+            hashes: hashes.clone(),
+        };
+        let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(progress_info));
+        syncer.sync().unwrap()
+    };
+
+    let stats = sync();
+    let recorded = hashes.lock().unwrap().clone();
+    assert_eq!(recorded.len() as u64, stats.copied);
+    assert!(recorded.contains(&(
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+        "abc.txt".to_string()
+    )));
+
+    // Files that are up to date are not read
+    hashes.lock().unwrap().clear();
+    sync();
+    assert!(hashes.lock().unwrap().is_empty());
+
+    // Large enough to be copied with checkpoints
+    fs::write(src_path.join("big.bin"), vec![b'a'; 300_000])?;
+    sync();
+    let recorded = hashes.lock().unwrap().clone();
+    assert_eq!(
+        recorded,
+        vec![(
+            "12e1b9b179b29a4f7e5889b185d7ac71bff0ad1f49a7b391d0911b737a0f5381".to_string(),
+            "big.bin".to_string()
+        )]
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn keep_symlinked_dirs_when_deleting() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    unix::fs::symlink("a_dir", src_path.join("linked_dir"))?;
+    let new_syncer = || {
+        let mut options = rusync::SyncOptions::new();
+        options.delete = true;
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        )
+    };
+    new_syncer().sync().unwrap();
+    assert_same_contents(
+        &src_path.join("a_dir/one.txt"),
+        &dest_path.join("linked_dir/one.txt"),
+    );
+
+    // The contents of the symlink are in the destination, so they
+    // must not be seen as extraneous by the next runs
+    let plan = new_syncer().plan().unwrap();
+    assert_eq!(plan.files_to_delete, 0);
+    assert_eq!(plan.dirs_to_delete, 0);
+    let stats = new_syncer().sync().unwrap();
+    assert_eq!(stats.files_removed, 0);
+    assert_eq!(stats.dirs_removed, 0);
+    assert_eq!(stats.copied, 0);
+    assert!(dest_path.join("linked_dir/one.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn throttle_files_per_second() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut options = rusync::SyncOptions::new();
+    options.max_files_per_sec = Some(20);
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let start = std::time::Instant::now();
+    syncer.sync().unwrap();
+
+    // 9 entries: the last one cannot start before 8 / 20 seconds
+    assert!(start.elapsed() >= std::time::Duration::from_millis(400));
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    Ok(())
+}
+
+#[test]
+fn stop_after_max_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let new_syncer = || {
+        let mut options = rusync::SyncOptions::new();
+        options.max_files = Some(2);
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        )
+    };
+    // Dry runs stop at the same point
+    let plan = new_syncer().plan().unwrap();
+    assert_eq!(plan.files_to_copy, 2);
+
+    let stats = new_syncer().sync().unwrap();
+    assert_eq!(stats.copied, 2);
+    assert_eq!(stats.transfers.largest.len(), 2);
+    assert!(stats.stopped.is_some());
+
+    // Running again without limit syncs the rest
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        rusync::SyncOptions::new(),
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 3);
+    assert_eq!(stats.up_to_date, 2);
+    assert!(stats.stopped.is_none());
+    Ok(())
+}
+
+#[test]
+fn stop_after_max_duration() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut options = rusync::SyncOptions::new();
+    options.max_duration = Some(std::time::Duration::from_secs(0));
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 0);
+    assert!(stats.stopped.is_some());
+    Ok(())
+}
+
+#[test]
+fn verify_sample_of_copies() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut options = rusync::SyncOptions::new();
+    options.verify_sample = Some(100.0);
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options.clone(),
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.verified, 5);
+
+    options.verify_sample = Some(0.0);
+    make_recent(&src_path.join("top.txt"))?;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 1);
+    assert_eq!(stats.verified, 0);
+    Ok(())
+}
+
+#[test]
+fn copy_through_temp_dir() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let temp_path = tmp_dir.path().join("temp");
+    fs::create_dir(&temp_path)?;
+    let mut options = rusync::SyncOptions::new();
+    options.temp_dir = Some(temp_path.clone());
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 5);
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    #[cfg(unix)]
+    assert_executable(&dest_path.join("a_dir/foo.exe"));
+    assert_eq!(fs::read_dir(&temp_path)?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn no_checkpoints_through_temp_dir() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let mut options = rusync::SyncOptions::new();
+    options.temp_dir = Some(tmp_dir.path().to_path_buf());
+    options.checkpoint_interval = Some(100_000);
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    assert!(syncer.sync().is_err());
+    assert!(!dest_path.exists());
+    Ok(())
+}
+
+#[test]
+fn plan_before_syncing() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let files = [
+        "top.txt",
+        "a_dir/foo.exe",
+        "a_dir/one.txt",
+        "a_dir/two.txt",
+        "b_dir/c_dir/three.txt",
+    ];
+    let total_size: u64 = files
+        .iter()
+        .map(|p| fs::metadata(src_path.join(p)).unwrap().len())
+        .sum();
+
+    let plan = new_test_syncer(&src_path, &dest_path).plan().unwrap();
+    assert!(!dest_path.exists());
+    assert_eq!(plan.files_to_copy, 5);
+    assert_eq!(plan.bytes_to_copy, total_size);
+    assert_eq!(plan.symlinks_to_write, 1);
+    assert_eq!(plan.dirs_to_create, 3);
+    assert_eq!(plan.entries_to_delete(), 0);
+
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+    let mut options = rusync::SyncOptions::new();
+    options.delete = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let plan = syncer.plan().unwrap();
+    assert_eq!(plan.files_to_delete, 1);
+    assert_eq!(plan.files_to_copy, 0);
+    assert!(dest_path.join("extra.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn run_jobs_in_parallel() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, _) = setup_test(tmp_dir.path());
+    let job = |source: &Path, dest: &str| rusync::batch::Job {
+        source: source.to_path_buf(),
+        destination: tmp_dir.path().join(dest),
+        label: None,
+        delete: None,
+        last_sync: None,
+    };
+    let batch = rusync::batch::Batch {
+        parallel: 2,
+        max_open_files: None,
+        max_memory: None,
+        jobs: vec![
+            job(&src_path, "one"),
+            job(&tmp_dir.path().join("no-such-dir"), "two"),
+            job(&src_path.join("a_dir"), "three"),
+        ],
+    };
+
+    let outcomes = rusync::batch::run(&batch, &rusync::SyncOptions::new(), |_| {
+        Box::new(DummyProgressInfo {})
+    });
+
+    // In order, and a failure does not prevent the other jobs from running
+    assert_eq!(outcomes.len(), 3);
+    assert_eq!(outcomes[0].result.as_ref().unwrap().copied, 5);
+    assert!(outcomes[1].result.is_err());
+    assert_eq!(outcomes[2].result.as_ref().unwrap().copied, 3);
+    assert_same_contents(&src_path.join("a_dir"), &tmp_dir.path().join("three"));
+    let report = rusync::batch::report(&outcomes);
+    assert!(report.contains("3 jobs, 1 failed: 8 files copied"));
+    Ok(())
+}
+
+#[test]
+fn resolve_conflicts_in_batches() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    new_test_syncer(&src_path, &dest_path).sync().unwrap();
+    let last_sync = std::time::SystemTime::now();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    fs::write(src_path.join("top.txt"), "changed in the source")?;
+    fs::write(dest_path.join("top.txt"), "changed in the destination")?;
+    let batch = rusync::batch::Batch {
+        parallel: 1,
+        max_open_files: None,
+        max_memory: None,
+        jobs: vec![rusync::batch::Job {
+            source: src_path.clone(),
+            destination: dest_path.clone(),
+            label: None,
+            delete: None,
+            last_sync: Some(last_sync),
+        }],
+    };
+    let mut options = rusync::SyncOptions::new();
+    options.on_conflict = rusync::sync::ConflictPolicy::DestWins;
+
+    let outcomes = rusync::batch::run(&batch, &options, |_| Box::new(DummyProgressInfo {}));
+
+    let stats = outcomes[0].result.as_ref().unwrap();
+    assert_eq!(stats.conflicts, 1);
+    assert_eq!(
+        fs::read_to_string(dest_path.join("top.txt"))?,
+        "changed in the destination"
+    );
+    Ok(())
+}
+
+// Remove the conflicting file and retry, or retry once and skip
+#[cfg(unix)]
+struct FixOrSkip {
+    dest_path: PathBuf,
+    fix: bool,
+    errors: std::sync::Mutex<Vec<(PathBuf, usize)>>,
+}
+
+#[cfg(unix)]
+impl rusync::sync::ErrorPolicy for FixOrSkip {
+    fn on_error(
+        &self,
+        path: &Path,
+        _error: &rusync::Error,
+        attempt: usize,
+    ) -> rusync::sync::ErrorAction {
+        self.errors
+            .lock()
+            .unwrap()
+            .push((path.to_path_buf(), attempt));
+        if self.fix {
+            fs::remove_file(self.dest_path.join(path)).unwrap();
+            rusync::sync::ErrorAction::Retry
+        } else if attempt == 1 {
+            rusync::sync::ErrorAction::Retry
+        } else {
+            rusync::sync::ErrorAction::Skip
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn error_policy() -> Result<(), std::io::Error> {
+    for fix in &[false, true] {
+        let tmp_dir = TempDir::new("test-rusync")?;
+        let (src_path, dest_path) = setup_test(tmp_dir.path());
+        // Replacing a file by a symlink is refused
+        fs::create_dir_all(dest_path.join("a_dir"))?;
+        fs::write(dest_path.join("a_dir/link_to_one"), "not a link")?;
+
+        let policy = std::sync::Arc::new(FixOrSkip {
+            dest_path: dest_path.clone(),
+            fix: *fix,
+            errors: std::sync::Mutex::new(vec![]),
+        });
+        let mut options = rusync::SyncOptions::new();
+        options.error_policy = Some(policy.clone());
+        let syncer = rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            options,
+            Box::new(DummyProgressInfo {}),
+        );
+        let stats = syncer.sync().unwrap();
+
+        let errors = policy.errors.lock().unwrap();
+        let link = PathBuf::from("a_dir/link_to_one");
+        assert_eq!(stats.copied, 5);
+        if *fix {
+            assert_eq!(*errors, vec![(link, 1)]);
+            assert_eq!(stats.errors_skipped, 0);
+            assert_eq!(stats.symlink_created, 1);
+        } else {
+            assert_eq!(*errors, vec![(link.clone(), 1), (link, 2)]);
+            assert_eq!(stats.errors_skipped, 1);
+            assert_eq!(stats.symlink_created, 0);
+        }
+    }
+    Ok(())
+}
+
+struct RecordingPlanInfo {
+    changes: std::sync::Arc<std::sync::Mutex<Vec<(rusync::progress::PlannedChange, String)>>>,
+}
+
+impl ProgressInfo for RecordingPlanInfo {
+    fn planned(&self, change: rusync::progress::PlannedChange, description: &str) {
+        let mut changes = self.changes.lock().unwrap();
+        changes.push((change, description.to_string()));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn audit_tells_contents_from_metadata() -> Result<(), std::io::Error> {
+    use rusync::progress::PlannedChange;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    fs::write(src_path.join("top.txt"), "new contents")?;
+    let future = FileTime::from_seconds_since_1970(4_000_000_000, 0);
+    filetime::set_file_times(src_path.join("a_dir/one.txt"), future, future)?;
+    fs::set_permissions(
+        dest_path.join("a_dir/two.txt"),
+        fs::Permissions::from_mode(0o600),
+    )?;
+    let dest_top = fs::read_to_string(dest_path.join("top.txt"))?;
+
+    let changes = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut options = rusync::SyncOptions::new();
+    options.audit = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(RecordingPlanInfo {
+            changes: changes.clone(),
+        }),
+    );
+    syncer.sync().unwrap();
+
+    let mut changes = changes.lock().unwrap().clone();
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    assert_eq!(
+        changes,
+        vec![
+            (PlannedChange::ReplaceMetadata, "a_dir/one.txt".to_string()),
+            (PlannedChange::UpdateMetadata, "a_dir/two.txt".to_string()),
+            (PlannedChange::ReplaceContents, "top.txt".to_string()),
+        ]
+    );
+    assert_eq!(fs::read_to_string(dest_path.join("top.txt"))?, dest_top);
+    Ok(())
+}
+
+#[test]
+fn dry_run_tells_new_files_from_updates() -> Result<(), std::io::Error> {
+    use rusync::progress::PlannedChange;
+
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    fs::write(dest_path.join("top.txt"), "old contents")?;
+    let changes = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut options = rusync::SyncOptions::new();
+    options.dry_run = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(RecordingPlanInfo {
+            changes: changes.clone(),
+        }),
+    );
+    syncer.sync().unwrap();
+
+    let changes = changes.lock().unwrap();
+    let change_of = |path: &str| changes.iter().find(|c| c.1 == path).map(|c| c.0);
+    assert_eq!(change_of("top.txt"), Some(PlannedChange::UpdateFile));
+    assert_eq!(change_of("a_dir/one.txt"), Some(PlannedChange::CopyFile));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn setup_type_conflicts(tmp_path: &Path, dest_path: &Path) -> io::Result<PathBuf> {
+    let elsewhere = tmp_path.join("elsewhere");
+    fs::create_dir_all(&elsewhere)?;
+    fs::create_dir_all(dest_path.join("top.txt"))?;
+    fs::write(dest_path.join("top.txt/inside.txt"), "inside")?;
+    unix::fs::symlink(&elsewhere, dest_path.join("a_dir"))?;
+    fs::write(dest_path.join("b_dir"), "not a dir")?;
+    Ok(elsewhere)
+}
+
+#[test]
+#[cfg(unix)]
+fn type_conflicts_abort_without_force() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let elsewhere = setup_type_conflicts(tmp_dir.path(), &dest_path)?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let result = syncer.sync();
+
+    let err = result.err().expect("sync should have failed");
+    assert!(err.to_string().contains("--force"), "{}", err);
+    // Nothing was written through the symlink
+    assert_eq!(fs::read_dir(&elsewhere)?.count(), 0);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn force_replaces_type_conflicts() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let elsewhere = setup_type_conflicts(tmp_dir.path(), &dest_path)?;
+
+    let mut options = rusync::SyncOptions::new();
+    options.force = true;
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    assert!(!fs::symlink_metadata(dest_path.join("a_dir"))?
+        .file_type()
+        .is_symlink());
+    assert_same_contents(
+        &src_path.join("a_dir/one.txt"),
+        &dest_path.join("a_dir/one.txt"),
+    );
+    assert_same_contents(
+        &src_path.join("b_dir/c_dir/three.txt"),
+        &dest_path.join("b_dir/c_dir/three.txt"),
+    );
+    assert_eq!(fs::read_dir(&elsewhere)?.count(), 0);
+    assert_eq!(stats.files_removed, 2);
+    assert_eq!(stats.symlinks_removed, 1);
+    assert_eq!(stats.dirs_removed, 1);
+    Ok(())
+}
+
+#[test]
+fn labels_in_progress() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new("test-rusync")?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let updates = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let threads: Vec<_> = ["one", "two"]
+        .iter()
+        .map(|label| {
+            let mut options = rusync::SyncOptions::new();
+            options.label = Some(label.to_string());
+            let syncer = rusync::Syncer::new(
+                &src_path,
+                &dest_path.join(label),
+                options,
+                Box::new(RecordingProgressInfo {
+                    updates: updates.clone(),
+                }),
+            );
+            std::thread::spawn(move || syncer.sync().unwrap())
+        })
+        .collect();
+    for thread in threads {
+        let stats = thread.join().unwrap();
+        assert!(stats.label.is_some());
+    }
+
+    let updates = updates.lock().unwrap();
+    for label in &["one", "two"] {
+        let count = updates
+            .iter()
+            .filter(|p| p.label.as_deref() == Some(*label))
+            .count();
+        // 5 files, 1 symlink and 3 directories
+        assert_eq!(count, 9);
+    }
+    assert_eq!(updates.len(), 18);
+    Ok(())
+}